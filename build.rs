@@ -2,9 +2,13 @@ use std::path::Path;
 use std::{env, fs};
 
 fn main() {
-  let target_dir_path = env::var("OUT_DIR").unwrap();
-  copy(&target_dir_path, "portaudio_x64.dll");
-  copy(&target_dir_path, "portaudio_x64.pdb");
+  // prebuilt portaudio, ogg and vorbis binaries are shipped only for windows, other hosts use system libraries
+  if env::var("CARGO_CFG_WINDOWS").is_ok() {
+    println!("cargo:rustc-link-search=native={}", env::var("CARGO_MANIFEST_DIR").unwrap());
+    let target_dir_path = env::var("OUT_DIR").unwrap();
+    copy(&target_dir_path, "portaudio_x64.dll");
+    copy(&target_dir_path, "portaudio_x64.pdb");
+  }
   println!("cargo:rerun-if-changed=build.rs");
 }
 
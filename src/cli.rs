@@ -0,0 +1,338 @@
+use {
+  crate::device::{
+    backend::*,
+    convert::Resampling,
+    hotplug::{DeviceEvent, HotPlug},
+    info::*,
+    input::InputDevice,
+    output::OutputDevice,
+  },
+  std::{
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+  },
+};
+
+pub const USAGE: &str = "usage: divana [--host-api <name>] [command]
+
+commands:
+  shell                     interactive shell, what runs without a command
+  tui                       full screen view of the devices with level meters, driven by keys
+  -s, --script <file>       runs shell commands from a file, stops at the first failing one
+  devices                   list input and output devices
+  host-apis                 list PortAudio host apis usable with --host-api
+  loopback [options]        play what the input captures
+    --input <device>        index, id or unique part of the name, first device if omitted
+    --output <device>
+    --rate <hz>             open both devices at this sample rate
+    --buffers <count>x<ms>  device buffers, 4x20 if omitted
+    --duration <time>       30s, 500ms, 2m; runs until killed if omitted
+  help                      this text
+
+--host-api opens devices through PortAudio with the given host api";
+
+// how often a running loopback looks at device events
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+  pub host_api: Option<String>,
+  pub command: Command,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+  Shell,
+  Tui,
+  // shell reading its commands from a file, stops at the first failing one
+  Script(PathBuf),
+  Help,
+  Devices,
+  HostApis,
+  Loopback(LoopbackOptions),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LoopbackOptions {
+  pub input: Option<String>,
+  pub output: Option<String>,
+  pub rate: Option<u32>,
+  pub buffers: BufferConfig,
+  pub duration: Option<Duration>,
+}
+
+impl Cli {
+  // arguments without the program name, options take "--name value" as well as "--name=value"
+  pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, String> {
+    let mut host_api = None;
+    let mut command: Option<Command> = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+      let (name, inline_value) = match arg.find('=') {
+        Some(at) if arg.starts_with("--") => (arg[..at].to_string(), Some(arg[at + 1..].to_string())),
+        _ => (arg.clone(), None),
+      };
+      let mut value = || match inline_value.clone().or_else(|| args.next()) {
+        Some(value) => Ok(value),
+        None => Err(format!("{} needs a value", name)),
+      };
+      match (name.as_str(), &mut command) {
+        ("--host-api", _) => host_api = Some(value()?),
+        ("-h", _) | ("--help", _) => command = Some(Command::Help),
+        ("-s", None) | ("--script", None) => command = Some(Command::Script(PathBuf::from(value()?))),
+        ("--input", Some(Command::Loopback(options))) => options.input = Some(value()?),
+        ("--output", Some(Command::Loopback(options))) => options.output = Some(value()?),
+        ("--rate", Some(Command::Loopback(options))) => {
+          let rate = value()?;
+          options.rate = Some(rate.parse().ok().filter(|&rate| rate > 0).ok_or(format!("bad rate {}", rate))?);
+        }
+        ("--buffers", Some(Command::Loopback(options))) => {
+          let buffers = value()?;
+          options.buffers = BufferConfig::parse(&buffers).ok_or(format!("bad buffers {}, write it like 4x20", buffers))?;
+        }
+        ("--duration", Some(Command::Loopback(options))) => {
+          let duration = value()?;
+          options.duration = Some(parse_duration(&duration).ok_or(format!("bad duration {}, write it like 30s", duration))?);
+        }
+        (option, _) if option.starts_with('-') => return Err(format!("unknown option {}", option)),
+        (name, None) => {
+          command = Some(match name {
+            "shell" => Command::Shell,
+            "tui" => Command::Tui,
+            "help" => Command::Help,
+            "devices" => Command::Devices,
+            "host-apis" => Command::HostApis,
+            "loopback" => Command::Loopback(LoopbackOptions {
+              input: None,
+              output: None,
+              rate: None,
+              buffers: BufferConfig::default(),
+              duration: None,
+            }),
+            _ => return Err(format!("unknown command {}", name)),
+          })
+        }
+        (name, Some(_)) => return Err(format!("unexpected argument {}", name)),
+      }
+    }
+    Ok(Cli {
+      host_api,
+      command: command.unwrap_or(Command::Shell),
+    })
+  }
+}
+
+// "30s", "500ms", "2m" or plain seconds
+pub fn parse_duration(text: &str) -> Option<Duration> {
+  let text = text.trim();
+  let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+  let amount = text[..split].parse::<u64>().ok()?;
+  match &text[split..] {
+    "ms" => Some(Duration::from_millis(amount)),
+    "" | "s" => Some(Duration::from_secs(amount)),
+    "m" => Some(Duration::from_secs(amount * 60)),
+    _ => None,
+  }
+}
+
+pub fn list_devices<B: AudioBackend>(backend: &B, out: &mut dyn FnMut(String)) {
+  for (direction, devices) in [("input", backend.input_devices()), ("output", backend.output_devices())].iter() {
+    out(format!("{} devices:", direction));
+    if devices.is_empty() {
+      out("  none".to_string());
+    }
+    for device in devices.iter() {
+      match device.get_best_format() {
+        Some(format) => out(format!("  [{}] {} ({})", device.index, device, format)),
+        None => out(format!("  [{}] {} (no supported format)", device.index, device)),
+      }
+    }
+  }
+}
+
+fn select_device(devices: Vec<DeviceInfo>, query: &Option<String>, direction: &str) -> Result<DeviceInfo, String> {
+  let device = match query {
+    Some(query) => DeviceInfo::select(&devices, query),
+    None => devices.first(),
+  };
+  match (device, query) {
+    (Some(device), _) => Ok(device.clone()),
+    (None, Some(query)) => Err(format!("no single {} device matches {} (see \"devices\")", direction, query)),
+    (None, None) => Err(format!("there are no {} devices", direction)),
+  }
+}
+
+fn select_format(device: &DeviceInfo, rate: Option<u32>) -> Result<DeviceFormat, String> {
+  match rate {
+    Some(rate) => device
+      .get_best_format_at(rate)
+      .ok_or(format!("{} does not support {}hz", device, rate)),
+    None => device.get_best_format().ok_or(format!("{} supports no format", device)),
+  }
+}
+
+// plays the input until the duration is over, the recording it replays ends or a device fails,
+// removed devices are waited for
+pub fn loopback<B: AudioBackend>(backend: &B, options: &LoopbackOptions, hot_plug: HotPlug, resampling: Resampling) -> Result<(), String> {
+  let input = select_device(backend.input_devices(), &options.input, "input")?;
+  let output = select_device(backend.output_devices(), &options.output, "output")?;
+  let (input_format, output_format) = (select_format(&input, options.rate)?, select_format(&output, options.rate)?);
+  let output_device = OutputDevice::new(backend, output_format, options.buffers, &output, hot_plug, resampling)
+    .map_err(|err| format!("could not open output: {}", err))?;
+  let input_device = InputDevice::new(
+    backend,
+    input_format,
+    options.buffers,
+    &input,
+    hot_plug,
+    output_device.sender.clone(),
+  )
+  .map_err(|err| format!("could not open input: {}", err))?;
+  println!("playing {} ({}) on {} ({})", input, input_format, output, output_format);
+
+  let started = Instant::now();
+  let result = loop {
+    let events = std::iter::from_fn(|| input_device.poll_event())
+      .map(|event| ("input", event))
+      .chain(std::iter::from_fn(|| output_device.poll_event()).map(|event| ("output", event)));
+    let (mut failed, mut ended) = (None, false);
+    for (direction, event) in events {
+      match event {
+        DeviceEvent::Failed(err) => failed = Some(format!("{} device failed: {}", direction, err)),
+        DeviceEvent::Lost(err) => println!("{} device lost: {} ({})", direction, err, hot_plug),
+        DeviceEvent::Reopened(id) => println!("{} device is back: {}", direction, id),
+        DeviceEvent::Ended => ended = true,
+      }
+    }
+    if let Some(err) = failed {
+      break Err(err);
+    }
+    if ended {
+      println!("{} has ended", input);
+      break Ok(());
+    }
+    let remaining = match options.duration {
+      Some(duration) if started.elapsed() >= duration => break Ok(()),
+      Some(duration) => duration - started.elapsed(),
+      None => POLL_INTERVAL,
+    };
+    thread::sleep(remaining.min(POLL_INTERVAL));
+  };
+  // producer goes first so nothing is sent to an already stopped output
+  drop(input_device);
+  drop(output_device);
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::device::backend::{
+      file::{FileBackend, FileSource, Pace},
+      memory::MemoryBackend,
+    },
+    crate::test_util::{temp_path, FORMAT},
+  };
+
+  fn parse(args: &[&str]) -> Result<Cli, String> {
+    Cli::parse(args.iter().map(|arg| arg.to_string()))
+  }
+
+  fn loopback_options(args: &[&str]) -> LoopbackOptions {
+    match parse(args).unwrap().command {
+      Command::Loopback(options) => options,
+      command => panic!("parsed as {:?}", command),
+    }
+  }
+
+  #[test]
+  fn loopback_options_are_parsed() {
+    let cli = parse(&[
+      "loopback",
+      "--input",
+      "USB Headset #2",
+      "--output=0",
+      "--rate",
+      "48000",
+      "--duration=30s",
+      "--host-api",
+      "ALSA",
+    ])
+    .unwrap();
+    assert_eq!(cli.host_api.as_deref(), Some("ALSA"));
+    assert_eq!(
+      cli.command,
+      Command::Loopback(LoopbackOptions {
+        input: Some("USB Headset #2".to_string()),
+        output: Some("0".to_string()),
+        rate: Some(48000),
+        buffers: BufferConfig::default(),
+        duration: Some(Duration::from_secs(30)),
+      })
+    );
+
+    assert_eq!(parse(&[]).unwrap().command, Command::Shell);
+    assert_eq!(parse(&["tui"]).unwrap().command, Command::Tui);
+    assert_eq!(parse(&["--host-api", "ALSA", "devices"]).unwrap().command, Command::Devices);
+    assert!(parse(&["devices", "--rate", "48000"]).is_err());
+    assert!(parse(&["loopback", "--rate"]).is_err());
+    assert!(parse(&["loopback", "--duration", "soon"]).is_err());
+    assert!(parse(&["loopback", "devices"]).is_err());
+    assert_eq!(
+      parse(&["-s", "session.txt"]).unwrap().command,
+      Command::Script(PathBuf::from("session.txt"))
+    );
+  }
+
+  #[test]
+  fn loopback_plays_capture_until_it_ends_or_the_duration_is_over() {
+    let format = FORMAT;
+    let backend = MemoryBackend::new(format);
+    let samples: Vec<u8> = (0..800).map(|i| i as u8).collect();
+    backend.push_capture(&samples);
+    // captured before the devices open, the stream reports it as soon as it starts
+    backend.advance(Duration::from_millis(100));
+    backend.end_capture();
+    let options = loopback_options(&["loopback", "--input", "0", "--rate", "8000"]);
+    loopback(&backend, &options, HotPlug::Stop, Resampling::Linear).unwrap();
+    assert_eq!(backend.pull_playback(), samples);
+
+    // a capture which never ends is stopped by the duration
+    let backend = MemoryBackend::new(format);
+    let options = loopback_options(&["loopback", "--duration", "1ms"]);
+    loopback(&backend, &options, HotPlug::Stop, Resampling::Linear).unwrap();
+
+    let options = loopback_options(&["loopback", "--rate", "48000"]);
+    assert!(loopback(&backend, &options, HotPlug::Stop, Resampling::Linear).is_err());
+  }
+
+  #[test]
+  fn file_loopback_finishes_with_the_recording() {
+    let format = FORMAT;
+    let samples: Vec<u8> = (0..12000).map(|i| i as u8).collect();
+    let path = temp_path("loopback.pcm");
+    std::fs::write(&path, &samples).unwrap();
+    let speakers = MemoryBackend::new(format);
+    let backend = (
+      FileBackend::new(FileSource::RawPcm(path.clone(), format), Pace::AsFastAsPossible).unwrap(),
+      speakers.clone(),
+    );
+    let options = loopback_options(&["loopback"]);
+    let result = loopback(&backend, &options, HotPlug::Stop, Resampling::Linear);
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+    assert_eq!(speakers.pull_playback(), samples);
+  }
+
+  #[test]
+  fn durations_take_units() {
+    assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+    assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+    assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+    assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+    assert_eq!(parse_duration("2h"), None);
+    assert_eq!(parse_duration("s"), None);
+  }
+}
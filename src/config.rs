@@ -0,0 +1,353 @@
+use {
+  crate::device::{convert::Resampling, hotplug::HotPlug, info::*, jitter::JitterConfig},
+  std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+  },
+};
+
+// Selection of a device as it is saved, the device is identified by name so it is found again after
+// indices shifted
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedSelection {
+  pub device: DeviceId,
+  pub format: DeviceFormat,
+  pub buffers: BufferConfig,
+}
+
+// What the shell restores on startup. Kept in a small TOML file, everything missing or unreadable
+// in it falls back to the defaults
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+  pub input: Option<SavedSelection>,
+  pub output: Option<SavedSelection>,
+  pub hot_plug: HotPlug,
+  pub resampling: Resampling,
+  pub jitter: JitterConfig,
+  // gain applied to everything played
+  pub volume: f32,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      input: None,
+      output: None,
+      hot_plug: HotPlug::SameDevice,
+      resampling: Resampling::Sinc,
+      jitter: JitterConfig::default(),
+      volume: 1.0,
+    }
+  }
+}
+
+// %APPDATA%\divana\config.toml on windows, $XDG_CONFIG_HOME/divana/config.toml or
+// ~/.config/divana/config.toml elsewhere
+pub fn default_path() -> Option<PathBuf> {
+  let base = if cfg!(windows) {
+    env::var_os("APPDATA").map(PathBuf::from)
+  } else {
+    env::var_os("XDG_CONFIG_HOME")
+      .map(PathBuf::from)
+      .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+  };
+  base.map(|base| base.join("divana").join("config.toml"))
+}
+
+impl SavedSelection {
+  // device this selection was made for. If it is gone an equally named one is taken, then the first
+  // device. Saved format is kept only if the device still supports it, what was replaced goes to `out`
+  pub fn restore(&self, devices: &[DeviceInfo], out: &mut dyn FnMut(String)) -> Option<(DeviceInfo, DeviceFormat)> {
+    let device = DeviceInfo::find(devices, &self.device)
+      .or_else(|| devices.iter().find(|device| device.id.name == self.device.name))
+      .or_else(|| devices.first())?;
+    if device.id != self.device {
+      out(format!("saved device {} is not present, using {}", self.device, device));
+    }
+    if device.supports(&self.format) {
+      return Some((device.clone(), self.format));
+    }
+    let format = device.get_best_format()?;
+    out(format!(
+      "{} does not support saved format {}, using {}",
+      device, self.format, format
+    ));
+    Some((device.clone(), format))
+  }
+}
+
+impl Config {
+  // missing file is a fresh start, nothing to report
+  pub fn load(path: &Path, out: &mut dyn FnMut(String)) -> Config {
+    match fs::read_to_string(path) {
+      Ok(text) => Config::parse(&text, out),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Config::default(),
+      Err(err) => {
+        out(format!("WARN: cannot read {}: {}", path.display(), err));
+        Config::default()
+      }
+    }
+  }
+
+  pub fn save(&self, path: &Path) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+      fs::create_dir_all(directory)?;
+    }
+    fs::write(path, self.to_string())
+  }
+
+  // lines which do not parse are reported to `out` and skipped, a selection missing any of its keys is dropped
+  pub fn parse(text: &str, out: &mut dyn FnMut(String)) -> Config {
+    let mut config = Config::default();
+    let mut input = PartialSelection::default();
+    let mut output = PartialSelection::default();
+    let mut section = String::new();
+    for (number, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      if line.starts_with('[') && line.ends_with(']') {
+        section = line[1..line.len() - 1].trim().to_string();
+        continue;
+      }
+      let parsed = line.find('=').and_then(|at| {
+        let (key, value) = (line[..at].trim(), parse_value(line[at + 1..].trim())?);
+        match section.as_str() {
+          "input" => input.set(key, &value),
+          "output" => output.set(key, &value),
+          "stream" => config.set(key, &value),
+          _ => Some(()),
+        }
+      });
+      if parsed.is_none() {
+        out(format!("WARN: config line {} ignored: {}", number + 1, line));
+      }
+    }
+    if config.jitter.min_delay > config.jitter.max_delay {
+      out(format!(
+        "WARN: config jitter_min_delay_ms {} is above jitter_max_delay_ms {}, using the defaults",
+        config.jitter.min_delay.as_millis(),
+        config.jitter.max_delay.as_millis()
+      ));
+      config.jitter = JitterConfig::default();
+    }
+    config.input = input.complete();
+    config.output = output.complete();
+    config
+  }
+
+  fn set(&mut self, key: &str, value: &str) -> Option<()> {
+    match key {
+      "hotplug" => self.hot_plug = HotPlug::from_name(value)?,
+      "resampling" => self.resampling = Resampling::from_name(value)?,
+      "volume" => self.volume = value.parse().ok().filter(|volume: &f32| volume.is_finite() && *volume >= 0.0)?,
+      "jitter_min_delay_ms" => self.jitter.min_delay = parse_delay(value)?,
+      "jitter_max_delay_ms" => self.jitter.max_delay = parse_delay(value)?,
+      // written by a newer version
+      _ => {}
+    }
+    Some(())
+  }
+}
+
+impl fmt::Display for Config {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "# divana settings, rewritten whenever they are changed in the shell")?;
+    for (section, selection) in [("input", &self.input), ("output", &self.output)].iter() {
+      if let Some(selection) = selection {
+        writeln!(f, "\n[{}]", section)?;
+        writeln!(f, "device = {}", quote(&selection.device.name))?;
+        writeln!(f, "ordinal = {}", selection.device.ordinal)?;
+        writeln!(f, "frequency = {}", selection.format.frequency)?;
+        writeln!(f, "channels = {}", selection.format.channels)?;
+        writeln!(f, "bits = {}", selection.format.bits)?;
+        writeln!(f, "encoding = {}", quote(encoding_name(selection.format.encoding)))?;
+        writeln!(f, "buffers = {}", quote(&selection.buffers.to_string()))?;
+      }
+    }
+    writeln!(f, "\n[stream]")?;
+    writeln!(f, "hotplug = {}", quote(self.hot_plug.name()))?;
+    writeln!(f, "resampling = {}", quote(self.resampling.name()))?;
+    writeln!(f, "volume = {}", self.volume)?;
+    writeln!(f, "jitter_min_delay_ms = {}", self.jitter.min_delay.as_millis())?;
+    writeln!(f, "jitter_max_delay_ms = {}", self.jitter.max_delay.as_millis())
+  }
+}
+
+// keys of a selection section collected until the whole file is read
+#[derive(Default)]
+struct PartialSelection {
+  name: Option<String>,
+  ordinal: u32,
+  frequency: Option<u32>,
+  channels: Option<u16>,
+  bits: Option<u16>,
+  encoding: Option<Encoding>,
+  buffers: Option<BufferConfig>,
+}
+
+impl PartialSelection {
+  fn set(&mut self, key: &str, value: &str) -> Option<()> {
+    match key {
+      "device" => self.name = Some(value.to_string()),
+      "ordinal" => self.ordinal = value.parse().ok()?,
+      "frequency" => self.frequency = Some(value.parse().ok()?),
+      "channels" => self.channels = Some(value.parse().ok()?),
+      "bits" => self.bits = Some(value.parse().ok()?),
+      "encoding" => {
+        self.encoding = Some(
+          *[Encoding::Pcm, Encoding::Float]
+            .iter()
+            .find(|encoding| encoding_name(**encoding) == value)?,
+        )
+      }
+      "buffers" => self.buffers = Some(BufferConfig::parse(value)?),
+      _ => {}
+    }
+    Some(())
+  }
+
+  fn complete(self) -> Option<SavedSelection> {
+    Some(SavedSelection {
+      device: DeviceId {
+        name: self.name?,
+        ordinal: self.ordinal,
+      },
+      format: DeviceFormat {
+        frequency: self.frequency?,
+        channels: self.channels?,
+        bits: self.bits?,
+        encoding: self.encoding?,
+      },
+      buffers: self.buffers.unwrap_or_default(),
+    })
+  }
+}
+
+fn encoding_name(encoding: Encoding) -> &'static str {
+  match encoding {
+    Encoding::Pcm => "pcm",
+    Encoding::Float => "float",
+  }
+}
+
+// whole milliseconds, a jitter buffer without any delay cannot play
+fn parse_delay(value: &str) -> Option<Duration> {
+  value.parse().ok().filter(|&delay| delay > 0).map(Duration::from_millis)
+}
+
+fn quote(text: &str) -> String {
+  format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// quoted string with \" and \\ escapes or a bare number
+fn parse_value(text: &str) -> Option<String> {
+  if !text.starts_with('"') {
+    return Some(text.to_string()).filter(|text| text.parse::<f64>().is_ok());
+  }
+  let mut value = String::new();
+  let mut chars = text[1..].chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => value.push(chars.next()?),
+      '"' => return Some(value).filter(|_| chars.as_str().trim().is_empty()),
+      c => value.push(c),
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FORMAT: DeviceFormat = DeviceFormat {
+    frequency: 48000,
+    channels: 2,
+    bits: 24,
+    encoding: Encoding::Pcm,
+  };
+
+  // parsed config and what was reported about it
+  fn parse(text: &str) -> (Config, Vec<String>) {
+    let mut warnings = Vec::new();
+    let config = Config::parse(text, &mut |warning| warnings.push(warning));
+    (config, warnings)
+  }
+
+  #[test]
+  fn config_survives_save_and_load() {
+    let config = Config {
+      input: Some(SavedSelection {
+        device: DeviceId {
+          name: "Mic \"Pro\" \\ USB".to_string(),
+          ordinal: 1,
+        },
+        format: FORMAT,
+        buffers: BufferConfig::parse("3x10").unwrap(),
+      }),
+      output: None,
+      hot_plug: HotPlug::FallBackToDefault,
+      resampling: Resampling::Linear,
+      jitter: JitterConfig {
+        min_delay: Duration::from_millis(60),
+        max_delay: Duration::from_millis(300),
+      },
+      volume: 0.25,
+    };
+    assert_eq!(parse(&config.to_string()), (config, Vec::new()));
+
+    let broken = "[input]\ndevice = \"Mic\"\nfrequency = fast\n[stream]\nresampling = \"sinc\"\nunknown = 1\nvolume = inf\n";
+    let (parsed, warnings) = parse(broken);
+    assert_eq!(parsed.input, None);
+    assert_eq!(parsed.resampling, Resampling::Sinc);
+    assert_eq!(parsed.volume, 1.0);
+    assert_eq!(
+      warnings,
+      [
+        "WARN: config line 3 ignored: frequency = fast",
+        "WARN: config line 7 ignored: volume = inf",
+      ]
+    );
+
+    let delays = |text: &str| {
+      let jitter = parse(&format!("[stream]\n{}", text)).0.jitter;
+      (jitter.min_delay.as_millis(), jitter.max_delay.as_millis())
+    };
+    assert_eq!(delays("jitter_min_delay_ms = 20\njitter_max_delay_ms = 100"), (20, 100));
+    assert_eq!(delays("jitter_min_delay_ms = 0\njitter_max_delay_ms = 0"), (40, 500));
+    assert_eq!(delays("jitter_min_delay_ms = 300\njitter_max_delay_ms = 100"), (40, 500));
+  }
+
+  #[test]
+  fn missing_device_falls_back() {
+    let mut devices = vec![
+      DeviceInfo::new(0, "Speakers".to_string(), vec![FORMAT]),
+      DeviceInfo::new(1, "Headset".to_string(), vec![FORMAT]),
+    ];
+    DeviceInfo::assign_ordinals(&mut devices);
+    let restore = |selection: SavedSelection, devices: &[DeviceInfo]| selection.restore(devices, &mut |_| {});
+    let saved = |name: &str, ordinal, format| SavedSelection {
+      device: DeviceId {
+        name: name.to_string(),
+        ordinal,
+      },
+      format,
+      buffers: BufferConfig::default(),
+    };
+
+    let (device, format) = restore(saved("Headset", 0, FORMAT), &devices).unwrap();
+    assert_eq!((device.index, format), (1, FORMAT));
+    // second headset is unplugged, the first one is equally good
+    assert_eq!(restore(saved("Headset", 1, FORMAT), &devices).unwrap().0.index, 1);
+    assert_eq!(restore(saved("Gone", 0, FORMAT), &devices).unwrap().0.index, 0);
+    let mut warnings = Vec::new();
+    saved("Gone", 0, FORMAT).restore(&devices, &mut |warning| warnings.push(warning));
+    assert_eq!(warnings, ["saved device Gone is not present, using Speakers"]);
+    let mono = DeviceFormat { channels: 1, ..FORMAT };
+    assert_eq!(restore(saved("Headset", 0, mono), &devices).unwrap().1, FORMAT);
+    assert!(restore(saved("Headset", 0, FORMAT), &[]).is_none());
+  }
+}
@@ -0,0 +1,517 @@
+use {
+  crate::device::{
+    backend::*,
+    common::*,
+    error::{spawn_error, DeviceError},
+    info::*,
+    pool::BufferPool,
+    wav,
+  },
+  std::{
+    cmp::min,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Arc,
+    },
+    thread,
+    time::Instant,
+  },
+};
+
+#[derive(Clone)]
+pub enum FileSource {
+  Wav(PathBuf),
+  // headerless samples, format has to be known in advance
+  RawPcm(PathBuf, DeviceFormat),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pace {
+  // hand out data no faster than a real device would capture it
+  RealTime,
+  // hand out one buffer period on each read, as if the device completed its buffers back to back
+  AsFastAsPossible,
+}
+
+// Virtual input device replaying a recording as if it was captured
+#[derive(Clone)]
+pub struct FileBackend {
+  path: PathBuf,
+  format: DeviceFormat,
+  data_offset: u64,
+  data_length: u64,
+  pace: Pace,
+}
+
+impl FileBackend {
+  pub fn new(source: FileSource, pace: Pace) -> io::Result<FileBackend> {
+    let (path, format, data_offset, data_length) = match source {
+      FileSource::Wav(path) => {
+        let file = File::open(&path)?;
+        let file_length = file.metadata()?.len();
+        let mut reader = CountingReader {
+          inner: BufReader::new(file),
+          position: 0,
+        };
+        let header = wav::read_header(&mut reader)?;
+        // unfinished recordings have zero or u32::MAX in data length
+        let available = file_length - reader.position;
+        let data_length = match header.data_length {
+          0 => available,
+          length => min(length as u64, available),
+        };
+        (path, header.format, reader.position, data_length)
+      }
+      FileSource::RawPcm(path, format) => {
+        let data_length = File::open(&path)?.metadata()?.len();
+        (path, format, 0, data_length)
+      }
+    };
+    Ok(FileBackend {
+      path,
+      format,
+      data_offset,
+      data_length,
+      pace,
+    })
+  }
+}
+
+// counts bytes consumed by wav::read_header to find where the samples start
+struct CountingReader<R> {
+  inner: R,
+  position: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let count = self.inner.read(buf)?;
+    self.position += count as u64;
+    Ok(count)
+  }
+}
+
+impl CaptureBackend for FileBackend {
+  type Capture = FileCapture;
+
+  fn input_devices(&self) -> Vec<DeviceInfo> {
+    let name = match self.path.file_name() {
+      Some(name) => name.to_string_lossy().into_owned(),
+      None => self.path.to_string_lossy().into_owned(),
+    };
+    vec![DeviceInfo::new(0, name, vec![self.format])]
+  }
+
+  fn open_capture(
+    &self,
+    format: DeviceFormat,
+    buffers: BufferConfig,
+    _device_index: u32,
+    notifier: Notifier,
+  ) -> Result<FileCapture, DeviceError> {
+    if format != self.format {
+      println!(
+        "WARN: file {} is replayed as {}, requested format {} ignored",
+        self.path.display(),
+        self.format,
+        format
+      );
+    }
+    let ring = buffers.buffer_length(&self.format) * buffers.count as usize;
+    Ok(FileCapture {
+      backend: self.clone(),
+      reader: None,
+      started: Instant::now(),
+      position: 0,
+      buffers,
+      notifier,
+      data: Vec::with_capacity(ring),
+      // two more than the pipeline holds: one being filled, one being played
+      pool: BufferPool::new(PIPELINE_DEPTH + 2, buffers.ring_samples(&self.format)),
+      ticker: None,
+    })
+  }
+}
+
+pub struct FileCapture {
+  backend: FileBackend,
+  reader: Option<BufReader<File>>,
+  started: Instant,
+  position: u64,
+  buffers: BufferConfig,
+  notifier: Notifier,
+  // bytes of the last read, kept so reads do not allocate
+  data: Vec<u8>,
+  pool: BufferPool<f32>,
+  // a file has no driver to report completed buffers, in real time pace this thread plays its role
+  ticker: Option<(Arc<AtomicBool>, thread::JoinHandle<()>)>,
+}
+
+impl FileCapture {
+  fn bytes_per_second(&self) -> u64 {
+    let format = &self.backend.format;
+    format.frequency as u64 * block_align(format)
+  }
+}
+
+fn block_align(format: &DeviceFormat) -> u64 {
+  format.block_align() as u64
+}
+
+fn io_error(action: &str, path: &Path, err: io::Error) -> DeviceError {
+  DeviceError::Backend {
+    backend: "file",
+    code: err.raw_os_error().unwrap_or(0),
+    description: format!("cannot {} {}: {}", action, path.display(), err),
+  }
+}
+
+impl CaptureStream for FileCapture {
+  fn start(&mut self) -> Result<(), DeviceError> {
+    let path = &self.backend.path;
+    let mut file = File::open(path).map_err(|err| DeviceError::OpenFailed {
+      reason: format!("cannot open {}: {}", path.display(), err),
+    })?;
+    file
+      .seek(SeekFrom::Start(self.backend.data_offset))
+      .map_err(|err| io_error("seek", path, err))?;
+    self.reader = Some(BufReader::new(file));
+    self.started = Instant::now();
+    self.position = 0;
+    match self.backend.pace {
+      Pace::RealTime => {
+        let running = Arc::new(AtomicBool::new(true));
+        let ticking = running.clone();
+        let notifier = self.notifier.clone();
+        let period = self.buffers.period;
+        let thread = thread::Builder::new()
+          .name("file ticker".into())
+          .spawn(move || {
+            while ticking.load(Ordering::Relaxed) {
+              thread::sleep(period);
+              notifier.notify();
+            }
+          })
+          .map_err(spawn_error)?;
+        self.ticker = Some((running, thread));
+      }
+      // whole file is available right away
+      Pace::AsFastAsPossible => self.notifier.notify(),
+    }
+    Ok(())
+  }
+
+  // reports EndOfStream once the whole recording was handed out
+  fn read(&mut self) -> Result<Option<AudioBuffer<f32>>, DeviceError> {
+    if self.reader.is_some() && self.position >= self.backend.data_length {
+      return Err(DeviceError::EndOfStream);
+    }
+    let block_align = block_align(&self.backend.format);
+    let period = self.buffers.buffer_length(&self.backend.format) as u64;
+    let due = match self.backend.pace {
+      Pace::RealTime => {
+        let captured = (self.started.elapsed().as_secs_f64() * self.bytes_per_second() as f64) as u64;
+        captured.saturating_sub(self.position)
+      }
+      Pace::AsFastAsPossible => period,
+    };
+    // a late reader gets no more than the ring of device buffers holds, the rest comes with the next read
+    let ring = period * self.buffers.count as u64;
+    let remaining = self.backend.data_length - self.position;
+    let length = min(min(due, ring), remaining) / block_align * block_align;
+    let reader = match self.reader.as_mut() {
+      Some(reader) if length > 0 => reader,
+      _ => return Ok(None),
+    };
+    self.data.resize(length as usize, 0);
+    if let Err(err) = reader.read_exact(&mut self.data) {
+      self.position = self.backend.data_length;
+      return Err(io_error("read", &self.backend.path, err));
+    }
+    self.position += length;
+    // next read hands out the rest, or reports the end
+    if self.backend.pace == Pace::AsFastAsPossible {
+      self.notifier.notify();
+    }
+    Ok(Some(AudioBuffer::from_pooled_bytes(self.backend.format, &self.data, &self.pool)))
+  }
+
+  fn stop(&mut self) -> Result<(), DeviceError> {
+    if let Some((running, thread)) = self.ticker.take() {
+      running.store(false, Ordering::Relaxed);
+      thread.join().unwrap();
+    }
+    self.reader = None;
+    Ok(())
+  }
+}
+
+// Virtual output device recording everything it is asked to play into a wav file
+#[derive(Clone)]
+pub struct WavRecorder {
+  path: PathBuf,
+}
+
+impl WavRecorder {
+  pub fn new<P: Into<PathBuf>>(path: P) -> WavRecorder {
+    WavRecorder { path: path.into() }
+  }
+}
+
+impl PlaybackBackend for WavRecorder {
+  type Playback = WavSink;
+
+  fn output_devices(&self) -> Vec<DeviceInfo> {
+    // wav takes anything, nothing to probe
+    let formats = DeviceFormat::probe(&[1, 2], |_| true);
+    vec![DeviceInfo::new(0, self.path.to_string_lossy().into_owned(), formats)]
+  }
+
+  fn open_playback(&self, format: DeviceFormat, _buffers: BufferConfig, _device_index: u32) -> Result<WavSink, DeviceError> {
+    Ok(WavSink::new(&self.path, format))
+  }
+}
+
+pub struct WavSink {
+  path: PathBuf,
+  format: DeviceFormat,
+  writer: Option<BufWriter<File>>,
+  data_length: u32,
+}
+
+impl WavSink {
+  pub fn new<P: Into<PathBuf>>(path: P, format: DeviceFormat) -> WavSink {
+    WavSink {
+      path: path.into(),
+      format,
+      writer: None,
+      data_length: 0,
+    }
+  }
+
+  // rewrites header with the final sizes, also called on drop so recording survives a dead device thread
+  fn finalize(&mut self) -> io::Result<()> {
+    let mut writer = match self.writer.take() {
+      Some(writer) => writer,
+      None => return Ok(()),
+    };
+    writer.seek(SeekFrom::Start(0))?;
+    wav::write_header(&mut writer, &self.format, self.data_length)?;
+    writer.flush()
+  }
+}
+
+impl PlaybackStream for WavSink {
+  fn start(&mut self) -> Result<(), DeviceError> {
+    let mut writer = match File::create(&self.path) {
+      Ok(file) => BufWriter::new(file),
+      Err(err) => {
+        return Err(DeviceError::OpenFailed {
+          reason: format!("cannot create {}: {}", self.path.display(), err),
+        })
+      }
+    };
+    wav::write_header(&mut writer, &self.format, 0).map_err(|err| io_error("write", &self.path, err))?;
+    self.writer = Some(writer);
+    self.data_length = 0;
+    Ok(())
+  }
+
+  fn write(&mut self, buffer: &AudioBuffer<f32>) -> Result<(), DeviceError> {
+    let writer = match self.writer.as_mut() {
+      Some(writer) => writer,
+      None => return Ok(()),
+    };
+    let data = buffer.to_bytes();
+    let max_length = u32::MAX - wav::header_length(&self.format);
+    if self.data_length.saturating_add(data.len() as u32) > max_length {
+      println!("WARN: WavSink: {} reached wav size limit, data dropped", self.path.display());
+      return Ok(());
+    }
+    writer.write_all(&data).map_err(|err| io_error("write", &self.path, err))?;
+    self.data_length += data.len() as u32;
+    Ok(())
+  }
+
+  fn stop(&mut self) -> Result<(), DeviceError> {
+    self.finalize().map_err(|err| io_error("finalize", &self.path, err))
+  }
+}
+
+impl Drop for WavSink {
+  fn drop(&mut self) {
+    if let Err(err) = self.finalize() {
+      println!("WavSink: cannot finalize {}: {}", self.path.display(), err);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::device::{
+      convert::Resampling,
+      hotplug::{DeviceEvent, HotPlug},
+      input::InputDevice,
+      output::OutputDevice,
+    },
+    crate::test_util::{temp_path, FORMAT},
+    std::{fs, sync::mpsc, time::Duration},
+  };
+
+  fn write_wav(path: &Path, format: DeviceFormat, samples: &[u8]) {
+    let mut file = Vec::new();
+    wav::write_header(&mut file, &format, samples.len() as u32).unwrap();
+    file.extend_from_slice(samples);
+    fs::write(path, file).unwrap();
+  }
+
+  #[test]
+  fn wav_is_replayed_in_period_chunks() {
+    let format = DeviceFormat {
+      frequency: 11025,
+      channels: 1,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    };
+    // 220 frames in 20ms
+    let buffers = BufferConfig {
+      count: 4,
+      period: Duration::from_millis(20),
+    };
+    let samples: Vec<u8> = (0..440 * 5 + 100).map(|i| i as u8).collect();
+    let path = temp_path("replay.wav");
+    write_wav(&path, format, &samples);
+
+    let backend = FileBackend::new(FileSource::Wav(path.clone()), Pace::AsFastAsPossible).unwrap();
+    let devices = backend.input_devices();
+    assert_eq!(devices.len(), 1);
+    assert!(devices[0].get_best_format() == Some(format));
+
+    let mut capture = backend.open_capture(format, buffers, 0, Notifier::new(|| {})).unwrap();
+    capture.start().unwrap();
+    let mut chunks = Vec::new();
+    let end = loop {
+      match capture.read() {
+        Ok(Some(buffer)) => chunks.push(buffer.to_bytes()),
+        result => break result.map(|_| ()),
+      }
+    };
+    capture.stop().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let lengths: Vec<usize> = chunks.iter().map(|chunk| chunk.len()).collect();
+    assert_eq!(lengths, vec![440, 440, 440, 440, 440, 100]);
+    assert_eq!(chunks.concat(), samples);
+    assert_eq!(end, Err(DeviceError::EndOfStream));
+  }
+
+  #[test]
+  fn real_time_pace_follows_clock() {
+    let format = FORMAT;
+    let path = temp_path("realtime.pcm");
+    fs::write(&path, vec![128u8; 8000]).unwrap();
+
+    let backend = FileBackend::new(FileSource::RawPcm(path.clone(), format), Pace::RealTime).unwrap();
+    let (ticks, ticked) = mpsc::sync_channel(64);
+    let notifier = Notifier::new(move || {
+      let _ = ticks.try_send(());
+    });
+    let mut capture = backend.open_capture(format, BufferConfig::default(), 0, notifier).unwrap();
+    let started = Instant::now();
+    capture.start().unwrap();
+    // whatever the scheduler does, no tick hands out more than the clock has played since start
+    let mut frames = 0;
+    while frames < 400 {
+      ticked.recv_timeout(Duration::from_secs(5)).expect("file ticker stopped");
+      frames += capture.read().unwrap().map(|buffer| buffer.frames()).unwrap_or(0);
+      let elapsed = started.elapsed().as_secs_f64();
+      assert!(frames as f64 <= elapsed * 8000.0, "{} frames handed out after {}s", frames, elapsed);
+    }
+    capture.stop().unwrap();
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn wav_sink_finalizes_sizes_on_stop() {
+    let format = DeviceFormat {
+      frequency: 22050,
+      channels: 1,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    };
+    let path = temp_path("sink.wav");
+    let mut sink = WavRecorder::new(&path).open_playback(format, BufferConfig::default(), 0).unwrap();
+    sink.start().unwrap();
+    sink.write(&AudioBuffer::from_bytes(format, &[1, 2, 3, 4])).unwrap();
+    sink.write(&AudioBuffer::from_bytes(format, &[5, 6])).unwrap();
+    sink.stop().unwrap();
+
+    let file = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let mut reader = &file[..];
+    let header = wav::read_header(&mut reader).unwrap();
+    assert!(header.format == format);
+    assert_eq!(header.data_length, 6);
+    assert_eq!(&file[4..8], &(wav::header_length(&format) - 8 + 6).to_le_bytes());
+    assert_eq!(reader, &[1, 2, 3, 4, 5, 6]);
+  }
+
+  #[test]
+  fn replayed_file_reaches_recorder() {
+    let format = DeviceFormat {
+      frequency: 11025,
+      channels: 1,
+      bits: 8,
+      encoding: Encoding::Pcm,
+    };
+    let samples: Vec<u8> = (0..11025 * 3 / 2).map(|i| (i % 251) as u8).collect();
+    let input_path = temp_path("pipeline-in.wav");
+    let output_path = temp_path("pipeline-out.wav");
+    write_wav(&input_path, format, &samples);
+
+    let backend = (
+      FileBackend::new(FileSource::Wav(input_path.clone()), Pace::AsFastAsPossible).unwrap(),
+      WavRecorder::new(&output_path),
+    );
+    let buffers = BufferConfig::default();
+    let output = OutputDevice::new(
+      &backend,
+      format,
+      buffers,
+      &backend.output_devices()[0],
+      HotPlug::Stop,
+      Resampling::Linear,
+    )
+    .unwrap();
+    let input = InputDevice::new(
+      &backend,
+      format,
+      buffers,
+      &backend.input_devices()[0],
+      HotPlug::Stop,
+      output.sender.clone(),
+    )
+    .unwrap();
+    assert_eq!(
+      input.wait_event(Duration::from_secs(5)),
+      Some(DeviceEvent::Ended),
+      "replay has not ended"
+    );
+    drop(input);
+    drop(output);
+
+    let file = fs::read(&output_path).unwrap();
+    fs::remove_file(&input_path).unwrap();
+    fs::remove_file(&output_path).unwrap();
+    let mut reader = &file[..];
+    let header = wav::read_header(&mut reader).unwrap();
+    assert!(header.format == format);
+    assert_eq!(header.data_length as usize, samples.len());
+    assert_eq!(reader, &samples[..]);
+  }
+}
@@ -0,0 +1,404 @@
+use {
+  crate::device::{backend::*, common::*, error::DeviceError, info::*, pool::BufferPool},
+  std::{
+    cmp::min,
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+  },
+};
+
+// In-memory loopback device pair: tests push PCM into the fake microphone, advance the virtual clock
+// to let the input thread capture it and pull whatever reached the fake speakers
+#[derive(Clone)]
+pub struct MemoryBackend {
+  format: DeviceFormat,
+  shared: Arc<(Mutex<MemoryState>, Condvar)>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+  // virtual clock, only moves in advance
+  now: Duration,
+  // pushed by test, not yet captured according to the virtual clock
+  pending: VecDeque<u8>,
+  // captured, waiting for the input thread to read it
+  ready: VecDeque<u8>,
+  released: u64,
+  played: Vec<u8>,
+  // one per open capture stream by its id, fired like a driver completion callback
+  notifiers: Vec<(u64, Notifier)>,
+  next_stream: u64,
+  // returned by whichever stream operation comes next
+  failure: Option<DeviceError>,
+  // devices are not listed and every stream operation fails until plugged back
+  unplugged: bool,
+  // nothing is pushed anymore, capture ends once the rest was read
+  ended: bool,
+}
+
+impl MemoryBackend {
+  pub fn new(format: DeviceFormat) -> MemoryBackend {
+    MemoryBackend {
+      format,
+      shared: Arc::new((Mutex::new(MemoryState::default()), Condvar::new())),
+    }
+  }
+
+  fn block_align(&self) -> u64 {
+    self.format.block_align() as u64
+  }
+
+  // makes the next open, start, read or write fail the way a real device would
+  pub fn fail_next(&self, error: DeviceError) {
+    let (state, _) = &*self.shared;
+    state.lock().unwrap().failure = Some(error);
+  }
+
+  pub fn unplug(&self) {
+    let (state, _) = &*self.shared;
+    state.lock().unwrap().unplugged = true;
+  }
+
+  pub fn plug(&self) {
+    let (state, _) = &*self.shared;
+    state.lock().unwrap().unplugged = false;
+  }
+
+  fn check_failure(&self) -> Result<(), DeviceError> {
+    let (state, _) = &*self.shared;
+    let mut state = state.lock().unwrap();
+    if state.unplugged {
+      return Err(DeviceError::Removed);
+    }
+    match state.failure.take() {
+      Some(error) => Err(error),
+      None => Ok(()),
+    }
+  }
+
+  pub fn push_capture(&self, data: &[u8]) {
+    let (state, _) = &*self.shared;
+    state.lock().unwrap().pending.extend(data);
+  }
+
+  // capture reports the end like a replayed recording does, once everything pushed was captured and read
+  pub fn end_capture(&self) {
+    let notifiers = {
+      let (state, _) = &*self.shared;
+      let mut state = state.lock().unwrap();
+      state.ended = true;
+      state.notifiers.iter().map(|(_, notifier)| notifier.clone()).collect::<Vec<_>>()
+    };
+    for notifier in notifiers {
+      notifier.notify();
+    }
+  }
+
+  // moves `step` worth of pushed audio to the capture device and notifies the input thread if anything was captured
+  pub fn advance(&self, step: Duration) {
+    let notifiers = {
+      let (state, _) = &*self.shared;
+      let mut state = state.lock().unwrap();
+      let block_align = self.block_align();
+      state.now += step;
+      // whole frames by the clock, in integers so equal steps capture equal frame counts
+      let total = (state.now.as_nanos() * self.format.frequency as u128 / 1_000_000_000) as u64 * block_align;
+      let length = min(total - state.released, state.pending.len() as u64);
+      let captured: Vec<u8> = state.pending.drain(..length as usize).collect();
+      state.ready.extend(captured);
+      state.released = total;
+      if length == 0 {
+        return;
+      }
+      state.notifiers.iter().map(|(_, notifier)| notifier.clone()).collect::<Vec<_>>()
+    };
+    for notifier in notifiers {
+      notifier.notify();
+    }
+  }
+
+  pub fn pull_playback(&self) -> Vec<u8> {
+    let (state, _) = &*self.shared;
+    let mut state = state.lock().unwrap();
+    state.played.drain(..).collect()
+  }
+
+  // blocks until at least `length` bytes were played, returns everything played so far
+  pub fn wait_playback(&self, length: usize, timeout: Duration) -> Vec<u8> {
+    let deadline = Instant::now() + timeout;
+    let (state, played) = &*self.shared;
+    let mut state = state.lock().unwrap();
+    while state.played.len() < length {
+      let now = Instant::now();
+      if now >= deadline {
+        break;
+      }
+      state = played.wait_timeout(state, deadline - now).unwrap().0;
+    }
+    state.played.drain(..).collect()
+  }
+
+  fn devices(&self, name: &str) -> Vec<DeviceInfo> {
+    let (state, _) = &*self.shared;
+    if state.lock().unwrap().unplugged {
+      return Vec::new();
+    }
+    vec![DeviceInfo::new(0, name.to_string(), vec![self.format])]
+  }
+}
+
+impl CaptureBackend for MemoryBackend {
+  type Capture = MemoryCapture;
+
+  fn input_devices(&self) -> Vec<DeviceInfo> {
+    self.devices("memory capture")
+  }
+
+  fn open_capture(
+    &self,
+    format: DeviceFormat,
+    buffers: BufferConfig,
+    _device_index: u32,
+    notifier: Notifier,
+  ) -> Result<MemoryCapture, DeviceError> {
+    self.check_failure()?;
+    if format != self.format {
+      println!("WARN: memory capture works in {}, requested format {} ignored", self.format, format);
+    }
+    let (state, _) = &*self.shared;
+    let mut state = state.lock().unwrap();
+    let id = state.next_stream;
+    state.next_stream += 1;
+    state.notifiers.push((id, notifier.clone()));
+    Ok(MemoryCapture {
+      backend: self.clone(),
+      id,
+      buffers,
+      notifier,
+      data: Vec::with_capacity(buffers.buffer_length(&self.format) * buffers.count as usize),
+      // two more than the pipeline holds: one being filled, one being played
+      pool: BufferPool::new(PIPELINE_DEPTH + 2, buffers.ring_samples(&self.format)),
+    })
+  }
+}
+
+impl PlaybackBackend for MemoryBackend {
+  type Playback = MemoryPlayback;
+
+  fn output_devices(&self) -> Vec<DeviceInfo> {
+    self.devices("memory playback")
+  }
+
+  fn open_playback(&self, format: DeviceFormat, _buffers: BufferConfig, _device_index: u32) -> Result<MemoryPlayback, DeviceError> {
+    self.check_failure()?;
+    if format != self.format {
+      println!(
+        "WARN: memory playback works in {}, requested format {} ignored",
+        self.format, format
+      );
+    }
+    Ok(MemoryPlayback { backend: self.clone() })
+  }
+}
+
+pub struct MemoryCapture {
+  backend: MemoryBackend,
+  id: u64,
+  buffers: BufferConfig,
+  notifier: Notifier,
+  // bytes of the last read, kept so reads do not allocate
+  data: Vec<u8>,
+  pool: BufferPool<f32>,
+}
+
+// a closed stream is not woken anymore, like a driver forgets the callback of a closed device
+impl Drop for MemoryCapture {
+  fn drop(&mut self) {
+    let (state, _) = &*self.backend.shared;
+    state.lock().unwrap().notifiers.retain(|(id, _)| *id != self.id);
+  }
+}
+
+impl CaptureStream for MemoryCapture {
+  // audio captured before the stream was opened is reported right away
+  fn start(&mut self) -> Result<(), DeviceError> {
+    self.backend.check_failure()?;
+    let (state, _) = &*self.backend.shared;
+    if !state.lock().unwrap().ready.is_empty() {
+      self.notifier.notify();
+    }
+    Ok(())
+  }
+
+  fn read(&mut self) -> Result<Option<AudioBuffer<f32>>, DeviceError> {
+    self.backend.check_failure()?;
+    let (state, _) = &*self.backend.shared;
+    let mut state = state.lock().unwrap();
+    // at most what the ring of device buffers holds
+    let ring = self.buffers.buffer_length(&self.backend.format) * self.buffers.count as usize;
+    let length = min(state.ready.len(), ring);
+    if length == 0 {
+      if state.ended && state.pending.is_empty() {
+        return Err(DeviceError::EndOfStream);
+      }
+      return Ok(None);
+    }
+    self.data.clear();
+    self.data.extend(state.ready.drain(..length));
+    // the rest does not fit in the ring or the end is to be reported, either completes right away
+    if !state.ready.is_empty() || state.ended {
+      self.notifier.notify();
+    }
+    Ok(Some(AudioBuffer::from_pooled_bytes(self.backend.format, &self.data, &self.pool)))
+  }
+
+  fn stop(&mut self) -> Result<(), DeviceError> {
+    Ok(())
+  }
+}
+
+pub struct MemoryPlayback {
+  backend: MemoryBackend,
+}
+
+impl PlaybackStream for MemoryPlayback {
+  fn start(&mut self) -> Result<(), DeviceError> {
+    self.backend.check_failure()
+  }
+
+  fn write(&mut self, buffer: &AudioBuffer<f32>) -> Result<(), DeviceError> {
+    self.backend.check_failure()?;
+    let (state, played) = &*self.backend.shared;
+    buffer.write_bytes(&mut state.lock().unwrap().played);
+    played.notify_all();
+    Ok(())
+  }
+
+  fn stop(&mut self) -> Result<(), DeviceError> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::device::{
+      convert::Resampling,
+      hotplug::{DeviceEvent, HotPlug},
+      input::InputDevice,
+      output::OutputDevice,
+    },
+    crate::test_util::FORMAT,
+  };
+
+  const TIMEOUT: Duration = Duration::from_secs(5);
+
+  fn open_pair(backend: &MemoryBackend, hot_plug: HotPlug) -> (InputDevice, OutputDevice) {
+    let buffers = BufferConfig::default();
+    let output = OutputDevice::new(backend, FORMAT, buffers, &backend.output_devices()[0], hot_plug, Resampling::Linear).unwrap();
+    let input = InputDevice::new(
+      backend,
+      FORMAT,
+      buffers,
+      &backend.input_devices()[0],
+      hot_plug,
+      output.sender.clone(),
+    )
+    .unwrap();
+    (input, output)
+  }
+
+  #[test]
+  fn input_is_delivered_to_output_as_clock_advances() {
+    let backend = MemoryBackend::new(FORMAT);
+    let (input, output) = open_pair(&backend, HotPlug::Stop);
+
+    let samples: Vec<u8> = (0..800).map(|i| i as u8).collect();
+    backend.push_capture(&samples);
+    assert!(backend.pull_playback().is_empty());
+
+    backend.advance(Duration::from_millis(50));
+    assert_eq!(backend.wait_playback(400, TIMEOUT), &samples[..400]);
+
+    backend.advance(Duration::from_millis(25));
+    backend.advance(Duration::from_millis(25));
+    assert_eq!(backend.wait_playback(400, TIMEOUT), &samples[400..]);
+
+    // nothing left to capture, clock ticks do not produce data
+    backend.advance(Duration::from_millis(50));
+    drop(input);
+    drop(output);
+    assert!(backend.pull_playback().is_empty());
+  }
+
+  #[test]
+  fn advance_notifies_only_when_audio_was_captured() {
+    let backend = MemoryBackend::new(FORMAT);
+    let (sender, notifications) = std::sync::mpsc::channel();
+    let mut capture = backend
+      .open_capture(FORMAT, BufferConfig::default(), 0, Notifier::new(move || sender.send(()).unwrap()))
+      .unwrap();
+    capture.start().unwrap();
+
+    backend.advance(Duration::from_millis(10));
+    assert!(notifications.try_recv().is_err());
+
+    backend.push_capture(&[128; 100]);
+    backend.advance(Duration::from_millis(10));
+    assert!(notifications.try_recv().is_ok());
+    assert_eq!(capture.read().unwrap().map(|buffer| buffer.frames()), Some(80));
+    assert!(notifications.try_recv().is_err());
+    capture.stop().unwrap();
+
+    drop(capture);
+    backend.push_capture(&[128; 100]);
+    backend.advance(Duration::from_millis(10));
+    assert!(notifications.try_recv().is_err());
+  }
+
+  #[test]
+  fn device_errors_are_returned_and_reported() {
+    let backend = MemoryBackend::new(FORMAT);
+    let device = backend.output_devices()[0].clone();
+    backend.fail_next(DeviceError::Busy);
+    assert_eq!(
+      OutputDevice::new(
+        &backend,
+        FORMAT,
+        BufferConfig::default(),
+        &device,
+        HotPlug::Stop,
+        Resampling::Linear
+      )
+      .err(),
+      Some(DeviceError::Busy)
+    );
+
+    let (input, output) = open_pair(&backend, HotPlug::Stop);
+    backend.fail_next(DeviceError::Removed);
+    backend.push_capture(&[128; 80]);
+    backend.advance(Duration::from_millis(10));
+    assert_eq!(input.wait_event(TIMEOUT), Some(DeviceEvent::Failed(DeviceError::Removed)));
+    assert!(output.poll_event().is_none());
+  }
+
+  #[test]
+  fn unplugged_device_is_reopened_when_it_comes_back() {
+    let backend = MemoryBackend::new(FORMAT);
+    let id = backend.input_devices()[0].id.clone();
+    let (input, _output) = open_pair(&backend, HotPlug::SameDevice);
+
+    let samples: Vec<u8> = (0..80).map(|i| i as u8).collect();
+    backend.push_capture(&samples);
+    backend.unplug();
+    backend.advance(Duration::from_millis(10));
+    assert_eq!(input.wait_event(TIMEOUT), Some(DeviceEvent::Lost(DeviceError::Removed)));
+
+    backend.plug();
+    assert_eq!(input.wait_event(TIMEOUT), Some(DeviceEvent::Reopened(id)));
+    // audio captured while the device was gone is picked up by the new stream
+    assert_eq!(backend.wait_playback(samples.len(), TIMEOUT), samples);
+  }
+}
@@ -0,0 +1,106 @@
+pub mod file;
+pub mod memory;
+mod notify;
+pub mod portaudio;
+#[cfg(windows)]
+pub mod winmm;
+
+pub use self::notify::Notifier;
+
+use crate::device::{common::AudioBuffer, error::DeviceError, info::*};
+
+// capture side of a backend, owned by the input device thread
+pub trait CaptureStream {
+  fn start(&mut self) -> Result<(), DeviceError>;
+  // returns captured audio if the backend has finished filling some buffer, called after each notification
+  fn read(&mut self) -> Result<Option<AudioBuffer<f32>>, DeviceError>;
+  fn stop(&mut self) -> Result<(), DeviceError>;
+}
+
+// playback side of a backend, owned by the output device thread
+pub trait PlaybackStream {
+  fn start(&mut self) -> Result<(), DeviceError>;
+  // may block until the device has released enough of its buffers, buffer is in the stream format
+  fn write(&mut self, buffer: &AudioBuffer<f32>) -> Result<(), DeviceError>;
+  fn stop(&mut self) -> Result<(), DeviceError>;
+}
+
+// Backend is cloned into the device threads and streams are opened there,
+// so streams themselves are not required to be Send (winmm handles are not)
+pub trait CaptureBackend: Clone + Send + 'static {
+  type Capture: CaptureStream;
+
+  fn input_devices(&self) -> Vec<DeviceInfo>;
+  // same list without the supported formats, polled while waiting for a removed device to come back.
+  // Backends that have to ask the driver about every format override it
+  fn input_devices_unprobed(&self) -> Vec<DeviceInfo> {
+    self.input_devices()
+  }
+  // stream fires `notifier` whenever read would return data
+  fn open_capture(
+    &self,
+    format: DeviceFormat,
+    buffers: BufferConfig,
+    device_index: u32,
+    notifier: Notifier,
+  ) -> Result<Self::Capture, DeviceError>;
+}
+
+pub trait PlaybackBackend: Clone + Send + 'static {
+  type Playback: PlaybackStream;
+
+  fn output_devices(&self) -> Vec<DeviceInfo>;
+  fn output_devices_unprobed(&self) -> Vec<DeviceInfo> {
+    self.output_devices()
+  }
+  fn open_playback(&self, format: DeviceFormat, buffers: BufferConfig, device_index: u32) -> Result<Self::Playback, DeviceError>;
+}
+
+pub trait AudioBackend: CaptureBackend + PlaybackBackend {}
+
+impl<B: CaptureBackend + PlaybackBackend> AudioBackend for B {}
+
+// (capture, playback) pair takes input from the first backend and plays through the second one,
+// e.g. (FileBackend, DefaultBackend) replays a recording to the speakers
+impl<I: CaptureBackend, O: Clone + Send + 'static> CaptureBackend for (I, O) {
+  type Capture = I::Capture;
+
+  fn input_devices(&self) -> Vec<DeviceInfo> {
+    self.0.input_devices()
+  }
+
+  fn input_devices_unprobed(&self) -> Vec<DeviceInfo> {
+    self.0.input_devices_unprobed()
+  }
+
+  fn open_capture(
+    &self,
+    format: DeviceFormat,
+    buffers: BufferConfig,
+    device_index: u32,
+    notifier: Notifier,
+  ) -> Result<Self::Capture, DeviceError> {
+    self.0.open_capture(format, buffers, device_index, notifier)
+  }
+}
+
+impl<I: Clone + Send + 'static, O: PlaybackBackend> PlaybackBackend for (I, O) {
+  type Playback = O::Playback;
+
+  fn output_devices(&self) -> Vec<DeviceInfo> {
+    self.1.output_devices()
+  }
+
+  fn output_devices_unprobed(&self) -> Vec<DeviceInfo> {
+    self.1.output_devices_unprobed()
+  }
+
+  fn open_playback(&self, format: DeviceFormat, buffers: BufferConfig, device_index: u32) -> Result<Self::Playback, DeviceError> {
+    self.1.open_playback(format, buffers, device_index)
+  }
+}
+
+#[cfg(windows)]
+pub type DefaultBackend = winmm::WinmmBackend;
+#[cfg(not(windows))]
+pub type DefaultBackend = self::portaudio::PortAudioBackend;
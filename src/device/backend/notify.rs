@@ -0,0 +1,20 @@
+use std::sync::Arc;
+
+// Completion notification handed to a capture stream when it is opened. Backends fire it from their
+// driver callback (or whatever thread learns about it) each time a buffer has been filled, the input
+// device thread sleeps until then instead of polling
+#[derive(Clone)]
+pub struct Notifier {
+  wake: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl Notifier {
+  pub fn new<F: Fn() + Send + Sync + 'static>(wake: F) -> Notifier {
+    Notifier { wake: Arc::new(wake) }
+  }
+
+  // cheap and non-blocking, safe to call from audio callbacks
+  pub fn notify(&self) {
+    (self.wake)()
+  }
+}
@@ -0,0 +1,487 @@
+use {
+  crate::device::{backend::*, common::*, error::DeviceError, info::*, pool::BufferPool},
+  ::portaudio as pa,
+  std::{
+    sync::{
+      atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+      Arc, Condvar, Mutex,
+    },
+    time::Duration,
+  },
+};
+
+// Samples passed between a PortAudio callback and our thread, one side writes and the other reads.
+// Room is allocated up front and neither side locks, so the real-time thread never waits or allocates.
+// Callers move whole frames into a room of whole frames, so a frame is never split
+struct SampleRing {
+  // f32 bits
+  samples: Box<[AtomicU32]>,
+  // samples ever written and read, positions wrap around `samples`
+  written: AtomicUsize,
+  read: AtomicUsize,
+}
+
+impl SampleRing {
+  fn new(capacity: usize) -> SampleRing {
+    SampleRing {
+      samples: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+      written: AtomicUsize::new(0),
+      read: AtomicUsize::new(0),
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.written.load(Ordering::Acquire).wrapping_sub(self.read.load(Ordering::Acquire))
+  }
+
+  // as many of `samples` as there is room for, returns how many that were
+  fn push(&self, samples: &[f32]) -> usize {
+    let written = self.written.load(Ordering::Relaxed);
+    let room = self.samples.len() - written.wrapping_sub(self.read.load(Ordering::Acquire));
+    let count = samples.len().min(room);
+    for (offset, sample) in samples[..count].iter().enumerate() {
+      self.samples[written.wrapping_add(offset) % self.samples.len()].store(sample.to_bits(), Ordering::Relaxed);
+    }
+    self.written.store(written.wrapping_add(count), Ordering::Release);
+    count
+  }
+
+  // fills the start of `out` with what was written, returns how much of it
+  fn pop(&self, out: &mut [f32]) -> usize {
+    let read = self.read.load(Ordering::Relaxed);
+    let count = out.len().min(self.written.load(Ordering::Acquire).wrapping_sub(read));
+    for (offset, sample) in out[..count].iter_mut().enumerate() {
+      *sample = f32::from_bits(self.samples[read.wrapping_add(offset) % self.samples.len()].load(Ordering::Relaxed));
+    }
+    self.read.store(read.wrapping_add(count), Ordering::Release);
+    count
+  }
+}
+
+// Cross-platform backend, streams are always opened as interleaved f32 which is what the pipeline carries,
+// PortAudio converts to whatever the device takes
+#[derive(Clone, Default)]
+pub struct PortAudioBackend {
+  // None selects host api which PortAudio considers default for the platform
+  host_api: Option<String>,
+  // initialized on first use, shared by clones and the streams they open.
+  // PortAudio is terminated when the last of them lets go
+  pa: Arc<Mutex<Option<Arc<pa::PortAudio>>>>,
+}
+
+impl PortAudioBackend {
+  pub fn with_host_api(name: &str) -> PortAudioBackend {
+    PortAudioBackend {
+      host_api: Some(name.to_string()),
+      pa: Arc::default(),
+    }
+  }
+
+  pub fn host_api_names() -> Vec<String> {
+    match pa::PortAudio::new() {
+      Ok(pa) => pa.host_apis().map(|(_, info)| info.name.to_string()).collect(),
+      Err(err) => {
+        println!("PortAudio: {}", err);
+        Vec::new()
+      }
+    }
+  }
+
+  // PortAudio lists the devices that were present when it was initialized, `renew` starts it over
+  // so a probing listing sees devices plugged in since, unless a stream still uses the instance
+  fn portaudio(&self, renew: bool) -> Result<Arc<pa::PortAudio>, pa::Error> {
+    let mut shared = self.pa.lock().unwrap();
+    if renew && shared.as_ref().is_some_and(|pa| Arc::strong_count(pa) == 1) {
+      *shared = None;
+    }
+    match &*shared {
+      Some(pa) => Ok(pa.clone()),
+      None => {
+        let pa = Arc::new(pa::PortAudio::new()?);
+        *shared = Some(pa.clone());
+        Ok(pa)
+      }
+    }
+  }
+
+  fn host_api_index(&self, pa: &pa::PortAudio) -> Option<pa::HostApiIndex> {
+    match &self.host_api {
+      None => pa.default_host_api().ok(),
+      Some(name) => pa
+        .host_apis()
+        .find(|(_, info)| info.name.eq_ignore_ascii_case(name))
+        .map(|(index, _)| index),
+    }
+  }
+
+  // without `probe` devices are listed with no formats, only the reopen poll wants that
+  fn devices(&self, input: bool, probe: bool) -> Vec<DeviceInfo> {
+    let mut available_devices: Vec<DeviceInfo> = Vec::new();
+    let pa = match self.portaudio(probe) {
+      Ok(pa) => pa,
+      Err(err) => {
+        println!("PortAudio: {}", err);
+        return available_devices;
+      }
+    };
+    let host_api = match self.host_api_index(&pa) {
+      Some(host_api) => host_api,
+      None => {
+        println!("PortAudio: host api {:?} is not available", self.host_api);
+        return available_devices;
+      }
+    };
+    let devices = match pa.devices() {
+      Ok(devices) => devices,
+      Err(err) => {
+        println!("PortAudio devices: {}", err);
+        return available_devices;
+      }
+    };
+    for device in devices {
+      let (device_index, info) = match device {
+        Ok(device) => device,
+        Err(err) => {
+          println!("PortAudio device_info: {}", err);
+          continue;
+        }
+      };
+      if info.host_api != host_api {
+        continue;
+      }
+      let (max_channels, latency) = if input {
+        (info.max_input_channels, info.default_low_input_latency)
+      } else {
+        (info.max_output_channels, info.default_low_output_latency)
+      };
+      if max_channels < 1 {
+        continue;
+      }
+      let pa::DeviceIndex(index) = device_index;
+      if !probe {
+        available_devices.push(DeviceInfo::new(index, info.name.to_string(), Vec::new()));
+        continue;
+      }
+      let channel_counts = DeviceFormat::channel_counts(max_channels as u16);
+      // stream is f32 and PortAudio converts to what the device takes, so only channels and rate are
+      // asked for and every sample type is converted on our side. Answer is kept for the next type
+      let mut checked: Option<((u16, u32), bool)> = None;
+      let formats = DeviceFormat::probe(&channel_counts, |format| {
+        let key = (format.channels, format.frequency);
+        match checked {
+          Some((checked_key, supported)) if checked_key == key => supported,
+          _ => {
+            let params = pa::StreamParameters::<f32>::new(device_index, format.channels as i32, true, latency);
+            let supported = if input {
+              pa.is_input_format_supported(params, format.frequency as f64)
+            } else {
+              pa.is_output_format_supported(params, format.frequency as f64)
+            }
+            .is_ok();
+            checked = Some((key, supported));
+            supported
+          }
+        }
+      });
+      if formats.is_empty() {
+        continue;
+      }
+      available_devices.push(DeviceInfo::new(index, info.name.to_string(), formats));
+    }
+    DeviceInfo::assign_ordinals(&mut available_devices);
+    available_devices
+  }
+}
+
+impl CaptureBackend for PortAudioBackend {
+  type Capture = PortAudioCapture;
+
+  fn input_devices(&self) -> Vec<DeviceInfo> {
+    self.devices(true, true)
+  }
+
+  fn input_devices_unprobed(&self) -> Vec<DeviceInfo> {
+    self.devices(true, false)
+  }
+
+  fn open_capture(
+    &self,
+    format: DeviceFormat,
+    buffers: BufferConfig,
+    device_index: u32,
+    notifier: Notifier,
+  ) -> Result<PortAudioCapture, DeviceError> {
+    Ok(PortAudioCapture {
+      stream: None,
+      pa: None,
+      backend: self.clone(),
+      format,
+      buffers,
+      device_index,
+      // room for the ring twice, the input thread may be late to a notification
+      captured: Arc::new(SampleRing::new(buffers.ring_samples(&format) * 2)),
+      overrun: Arc::new(AtomicU64::new(0)),
+      // two more than the pipeline holds: one being filled, one being played
+      pool: BufferPool::new(PIPELINE_DEPTH + 2, buffers.ring_samples(&format)),
+      notifier,
+    })
+  }
+}
+
+impl PlaybackBackend for PortAudioBackend {
+  type Playback = PortAudioPlayback;
+
+  fn output_devices(&self) -> Vec<DeviceInfo> {
+    self.devices(false, true)
+  }
+
+  fn output_devices_unprobed(&self) -> Vec<DeviceInfo> {
+    self.devices(false, false)
+  }
+
+  fn open_playback(&self, format: DeviceFormat, buffers: BufferConfig, device_index: u32) -> Result<PortAudioPlayback, DeviceError> {
+    Ok(PortAudioPlayback {
+      stream: None,
+      pa: None,
+      backend: self.clone(),
+      format,
+      buffers,
+      device_index,
+      // writes wait while the configured ring is queued, so latency stays what was asked for
+      queued: Arc::new(SampleRing::new(buffers.ring_samples(&format))),
+      space: Arc::new((Mutex::new(()), Condvar::new())),
+    })
+  }
+}
+
+fn device_error(function: &'static str, err: pa::Error, format: &DeviceFormat) -> DeviceError {
+  match err {
+    pa::Error::DeviceUnavailable => DeviceError::Removed,
+    pa::Error::InvalidDevice => DeviceError::OpenFailed {
+      reason: format!("PortAudio {}: {}", function, err),
+    },
+    pa::Error::InvalidChannelCount | pa::Error::InvalidSampleRate | pa::Error::SampleFormatNotSupported => {
+      DeviceError::BadFormat { format: *format }
+    }
+    _ => DeviceError::Backend {
+      backend: "PortAudio",
+      code: err as i32,
+      description: format!("{}: {}", function, err),
+    },
+  }
+}
+
+fn stream_parameters(
+  pa: &pa::PortAudio,
+  format: &DeviceFormat,
+  buffers: &BufferConfig,
+  device_index: u32,
+  input: bool,
+) -> Result<pa::StreamParameters<f32>, DeviceError> {
+  let device_index = pa::DeviceIndex(device_index);
+  let info = pa
+    .device_info(device_index)
+    .map_err(|err| device_error("device_info", err, format))?;
+  let device_latency = if input {
+    info.default_low_input_latency
+  } else {
+    info.default_low_output_latency
+  };
+  // PortAudio keeps its own ring, whole configured ring is the latency we ask for
+  let latency = (buffers.period * buffers.count).as_secs_f64().max(device_latency);
+  Ok(pa::StreamParameters::<f32>::new(
+    device_index,
+    format.channels as i32,
+    true,
+    latency,
+  ))
+}
+
+pub struct PortAudioCapture {
+  // stream goes first so it is dropped before PortAudio is terminated
+  stream: Option<pa::Stream<pa::NonBlocking, pa::Input<f32>>>,
+  pa: Option<Arc<pa::PortAudio>>,
+  backend: PortAudioBackend,
+  format: DeviceFormat,
+  buffers: BufferConfig,
+  device_index: u32,
+  captured: Arc<SampleRing>,
+  // samples the callback had no room for, reported by the next read
+  overrun: Arc<AtomicU64>,
+  // read buffers come from here and return once played
+  pool: BufferPool<f32>,
+  notifier: Notifier,
+}
+
+impl CaptureStream for PortAudioCapture {
+  fn start(&mut self) -> Result<(), DeviceError> {
+    let pa = self.backend.portaudio(false).map_err(|err| DeviceError::OpenFailed {
+      reason: format!("PortAudio: {}", err),
+    })?;
+    let params = stream_parameters(&pa, &self.format, &self.buffers, self.device_index, true)?;
+    let settings = pa::InputStreamSettings::new(params, self.format.frequency as f64, self.buffers.frames(&self.format));
+    let (captured, overrun) = (self.captured.clone(), self.overrun.clone());
+    let notifier = self.notifier.clone();
+    let callback = move |pa::InputStreamCallbackArgs { buffer, .. }| {
+      let pushed = captured.push(buffer);
+      if pushed < buffer.len() {
+        overrun.fetch_add((buffer.len() - pushed) as u64, Ordering::Relaxed);
+      }
+      notifier.notify();
+      pa::Continue
+    };
+    let mut stream = pa
+      .open_non_blocking_stream(settings, callback)
+      .map_err(|err| device_error("open_non_blocking_stream", err, &self.format))?;
+    stream.start().map_err(|err| device_error("start", err, &self.format))?;
+    self.stream = Some(stream);
+    self.pa = Some(pa);
+    Ok(())
+  }
+
+  fn read(&mut self) -> Result<Option<AudioBuffer<f32>>, DeviceError> {
+    let overrun = self.overrun.swap(0, Ordering::Relaxed);
+    if overrun > 0 {
+      println!(
+        "PortAudioCapture: {} frames lost, the input thread did not keep up",
+        overrun / self.format.channels as u64
+      );
+    }
+    let length = self.captured.len().min(self.buffers.ring_samples(&self.format));
+    if length == 0 {
+      return Ok(None);
+    }
+    let mut samples = self.pool.take();
+    samples.resize(length, 0.0);
+    self.captured.pop(&mut samples);
+    // more than the ring piled up, let the input thread come back for the rest
+    if self.captured.len() > 0 {
+      self.notifier.notify();
+    }
+    Ok(Some(AudioBuffer::from_pool(self.format, samples)))
+  }
+
+  fn stop(&mut self) -> Result<(), DeviceError> {
+    // PortAudio is let go even if the stream refuses to stop
+    let result = match self.stream.take() {
+      Some(mut stream) => stream
+        .stop()
+        .and_then(|_| stream.close())
+        .map_err(|err| device_error("stop", err, &self.format)),
+      None => Ok(()),
+    };
+    self.pa = None;
+    result
+  }
+}
+
+pub struct PortAudioPlayback {
+  // stream goes first so it is dropped before PortAudio is terminated
+  stream: Option<pa::Stream<pa::NonBlocking, pa::Output<f32>>>,
+  pa: Option<Arc<pa::PortAudio>>,
+  backend: PortAudioBackend,
+  format: DeviceFormat,
+  buffers: BufferConfig,
+  device_index: u32,
+  queued: Arc<SampleRing>,
+  // signalled by the callback whenever it took samples, writes wait on it while the ring is full
+  space: Arc<(Mutex<()>, Condvar)>,
+}
+
+impl PlaybackStream for PortAudioPlayback {
+  fn start(&mut self) -> Result<(), DeviceError> {
+    let pa = self.backend.portaudio(false).map_err(|err| DeviceError::OpenFailed {
+      reason: format!("PortAudio: {}", err),
+    })?;
+    let params = stream_parameters(&pa, &self.format, &self.buffers, self.device_index, false)?;
+    let settings = pa::OutputStreamSettings::new(params, self.format.frequency as f64, self.buffers.frames(&self.format));
+    let (queued, space) = (self.queued.clone(), self.space.clone());
+    let callback = move |pa::OutputStreamCallbackArgs { buffer, .. }| {
+      let played = queued.pop(buffer);
+      // underrun is played as silence
+      buffer[played..].iter_mut().for_each(|sample| *sample = 0.0);
+      // waking does not take the lock, a wakeup missed meanwhile is caught by the timed wait
+      space.1.notify_one();
+      pa::Continue
+    };
+    let mut stream = pa
+      .open_non_blocking_stream(settings, callback)
+      .map_err(|err| device_error("open_non_blocking_stream", err, &self.format))?;
+    stream.start().map_err(|err| device_error("start", err, &self.format))?;
+    self.stream = Some(stream);
+    self.pa = Some(pa);
+    Ok(())
+  }
+
+  // waits until the whole buffer is queued
+  fn write(&mut self, buffer: &AudioBuffer<f32>) -> Result<(), DeviceError> {
+    let mut samples = buffer.samples();
+    loop {
+      // host api stops the stream by itself when its device disappears
+      if let Some(stream) = &self.stream {
+        if let Ok(false) = stream.is_active() {
+          return Err(DeviceError::Removed);
+        }
+      }
+      samples = &samples[self.queued.push(samples)..];
+      if samples.is_empty() {
+        return Ok(());
+      }
+      let (lock, space) = &*self.space;
+      let _ = space.wait_timeout(lock.lock().unwrap(), self.buffers.period.max(Duration::from_millis(1)));
+    }
+  }
+
+  fn stop(&mut self) -> Result<(), DeviceError> {
+    // PortAudio is let go even if the stream refuses to stop
+    let result = match self.stream.take() {
+      Some(mut stream) => stream
+        .stop()
+        .and_then(|_| stream.close())
+        .map_err(|err| device_error("stop", err, &self.format)),
+      None => Ok(()),
+    };
+    self.pa = None;
+    result
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, std::thread};
+
+  #[test]
+  fn ring_keeps_order_and_refuses_what_does_not_fit() {
+    let ring = SampleRing::new(4);
+    assert_eq!(ring.push(&[1.0, 2.0, 3.0]), 3);
+    assert_eq!(ring.push(&[4.0, 5.0]), 1);
+    let mut out = [0.0; 3];
+    assert_eq!(ring.pop(&mut out), 3);
+    assert_eq!(out, [1.0, 2.0, 3.0]);
+
+    // callback and stream thread on both ends, positions wrap many times
+    let ring = Arc::new(SampleRing::new(6));
+    let producer = {
+      let ring = ring.clone();
+      thread::spawn(move || {
+        let samples: Vec<f32> = (0..3000).map(|sample| sample as f32).collect();
+        let mut samples = &samples[..];
+        while !samples.is_empty() {
+          samples = &samples[ring.push(&samples[..samples.len().min(4)])..];
+          thread::yield_now();
+        }
+      })
+    };
+    let mut popped = Vec::new();
+    let mut out = [0.0; 5];
+    while popped.len() < 3000 {
+      let count = ring.pop(&mut out);
+      popped.extend_from_slice(&out[..count]);
+      if count == 0 {
+        thread::yield_now();
+      }
+    }
+    producer.join().unwrap();
+    assert!(popped.iter().enumerate().all(|(at, &sample)| sample == at as f32));
+  }
+}
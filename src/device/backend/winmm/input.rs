@@ -0,0 +1,197 @@
+use {
+  crate::device::{
+    backend::{winmm::*, CaptureStream, Notifier},
+    common::*,
+    error::{spawn_error, DeviceError},
+    info::*,
+    pool::BufferPool,
+  },
+  std::{
+    mem::{size_of, zeroed},
+    ptr::read_volatile,
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Arc,
+    },
+    thread,
+  },
+  winapi::{
+    shared::{basetsd::DWORD_PTR, mmreg::WAVEFORMATEXTENSIBLE},
+    um::{mmeapi::*, mmsystem::*},
+  },
+};
+
+pub struct InputProcessor {
+  desired_format: DeviceFormat,
+  format: WAVEFORMATEXTENSIBLE,
+  device_index: u32,
+  // memory the device records into while a header is queued, never reallocated
+  buffers: Vec<Vec<u8>>,
+  // captured samples are decoded into these, they return once played
+  pool: BufferPool<f32>,
+  // device keeps pointers to headers, vector is never resized after init
+  headers: Vec<WAVEHDR>,
+  // header the device fills first, headers complete in the order they were added
+  next: usize,
+  handle: HWAVEIN,
+  // handle has to be closed, also when start fails half way through
+  opened: bool,
+  // set by the driver each time a header is filled
+  done: Arc<Event>,
+  notifier: Notifier,
+  // forwards events to the notifier, driver callbacks are not allowed to do much more than SetEvent
+  waiter: Option<(Arc<AtomicBool>, thread::JoinHandle<()>)>,
+}
+
+impl InputProcessor {
+  pub unsafe fn new(
+    desired_format: DeviceFormat,
+    buffers: BufferConfig,
+    device_index: u32,
+    notifier: Notifier,
+  ) -> Result<InputProcessor, DeviceError> {
+    let format = wave_format(&desired_format);
+    let buffer_length = buffers.buffer_length(&desired_format);
+    let handle = zeroed::<HWAVEIN>();
+    Ok(InputProcessor {
+      desired_format,
+      format,
+      handle,
+      opened: false,
+      headers: (0..buffers.count).map(|_| zeroed::<WAVEHDR>()).collect(),
+      buffers: (0..buffers.count).map(|_| vec![0u8; buffer_length]).collect(),
+      // two more than the pipeline holds: one being filled, one being played
+      pool: BufferPool::new(PIPELINE_DEPTH + 2, buffers.ring_samples(&desired_format)),
+      next: 0,
+      device_index,
+      done: Arc::new(Event::new()?),
+      notifier,
+      waiter: None,
+    })
+  }
+
+  fn check(&self, function: &'static str, mmresult: MMRESULT) -> Result<(), DeviceError> {
+    mm_result(function, mmresult, &self.desired_format)
+  }
+
+  unsafe fn init(&mut self) -> Result<(), DeviceError> {
+    // WAVE_FORMAT_DIRECT??? does not perform conversions on the audio data
+    let mmresult = waveInOpen(
+      &mut self.handle,
+      self.device_index,
+      wave_format_ptr(&self.format),
+      self.done.handle() as DWORD_PTR,
+      0 as DWORD_PTR,
+      CALLBACK_EVENT,
+    );
+    self.check("waveInOpen", mmresult)?;
+    self.opened = true;
+    for index in 0..self.headers.len() {
+      let header = &mut self.headers[index];
+      header.lpData = self.buffers[index].as_mut_ptr() as *mut _;
+      header.dwBufferLength = self.buffers[index].len() as u32;
+      let mmresult = waveInPrepareHeader(self.handle, header, size_of::<WAVEHDR>() as u32);
+      self.check("waveInPrepareHeader", mmresult)?;
+      let mmresult = waveInAddBuffer(self.handle, &mut self.headers[index], size_of::<WAVEHDR>() as u32);
+      self.check("waveInAddBuffer", mmresult)?;
+    }
+    let running = Arc::new(AtomicBool::new(true));
+    let waiting = running.clone();
+    let done = self.done.clone();
+    let notifier = self.notifier.clone();
+    let waiter = thread::Builder::new()
+      .name("waveIn events".into())
+      .spawn(move || loop {
+        done.wait();
+        if !waiting.load(Ordering::Acquire) {
+          break;
+        }
+        notifier.notify();
+      })
+      .map_err(spawn_error)?;
+    self.waiter = Some((running, waiter));
+    let mmresult = waveInStart(self.handle);
+    self.check("waveInStart", mmresult)?;
+    Ok(())
+  }
+
+  // collects every filled buffer and hands it back to the device
+  unsafe fn new_data(&mut self) -> Result<Option<AudioBuffer<f32>>, DeviceError> {
+    let mut samples = self.pool.take();
+    for _ in 0..self.headers.len() {
+      let header = &mut self.headers[self.next];
+      // flags are written by the driver behind our back
+      let flags = read_volatile(&header.dwFlags);
+      if flags & WHDR_INQUEUE != 0 {
+        break;
+      }
+      if flags & WHDR_DONE == 0 {
+        println!("WARN: input header.dwFlags = {} not handled!", whdr_to_str(flags));
+        break;
+      }
+      decode(
+        &mut samples,
+        &self.buffers[self.next][..header.dwBytesRecorded as usize],
+        &self.desired_format,
+      );
+      let mmresult = waveInAddBuffer(self.handle, header, size_of::<WAVEHDR>() as u32);
+      self.check("waveInAddBuffer", mmresult)?;
+      self.next = (self.next + 1) % self.headers.len();
+    }
+    if samples.is_empty() {
+      return Ok(None);
+    }
+    Ok(Some(AudioBuffer::from_pool(self.desired_format, samples)))
+  }
+
+  // tears down as much as possible, first error is returned. Whatever is already torn down is skipped,
+  // so it is safe to call again
+  unsafe fn stop(&mut self) -> Result<(), DeviceError> {
+    let mut result = Ok(());
+    if self.opened {
+      result = self.check("waveInStop", waveInStop(self.handle));
+      result = result.and(self.check("waveInReset", waveInReset(self.handle)));
+      for index in 0..self.headers.len() {
+        if self.headers[index].dwFlags & WHDR_PREPARED == 0 {
+          continue;
+        }
+        let mmresult = waveInUnprepareHeader(self.handle, &mut self.headers[index], size_of::<WAVEHDR>() as u32);
+        result = result.and(self.check("waveInUnprepareHeader", mmresult));
+      }
+      result = result.and(self.check("waveInClose", waveInClose(self.handle)));
+      self.opened = false;
+    }
+    // the event thread waits without a timeout, it has to be woken to see it should leave
+    if let Some((running, waiter)) = self.waiter.take() {
+      running.store(false, Ordering::Release);
+      self.done.set();
+      if waiter.join().is_err() {
+        println!("InputProcessor: event thread panicked");
+      }
+    }
+    result
+  }
+}
+
+// a stream which failed to start, or was never stopped, still releases the device and its thread
+impl Drop for InputProcessor {
+  fn drop(&mut self) {
+    if let Err(err) = unsafe { InputProcessor::stop(self) } {
+      println!("InputProcessor: {}", err);
+    }
+  }
+}
+
+impl CaptureStream for InputProcessor {
+  fn start(&mut self) -> Result<(), DeviceError> {
+    unsafe { self.init() }
+  }
+
+  fn read(&mut self) -> Result<Option<AudioBuffer<f32>>, DeviceError> {
+    unsafe { self.new_data() }
+  }
+
+  fn stop(&mut self) -> Result<(), DeviceError> {
+    unsafe { InputProcessor::stop(self) }
+  }
+}
@@ -0,0 +1,351 @@
+mod input;
+mod output;
+
+pub use self::{input::InputProcessor, output::OutputProcessor};
+
+use {
+  crate::device::{backend::*, error::DeviceError, info::*},
+  std::{
+    mem::{size_of, zeroed},
+    ptr::{null, null_mut},
+    string::FromUtf16Error,
+    time::Duration,
+  },
+  winapi::{
+    shared::{
+      basetsd::UINT_PTR,
+      ksmedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, KSDATAFORMAT_SUBTYPE_PCM},
+      minwindef::{DWORD, FALSE},
+      mmreg::{WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_EXTENSIBLE, WAVE_FORMAT_PCM},
+    },
+    um::{
+      handleapi::CloseHandle,
+      mmeapi::*,
+      mmsystem::*,
+      synchapi::{CreateEventW, SetEvent, WaitForSingleObject},
+      winbase::{INFINITE, WAIT_OBJECT_0},
+      winnt::HANDLE,
+    },
+  },
+};
+
+#[derive(Clone, Copy, Default)]
+pub struct WinmmBackend;
+
+impl CaptureBackend for WinmmBackend {
+  type Capture = InputProcessor;
+
+  fn input_devices(&self) -> Vec<DeviceInfo> {
+    input_devices(true)
+  }
+
+  fn input_devices_unprobed(&self) -> Vec<DeviceInfo> {
+    input_devices(false)
+  }
+
+  fn open_capture(
+    &self,
+    format: DeviceFormat,
+    buffers: BufferConfig,
+    device_index: u32,
+    notifier: Notifier,
+  ) -> Result<InputProcessor, DeviceError> {
+    unsafe { InputProcessor::new(format, buffers, device_index, notifier) }
+  }
+}
+
+impl PlaybackBackend for WinmmBackend {
+  type Playback = OutputProcessor;
+
+  fn output_devices(&self) -> Vec<DeviceInfo> {
+    output_devices(true)
+  }
+
+  fn output_devices_unprobed(&self) -> Vec<DeviceInfo> {
+    output_devices(false)
+  }
+
+  fn open_playback(&self, format: DeviceFormat, buffers: BufferConfig, device_index: u32) -> Result<OutputProcessor, DeviceError> {
+    unsafe { OutputProcessor::new(format, buffers, device_index) }
+  }
+}
+
+// Every format query is a round trip to the driver, up to 165 per device, so the reopen poll lists
+// names only (`probe` off) and opening the matched device checks the one format in use
+fn input_devices(probe: bool) -> Vec<DeviceInfo> {
+  let mut available_devices: Vec<DeviceInfo> = Vec::new();
+  unsafe {
+    let device_count = waveInGetNumDevs();
+    for device_index in 0..device_count {
+      let size = size_of::<WAVEINCAPSW>() as u32;
+      let mut device_capabilities = zeroed::<WAVEINCAPSW>();
+      let mmresult = waveInGetDevCapsW(device_index as UINT_PTR, &mut device_capabilities, size);
+      if mmresult != MMSYSERR_NOERROR {
+        println!("waveInGetDevCapsW: {}", mm_error_to_string(mmresult));
+        continue;
+      }
+      let name = match device_name(&device_capabilities.szPname) {
+        Ok(res) => res,
+        _ => continue,
+      };
+      if !probe {
+        available_devices.push(DeviceInfo::new(device_index, name, Vec::new()));
+        continue;
+      }
+      let channel_counts = DeviceFormat::channel_counts(device_capabilities.wChannels);
+      let mut formats = DeviceFormat::probe(&channel_counts, |format| input_supports(device_index, format));
+      if formats.is_empty() {
+        formats = unpack_formats(device_capabilities.dwFormats);
+      }
+      if formats.is_empty() {
+        continue;
+      }
+      available_devices.push(DeviceInfo::new(device_index, name, formats));
+    }
+  }
+  DeviceInfo::assign_ordinals(&mut available_devices);
+  available_devices
+}
+
+fn output_devices(probe: bool) -> Vec<DeviceInfo> {
+  let mut available_devices: Vec<DeviceInfo> = Vec::new();
+  unsafe {
+    let device_count = waveOutGetNumDevs();
+    for device_index in 0..device_count {
+      let size = size_of::<WAVEOUTCAPSW>() as u32;
+      let mut device_capabilities = zeroed::<WAVEOUTCAPSW>();
+      let mmresult = waveOutGetDevCapsW(device_index as UINT_PTR, &mut device_capabilities, size);
+      if mmresult != MMSYSERR_NOERROR {
+        println!("waveOutGetDevCapsW: {}", mm_error_to_string(mmresult));
+        continue;
+      }
+      let name = match device_name(&device_capabilities.szPname) {
+        Ok(res) => res,
+        _ => continue,
+      };
+      if !probe {
+        available_devices.push(DeviceInfo::new(device_index, name, Vec::new()));
+        continue;
+      }
+      let channel_counts = DeviceFormat::channel_counts(device_capabilities.wChannels);
+      let mut formats = DeviceFormat::probe(&channel_counts, |format| output_supports(device_index, format));
+      if formats.is_empty() {
+        formats = unpack_formats(device_capabilities.dwFormats);
+      }
+      if formats.is_empty() {
+        continue;
+      }
+      available_devices.push(DeviceInfo::new(device_index, name, formats));
+    }
+  }
+  DeviceInfo::assign_ordinals(&mut available_devices);
+  available_devices
+}
+
+// Auto-reset event passed to waveInOpen/waveOutOpen with CALLBACK_EVENT, the driver sets it
+// every time a header is done (and on open/close, so waiters have to recheck header flags)
+pub struct Event(HANDLE);
+
+// kernel handles can be used from any thread
+unsafe impl Send for Event {}
+unsafe impl Sync for Event {}
+
+impl Event {
+  pub unsafe fn new() -> Result<Event, DeviceError> {
+    let handle = CreateEventW(null_mut(), FALSE, FALSE, null());
+    if handle.is_null() {
+      return Err(DeviceError::OpenFailed {
+        reason: "CreateEventW failed".to_string(),
+      });
+    }
+    Ok(Event(handle))
+  }
+
+  pub fn handle(&self) -> HANDLE {
+    self.0
+  }
+
+  pub fn set(&self) {
+    unsafe { SetEvent(self.0) };
+  }
+
+  pub fn wait(&self) {
+    unsafe { WaitForSingleObject(self.0, INFINITE) };
+  }
+
+  // false if `timeout` passed without the event being set
+  pub fn wait_timeout(&self, timeout: Duration) -> bool {
+    let milliseconds = timeout.as_millis().min(INFINITE as u128 - 1) as DWORD;
+    unsafe { WaitForSingleObject(self.0, milliseconds) == WAIT_OBJECT_0 }
+  }
+}
+
+impl Drop for Event {
+  fn drop(&mut self) {
+    unsafe { CloseHandle(self.0) };
+  }
+}
+
+// szPname is a fixed size buffer padded with zeros
+fn device_name(name: &[u16]) -> Result<String, FromUtf16Error> {
+  let length = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+  String::from_utf16(&name[..length])
+}
+
+// Plain WAVEFORMATEX describes only mono/stereo PCM up to 16 bit, anything else needs the extensible
+// variant with explicit speaker layout. waveInOpen/waveOutOpen take it through wave_format_ptr
+pub unsafe fn wave_format(format: &DeviceFormat) -> WAVEFORMATEXTENSIBLE {
+  let mut wave_format = zeroed::<WAVEFORMATEXTENSIBLE>();
+  let extensible = format.channels > 2 || format.bits > 16 || format.encoding == Encoding::Float;
+  // struct is packed, fields are assigned in place rather than through references
+  wave_format.Format.wFormatTag = if extensible { WAVE_FORMAT_EXTENSIBLE } else { WAVE_FORMAT_PCM };
+  wave_format.Format.nChannels = format.channels;
+  wave_format.Format.nSamplesPerSec = format.frequency;
+  wave_format.Format.wBitsPerSample = format.bits;
+  wave_format.Format.nBlockAlign = format.block_align();
+  wave_format.Format.nAvgBytesPerSec = format.frequency * format.block_align() as u32;
+  if extensible {
+    wave_format.Format.cbSize = (size_of::<WAVEFORMATEXTENSIBLE>() - size_of::<WAVEFORMATEX>()) as u16;
+    wave_format.Samples = format.bits;
+    wave_format.dwChannelMask = format.channel_mask();
+    wave_format.SubFormat = match format.encoding {
+      Encoding::Pcm => KSDATAFORMAT_SUBTYPE_PCM,
+      Encoding::Float => KSDATAFORMAT_SUBTYPE_IEEE_FLOAT,
+    };
+  }
+  wave_format
+}
+
+pub fn wave_format_ptr(wave_format: &WAVEFORMATEXTENSIBLE) -> *const WAVEFORMATEX {
+  wave_format as *const WAVEFORMATEXTENSIBLE as *const WAVEFORMATEX
+}
+
+// Asks the driver whether it would open `format`, nothing is opened
+unsafe fn input_supports(device_index: u32, format: &DeviceFormat) -> bool {
+  let wave_format = wave_format(format);
+  let mmresult = waveInOpen(null_mut(), device_index, wave_format_ptr(&wave_format), 0, 0, WAVE_FORMAT_QUERY);
+  mmresult == MMSYSERR_NOERROR
+}
+
+unsafe fn output_supports(device_index: u32, format: &DeviceFormat) -> bool {
+  let wave_format = wave_format(format);
+  let mmresult = waveOutOpen(null_mut(), device_index, wave_format_ptr(&wave_format), 0, 0, WAVE_FORMAT_QUERY);
+  mmresult == MMSYSERR_NOERROR
+}
+
+// legacy capability bitmask, only for drivers which refuse format queries
+pub fn unpack_formats(packed_format: DWORD) -> Vec<DeviceFormat> {
+  // on_device_format: ($const_dword: expr, $frequency: expr, $channels: expr, $bits: expr)
+  macro_rules! enumerate_device_formats {
+    ($on_device_format: ident) => {
+      $on_device_format!(WAVE_FORMAT_1M08, 11025u32, 1u16, 8u16);
+      $on_device_format!(WAVE_FORMAT_1M16, 11025u32, 1u16, 16u16);
+      $on_device_format!(WAVE_FORMAT_1S08, 11025u32, 2u16, 8u16);
+      $on_device_format!(WAVE_FORMAT_1S16, 11025u32, 2u16, 16u16);
+      $on_device_format!(WAVE_FORMAT_2M08, 22050u32, 1u16, 8u16);
+      $on_device_format!(WAVE_FORMAT_2M16, 22050u32, 1u16, 16u16);
+      $on_device_format!(WAVE_FORMAT_2S08, 22050u32, 2u16, 8u16);
+      $on_device_format!(WAVE_FORMAT_2S16, 22050u32, 2u16, 16u16);
+      $on_device_format!(WAVE_FORMAT_4M08, 44100u32, 1u16, 8u16);
+      $on_device_format!(WAVE_FORMAT_4M16, 44100u32, 1u16, 16u16);
+      $on_device_format!(WAVE_FORMAT_4S08, 44100u32, 2u16, 8u16);
+      $on_device_format!(WAVE_FORMAT_4S16, 44100u32, 2u16, 16u16);
+      $on_device_format!(WAVE_FORMAT_96M08, 96000u32, 1u16, 8u16);
+      $on_device_format!(WAVE_FORMAT_96M16, 96000u32, 1u16, 16u16);
+      $on_device_format!(WAVE_FORMAT_96S08, 96000u32, 2u16, 8u16);
+      $on_device_format!(WAVE_FORMAT_96S16, 96000u32, 2u16, 16u16);
+    };
+  }
+  let mut result: Vec<DeviceFormat> = Vec::new();
+  macro_rules! expand_device_format_enum {
+    ($const_dword: expr, $frequency: expr, $channels: expr, $bits: expr) => {
+      if packed_format & $const_dword != 0 {
+        result.push(DeviceFormat {
+          frequency: $frequency,
+          channels: $channels,
+          bits: $bits,
+          encoding: Encoding::Pcm,
+        })
+      }
+    };
+  }
+  enumerate_device_formats!(expand_device_format_enum);
+  result
+}
+
+// waveIn*/waveOut* result as a device error, `format` is what the device was opened with
+pub fn mm_result(function: &'static str, mmresult: MMRESULT, format: &DeviceFormat) -> Result<(), DeviceError> {
+  match mmresult {
+    MMSYSERR_NOERROR => Ok(()),
+    MMSYSERR_ALLOCATED => Err(DeviceError::Busy),
+    // device ids are not stable, unplugged device either lost its driver or its id
+    MMSYSERR_NODRIVER | MMSYSERR_BADDEVICEID => Err(DeviceError::Removed),
+    WAVERR_BADFORMAT => Err(DeviceError::BadFormat { format: *format }),
+    _ => Err(DeviceError::Backend {
+      backend: "winmm",
+      code: mmresult as i32,
+      description: format!("{}: {}", function, mm_error_to_string(mmresult)),
+    }),
+  }
+}
+
+pub fn mm_error_to_string(r: MMRESULT) -> &'static str {
+  match r {
+    MMSYSERR_NOERROR => "NOERROR",
+    MMSYSERR_ERROR => "ERROR",
+    MMSYSERR_BADDEVICEID => "BADDEVICEID",
+    MMSYSERR_NOTENABLED => "NOTENABLED",
+    MMSYSERR_ALLOCATED => "ALLOCATED",
+    MMSYSERR_INVALHANDLE => "INVALHANDLE",
+    MMSYSERR_NODRIVER => "NODRIVER",
+    MMSYSERR_NOMEM => "NOMEM",
+    MMSYSERR_NOTSUPPORTED => "NOTSUPPORTED",
+    MMSYSERR_BADERRNUM => "BADERRNUM",
+    MMSYSERR_INVALFLAG => "INVALFLAG",
+    MMSYSERR_INVALPARAM => "INVALPARAM",
+    MMSYSERR_HANDLEBUSY => "HANDLEBUSY",
+    MMSYSERR_INVALIDALIAS => "INVALIDALIAS",
+    MMSYSERR_BADDB => "BADDB",
+    MMSYSERR_KEYNOTFOUND => "KEYNOTFOUND",
+    MMSYSERR_READERROR => "READERROR",
+    MMSYSERR_WRITEERROR => "WRITEERROR",
+    MMSYSERR_DELETEERROR => "DELETEERROR",
+    MMSYSERR_VALNOTFOUND => "VALNOTFOUND",
+    MMSYSERR_NODRIVERCB => "NODRIVERCB",
+    MMSYSERR_MOREDATA => "MOREDATA",
+    WAVERR_BADFORMAT => "BADFORMAT",
+    WAVERR_STILLPLAYING => "STILLPLAYING",
+    WAVERR_UNPREPARED => "UNPREPARED",
+    WAVERR_SYNC => "SYNC",
+    _ => "[unknown (not MM-system error)]",
+  }
+}
+
+pub const WHDR_DONE: DWORD = 0x00000001; // done bit
+pub const WHDR_PREPARED: DWORD = 0x00000002; // set if this header has been prepared
+pub const WHDR_BEGINLOOP: DWORD = 0x00000004; // loop start block
+pub const WHDR_ENDLOOP: DWORD = 0x00000008; // loop end block
+pub const WHDR_INQUEUE: DWORD = 0x00000010; // reserved for driver
+
+pub fn whdr_to_str(whdr: DWORD) -> String {
+  let mut res = vec![];
+  if whdr & WHDR_DONE != 0 {
+    res.push("WHDR_DONE".into())
+  };
+  if whdr & WHDR_PREPARED != 0 {
+    res.push("WHDR_PREPARED".into())
+  };
+  if whdr & WHDR_BEGINLOOP != 0 {
+    res.push("WHDR_BEGINLOOP".into())
+  };
+  if whdr & WHDR_ENDLOOP != 0 {
+    res.push("WHDR_ENDLOOP".into())
+  };
+  if whdr & WHDR_INQUEUE != 0 {
+    res.push("WHDR_INQUEUE".into())
+  };
+  if res.len() == 0 {
+    res.push(format!("UNKNOWN {}", whdr))
+  }
+  res.join(",")
+}
@@ -0,0 +1,153 @@
+use {
+  crate::device::{
+    backend::{winmm::*, PlaybackStream},
+    common::*,
+    error::DeviceError,
+    info::*,
+  },
+  std::{
+    mem::{size_of, zeroed},
+    ptr::read_volatile,
+    time::{Duration, Instant},
+  },
+  winapi::{
+    shared::{basetsd::DWORD_PTR, mmreg::WAVEFORMATEXTENSIBLE},
+    um::{mmeapi::*, mmsystem::*},
+  },
+};
+
+pub struct OutputProcessor {
+  // memory the device plays from while a header is queued, never reallocated
+  buffers: Vec<Vec<u8>>,
+  // frames fitting into one of `buffers`
+  buffer_frames: usize,
+  // device keeps pointers to headers, vector is never resized
+  headers: Vec<WAVEHDR>,
+  // header which is written next, headers are played in the order they were written
+  next: usize,
+  desired_format: DeviceFormat,
+  format: WAVEFORMATEXTENSIBLE,
+  device_index: u32,
+  handle: HWAVEOUT,
+  // handle has to be closed
+  opened: bool,
+  // set by the driver each time a header is played
+  done: Event,
+  // how long a queued header may stay unplayed. Headers of an unplugged device are never done, and
+  // the driver does not always say it is gone
+  stall_timeout: Duration,
+}
+
+impl OutputProcessor {
+  pub unsafe fn new(desired_format: DeviceFormat, buffers: BufferConfig, device_index: u32) -> Result<OutputProcessor, DeviceError> {
+    let format = wave_format(&desired_format);
+    let handle = zeroed::<HWAVEOUT>();
+    let buffer_length = buffers.buffer_length(&desired_format);
+    Ok(OutputProcessor {
+      buffers: (0..buffers.count).map(|_| vec![0u8; buffer_length]).collect(),
+      buffer_frames: buffers.frames(&desired_format) as usize,
+      headers: (0..buffers.count).map(|_| zeroed::<WAVEHDR>()).collect(),
+      next: 0,
+      desired_format,
+      format,
+      handle,
+      opened: false,
+      device_index,
+      done: Event::new()?,
+      // the whole ring played several times over
+      stall_timeout: (buffers.period * buffers.count * 4).max(Duration::from_secs(1)),
+    })
+  }
+
+  fn check(&self, function: &'static str, mmresult: MMRESULT) -> Result<(), DeviceError> {
+    mm_result(function, mmresult, &self.desired_format)
+  }
+
+  unsafe fn init(&mut self) -> Result<(), DeviceError> {
+    // WAVE_FORMAT_DIRECT??? does not perform conversions on the audio data
+    let mmresult = waveOutOpen(
+      &mut self.handle,
+      self.device_index,
+      wave_format_ptr(&self.format),
+      self.done.handle() as DWORD_PTR,
+      0 as DWORD_PTR,
+      CALLBACK_EVENT,
+    );
+    self.check("waveOutOpen", mmresult)?;
+    self.opened = true;
+    Ok(())
+  }
+
+  // incoming buffer is spread over as many ring buffers as it needs
+  unsafe fn new_data(&mut self, buffer: &AudioBuffer<f32>) -> Result<(), DeviceError> {
+    for chunk in buffer.chunks(self.buffer_frames) {
+      let index = self.next;
+      // whole ring is queued, sleep until the device is done with the oldest header
+      let deadline = Instant::now() + self.stall_timeout;
+      while read_volatile(&self.headers[index].dwFlags) & WHDR_INQUEUE != 0 {
+        let now = Instant::now();
+        if now >= deadline {
+          return Err(DeviceError::Removed);
+        }
+        self.done.wait_timeout(deadline - now);
+      }
+      if self.headers[index].dwFlags & WHDR_PREPARED != 0 {
+        let mmresult = waveOutUnprepareHeader(self.handle, &mut self.headers[index], size_of::<WAVEHDR>() as u32);
+        self.check("waveOutUnprepareHeader", mmresult)?;
+      }
+      // encoded in place, a chunk never needs more than the capacity so the memory stays where it is
+      self.buffers[index].clear();
+      chunk.write_bytes(&mut self.buffers[index]);
+      let header = &mut self.headers[index];
+      header.lpData = self.buffers[index].as_mut_ptr() as *mut _;
+      header.dwBufferLength = self.buffers[index].len() as u32;
+      header.dwFlags = 0;
+      let mmresult = waveOutPrepareHeader(self.handle, header, size_of::<WAVEHDR>() as u32);
+      self.check("waveOutPrepareHeader", mmresult)?;
+      let mmresult = waveOutWrite(self.handle, &mut self.headers[index], size_of::<WAVEHDR>() as u32);
+      self.check("waveOutWrite", mmresult)?;
+      self.next = (index + 1) % self.headers.len();
+    }
+    Ok(())
+  }
+
+  // tears down as much as possible, first error is returned. Safe to call again
+  unsafe fn stop(&mut self) -> Result<(), DeviceError> {
+    if !self.opened {
+      return Ok(());
+    }
+    let mut result = self.check("waveOutReset", waveOutReset(self.handle));
+    for index in 0..self.headers.len() {
+      if self.headers[index].dwFlags & WHDR_PREPARED == 0 {
+        continue;
+      }
+      let mmresult = waveOutUnprepareHeader(self.handle, &mut self.headers[index], size_of::<WAVEHDR>() as u32);
+      result = result.and(self.check("waveOutUnprepareHeader", mmresult));
+    }
+    result = result.and(self.check("waveOutClose", waveOutClose(self.handle)));
+    self.opened = false;
+    result
+  }
+}
+
+impl Drop for OutputProcessor {
+  fn drop(&mut self) {
+    if let Err(err) = unsafe { OutputProcessor::stop(self) } {
+      println!("OutputProcessor: {}", err);
+    }
+  }
+}
+
+impl PlaybackStream for OutputProcessor {
+  fn start(&mut self) -> Result<(), DeviceError> {
+    unsafe { self.init() }
+  }
+
+  fn write(&mut self, buffer: &AudioBuffer<f32>) -> Result<(), DeviceError> {
+    unsafe { self.new_data(buffer) }
+  }
+
+  fn stop(&mut self) -> Result<(), DeviceError> {
+    unsafe { OutputProcessor::stop(self) }
+  }
+}
@@ -1,114 +1,227 @@
 use {
-  std::{
-    alloc::{alloc_zeroed, dealloc, Layout},
-    ptr::copy_nonoverlapping,
+  crate::device::{
+    convert::{read_sample, write_sample},
+    info::DeviceFormat,
+    pool::{BufferPool, PooledSamples, SharedSamples},
   },
-  winapi::{shared::minwindef::DWORD, um::mmsystem::*},
+  std::ops::Range,
 };
 
-pub struct WaveBuffer {
-  pub data: *mut i8,
-  layout: Layout,
-  size: usize,
+// Buffers queued between a producer and the device or network thread consuming them. Producers block
+// when it is full, so this also bounds how many pooled buffers are in flight
+pub const PIPELINE_DEPTH: usize = 16;
+
+// In-memory sample type, -1.0..1.0 is full scale whatever the device format is
+pub trait Sample: Copy + Default + Send + Sync + 'static {
+  fn to_f32(self) -> f32;
+  fn from_f32(sample: f32) -> Self;
+}
+
+impl Sample for f32 {
+  fn to_f32(self) -> f32 {
+    self
+  }
+
+  fn from_f32(sample: f32) -> f32 {
+    sample
+  }
+}
+
+impl Sample for i16 {
+  fn to_f32(self) -> f32 {
+    self as f32 / 32768.0
+  }
+
+  fn from_f32(sample: f32) -> i16 {
+    (sample * 32768.0).round() as i16
+  }
+}
+
+impl Sample for i32 {
+  fn to_f32(self) -> f32 {
+    self as f32 / 2147483648.0
+  }
+
+  fn from_f32(sample: f32) -> i32 {
+    (sample as f64 * 2147483648.0).round() as i32
+  }
+}
+
+// unsigned like 8 bit PCM, silence is 128
+impl Sample for u8 {
+  fn to_f32(self) -> f32 {
+    (self as f32 - 128.0) / 128.0
+  }
+
+  fn from_f32(sample: f32) -> u8 {
+    (sample * 128.0 + 128.0).round() as u8
+  }
 }
 
-unsafe impl Send for WaveBuffer {}
+// Interleaved frames of a stream in `format`, `format` also says how they are stored by the device.
+// Samples are shared, slicing and cloning copy nothing. Pooled samples go back to their pool when the
+// last buffer using them is dropped
+pub struct AudioBuffer<T: Sample> {
+  format: DeviceFormat,
+  // position of the first frame in frames since the stream started
+  timestamp: u64,
+  samples: SharedSamples<T>,
+  // frames of `samples` this buffer covers
+  frames: Range<usize>,
+}
 
-impl WaveBuffer {
-  pub fn new(size: usize) -> Self {
-    let layout = match Layout::array::<i8>(size) {
-      Ok(layout) => layout,
-      Err(err) => panic!("cannot determine buffer layout: {}", err),
-    };
-    let data = unsafe { alloc_zeroed(layout) as *mut i8 };
-    Self { data, layout, size }
+impl<T: Sample> AudioBuffer<T> {
+  // incomplete trailing frame is dropped. Streams start at timestamp 0, captured buffers are stamped
+  // by the input device which keeps one timeline across device re-opens
+  pub fn new(format: DeviceFormat, samples: Vec<T>) -> AudioBuffer<T> {
+    AudioBuffer::from_shared(format, SharedSamples::unpooled(samples))
   }
 
-  pub fn length(&self) -> u32 {
-    self.size as u32
+  // samples taken from a BufferPool and filled by the caller
+  pub fn from_pool(format: DeviceFormat, samples: PooledSamples<T>) -> AudioBuffer<T> {
+    AudioBuffer::from_shared(format, samples.into_shared())
   }
 
-  pub fn copy_to(&self, dst: &mut Self) {
-    if self.size > dst.size {
-      panic!("original buffer size is bigger than destination, cannot perform copy_to")
+  fn from_shared(format: DeviceFormat, samples: SharedSamples<T>) -> AudioBuffer<T> {
+    // a format without channels holds no frames
+    let frames = samples.len().checked_div(format.channels as usize).unwrap_or(0);
+    AudioBuffer {
+      format,
+      timestamp: 0,
+      samples,
+      frames: 0..frames,
     }
-    unsafe { copy_nonoverlapping(self.data, dst.data, self.size) };
   }
 
-  pub fn partially_clone(&self, partition_size: u32) -> Self {
-    if partition_size > self.size as u32 {
-      panic!("partition_size could not be bigger than original size")
+  // decodes bytes as the device stores them. Allocates, streams use from_pooled_bytes
+  pub fn from_bytes(format: DeviceFormat, bytes: &[u8]) -> AudioBuffer<T> {
+    let mut samples = Vec::new();
+    decode(&mut samples, bytes, &format);
+    AudioBuffer::new(format, samples)
+  }
+
+  // like from_bytes, into samples taken from `pool`
+  pub fn from_pooled_bytes(format: DeviceFormat, bytes: &[u8], pool: &BufferPool<T>) -> AudioBuffer<T> {
+    let mut samples = pool.take();
+    decode(&mut samples, bytes, &format);
+    AudioBuffer::from_pool(format, samples)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(self.frames() * self.format.block_align() as usize);
+    self.write_bytes(&mut bytes);
+    bytes
+  }
+
+  // appends the samples as the device stores them, allocates only if `bytes` has no room left
+  pub fn write_bytes(&self, bytes: &mut Vec<u8>) {
+    for &sample in self.samples() {
+      write_sample(bytes, sample.to_f32(), &self.format);
+    }
+  }
+
+  pub fn format(&self) -> DeviceFormat {
+    self.format
+  }
+
+  pub fn timestamp(&self) -> u64 {
+    self.timestamp
+  }
+
+  pub fn with_timestamp(mut self, timestamp: u64) -> AudioBuffer<T> {
+    self.timestamp = timestamp;
+    self
+  }
+
+  pub fn frames(&self) -> usize {
+    self.frames.len()
+  }
+
+  pub fn samples(&self) -> &[T] {
+    let channels = self.format.channels as usize;
+    &self.samples[self.frames.start * channels..self.frames.end * channels]
+  }
+
+  // `frames` relative to this buffer, clamped to it
+  pub fn slice(&self, frames: Range<usize>) -> AudioBuffer<T> {
+    let end = (self.frames.start + frames.end).min(self.frames.end);
+    let start = (self.frames.start + frames.start).min(end);
+    AudioBuffer {
+      format: self.format,
+      timestamp: self.timestamp + (start - self.frames.start) as u64,
+      samples: self.samples.clone(),
+      frames: start..end,
     }
-    let buffer = WaveBuffer::new(partition_size as usize);
-    unsafe { copy_nonoverlapping(self.data, buffer.data, partition_size as usize) };
-    buffer
   }
-}
 
-impl Drop for WaveBuffer {
-  fn drop(&mut self) {
-    println!("WAVEBUFFER DROP");
-    unsafe { dealloc(self.data as *mut u8, self.layout) }
+  // pieces of at most `frames` frames each
+  pub fn chunks(&self, frames: usize) -> impl Iterator<Item = AudioBuffer<T>> + '_ {
+    let frames = frames.max(1);
+    (0..self.frames())
+      .step_by(frames)
+      .map(move |start| self.slice(start..start + frames))
   }
 }
 
-pub fn mm_error_to_string(r: MMRESULT) -> &'static str {
-  match r {
-    MMSYSERR_NOERROR => "NOERROR",
-    MMSYSERR_ERROR => "ERROR",
-    MMSYSERR_BADDEVICEID => "BADDEVICEID",
-    MMSYSERR_NOTENABLED => "NOTENABLED",
-    MMSYSERR_ALLOCATED => "ALLOCATED",
-    MMSYSERR_INVALHANDLE => "INVALHANDLE",
-    MMSYSERR_NODRIVER => "NODRIVER",
-    MMSYSERR_NOMEM => "NOMEM",
-    MMSYSERR_NOTSUPPORTED => "NOTSUPPORTED",
-    MMSYSERR_BADERRNUM => "BADERRNUM",
-    MMSYSERR_INVALFLAG => "INVALFLAG",
-    MMSYSERR_INVALPARAM => "INVALPARAM",
-    MMSYSERR_HANDLEBUSY => "HANDLEBUSY",
-    MMSYSERR_INVALIDALIAS => "INVALIDALIAS",
-    MMSYSERR_BADDB => "BADDB",
-    MMSYSERR_KEYNOTFOUND => "KEYNOTFOUND",
-    MMSYSERR_READERROR => "READERROR",
-    MMSYSERR_WRITEERROR => "WRITEERROR",
-    MMSYSERR_DELETEERROR => "DELETEERROR",
-    MMSYSERR_VALNOTFOUND => "VALNOTFOUND",
-    MMSYSERR_NODRIVERCB => "NODRIVERCB",
-    MMSYSERR_MOREDATA => "MOREDATA",
-    WAVERR_BADFORMAT => "BADFORMAT",
-    WAVERR_STILLPLAYING => "STILLPLAYING",
-    WAVERR_UNPREPARED => "UNPREPARED",
-    WAVERR_SYNC => "SYNC",
-    _ => "[unknown (not MM-system error)]",
+impl<T: Sample> Clone for AudioBuffer<T> {
+  fn clone(&self) -> AudioBuffer<T> {
+    self.slice(0..self.frames())
   }
 }
 
-pub const WHDR_DONE: DWORD = 0x00000001; // done bit
-pub const WHDR_PREPARED: DWORD = 0x00000002; // set if this header has been prepared
-pub const WHDR_BEGINLOOP: DWORD = 0x00000004; // loop start block
-pub const WHDR_ENDLOOP: DWORD = 0x00000008; // loop end block
-pub const WHDR_INQUEUE: DWORD = 0x00000010; // reserved for driver
+// appends `bytes` stored in `format` as samples, incomplete trailing frame is dropped
+pub fn decode<T: Sample>(samples: &mut Vec<T>, bytes: &[u8], format: &DeviceFormat) {
+  let sample_length = format.bits as usize / 8;
+  let whole_frames = bytes.len() / format.block_align() as usize * format.block_align() as usize;
+  samples.extend(
+    bytes[..whole_frames]
+      .chunks_exact(sample_length)
+      .map(|sample| T::from_f32(read_sample(sample, format))),
+  );
+}
 
-pub fn whdr_to_str(whdr: DWORD) -> String {
-  let mut res = vec![];
-  if whdr & WHDR_DONE != 0 {
-    res.push("WHDR_DONE".into())
-  };
-  if whdr & WHDR_PREPARED != 0 {
-    res.push("WHDR_PREPARED".into())
-  };
-  if whdr & WHDR_BEGINLOOP != 0 {
-    res.push("WHDR_BEGINLOOP".into())
-  };
-  if whdr & WHDR_ENDLOOP != 0 {
-    res.push("WHDR_ENDLOOP".into())
-  };
-  if whdr & WHDR_INQUEUE != 0 {
-    res.push("WHDR_INQUEUE".into())
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::device::info::Encoding};
+
+  const FORMAT: DeviceFormat = DeviceFormat {
+    frequency: 8000,
+    channels: 2,
+    bits: 16,
+    encoding: Encoding::Pcm,
   };
-  if res.len() == 0 {
-    res.push(format!("UNKNOWN {}", whdr))
+
+  #[test]
+  fn bytes_round_trip_and_partial_frames_are_dropped() {
+    let bytes: Vec<u8> = [1000i16, -1000, 32767, -32768]
+      .iter()
+      .flat_map(|sample| sample.to_le_bytes().to_vec())
+      .collect();
+    let mut with_partial_frame = bytes.clone();
+    with_partial_frame.extend_from_slice(&[1, 2]);
+
+    let buffer = AudioBuffer::<i16>::from_bytes(FORMAT, &with_partial_frame);
+    assert_eq!(buffer.frames(), 2);
+    assert_eq!(buffer.samples(), &[1000, -1000, 32767, -32768]);
+    assert_eq!(buffer.to_bytes(), bytes);
+
+    let float = AudioBuffer::<f32>::from_bytes(FORMAT, &bytes);
+    assert_eq!(float.to_bytes(), bytes);
+  }
+
+  #[test]
+  fn slices_share_samples_and_keep_time() {
+    let buffer = AudioBuffer::<i16>::new(FORMAT, (0..20).collect()).with_timestamp(100);
+    let middle = buffer.slice(2..5);
+    assert_eq!(middle.timestamp(), 102);
+    assert_eq!(middle.samples(), &[4, 5, 6, 7, 8, 9]);
+    assert!(SharedSamples::ptr_eq(&middle.samples, &buffer.samples));
+
+    let inner = middle.slice(1..10);
+    assert_eq!(inner.timestamp(), 103);
+    assert_eq!(inner.samples(), &[6, 7, 8, 9]);
+
+    let chunks: Vec<(u64, usize)> = buffer.chunks(4).map(|chunk| (chunk.timestamp(), chunk.frames())).collect();
+    assert_eq!(chunks, vec![(100, 4), (104, 4), (108, 2)]);
   }
-  res.join(",")
 }
@@ -0,0 +1,444 @@
+use {
+  crate::device::{
+    common::{AudioBuffer, Sample},
+    info::*,
+    pool::BufferPool,
+  },
+  std::{f64::consts::PI, fmt, mem},
+};
+
+// How the sample rate is converted when input and output run at different rates
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Resampling {
+  // cheap, audible aliasing on downsampling
+  Linear,
+  // windowed sinc low-passed below the lower of both nyquist frequencies
+  Sinc,
+}
+
+impl fmt::Display for Resampling {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let description = match self {
+      Resampling::Linear => "linear interpolation",
+      Resampling::Sinc => "windowed sinc",
+    };
+    write!(f, "{}", description)
+  }
+}
+
+impl Resampling {
+  pub fn all() -> [Resampling; 2] {
+    [Resampling::Linear, Resampling::Sinc]
+  }
+
+  // as written in the config and typed in the shell
+  pub fn name(&self) -> &'static str {
+    match self {
+      Resampling::Linear => "linear",
+      Resampling::Sinc => "sinc",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Resampling> {
+    Resampling::all().iter().copied().find(|resampling| resampling.name() == name)
+  }
+}
+
+// converted buffers are usually played and dropped right away, a few more cover the ones still queued
+const POOLED_BUFFERS: usize = 4;
+
+// Turns buffers of one format into another: channel layout and rate, the sample type is only how the
+// result is stored. Keeps the resampler state between buffers, so one converter serves one stream.
+// Results are pooled, once the stream runs converting does not allocate
+pub struct Converter {
+  from: DeviceFormat,
+  to: DeviceFormat,
+  remixer: Option<Remixer>,
+  resampler: Option<Resampler>,
+  pool: BufferPool<f32>,
+  // remixed samples waiting to be resampled
+  remixed: Vec<f32>,
+}
+
+impl Converter {
+  // None if there is nothing to convert
+  pub fn new(from: &DeviceFormat, to: &DeviceFormat, resampling: Resampling) -> Option<Converter> {
+    if from == to {
+      return None;
+    }
+    let resampler = if from.frequency == to.frequency {
+      None
+    } else {
+      Some(Resampler::new(from.frequency, to.frequency, to.channels, resampling))
+    };
+    Some(Converter {
+      from: *from,
+      to: *to,
+      remixer: Remixer::new(from, to),
+      resampler,
+      pool: BufferPool::new(POOLED_BUFFERS, 0),
+      remixed: Vec::new(),
+    })
+  }
+
+  pub fn from(&self) -> DeviceFormat {
+    self.from
+  }
+
+  pub fn convert(&mut self, buffer: &AudioBuffer<f32>) -> AudioBuffer<f32> {
+    let mut samples = self.pool.take();
+    match (&self.remixer, &mut self.resampler) {
+      (Some(remixer), Some(resampler)) => {
+        self.remixed.clear();
+        remixer.remix(buffer.samples(), &mut self.remixed);
+        resampler.process(&self.remixed, &mut samples);
+      }
+      (Some(remixer), None) => remixer.remix(buffer.samples(), &mut samples),
+      (None, Some(resampler)) => resampler.process(buffer.samples(), &mut samples),
+      (None, None) => samples.extend_from_slice(buffer.samples()),
+    }
+    let timestamp = buffer.timestamp() * self.to.frequency as u64 / self.from.frequency as u64;
+    AudioBuffer::from_pool(self.to, samples).with_timestamp(timestamp)
+  }
+}
+
+// Converts interleaved samples between channel layouts. Matrix has a row of source channel weights
+// for every target channel
+struct Remixer {
+  from: usize,
+  matrix: Vec<Vec<f32>>,
+}
+
+const LEFT: u32 = SPEAKER_FRONT_LEFT | SPEAKER_BACK_LEFT | SPEAKER_SIDE_LEFT;
+const RIGHT: u32 = SPEAKER_FRONT_RIGHT | SPEAKER_BACK_RIGHT | SPEAKER_SIDE_RIGHT;
+const CENTER: u32 = SPEAKER_FRONT_CENTER | SPEAKER_BACK_CENTER;
+// -3dB, center is split between two speakers
+const HALF_POWER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+impl Remixer {
+  // None if formats have the same channels
+  fn new(from: &DeviceFormat, to: &DeviceFormat) -> Option<Remixer> {
+    if from.channels == to.channels {
+      return None;
+    }
+    Some(Remixer {
+      from: from.channels as usize,
+      matrix: mix_matrix(from, to),
+    })
+  }
+
+  // appends to `result`
+  fn remix(&self, samples: &[f32], result: &mut Vec<f32>) {
+    result.reserve(samples.len() / self.from * self.matrix.len());
+    for frame in samples.chunks_exact(self.from) {
+      for weights in &self.matrix {
+        result.push(weights.iter().zip(frame).map(|(weight, sample)| weight * sample).sum());
+      }
+    }
+  }
+}
+
+fn mix_matrix(from: &DeviceFormat, to: &DeviceFormat) -> Vec<Vec<f32>> {
+  let (sources, targets) = (from.speakers(), to.speakers());
+  let speaker_at = |speakers: &[u32], channel: usize| speakers.get(channel).copied().unwrap_or(0);
+  let mut matrix: Vec<Vec<f32>> = (0..to.channels as usize)
+    .map(|target| {
+      (0..from.channels as usize)
+        .map(|source| weight(from, to, &targets, speaker_at(&sources, source), speaker_at(&targets, target)))
+        .collect()
+    })
+    .collect();
+  // folded channels are attenuated so the sum can not clip
+  for weights in matrix.iter_mut() {
+    let total: f32 = weights.iter().sum();
+    if total > 1.0 {
+      weights.iter_mut().for_each(|weight| *weight /= total);
+    }
+  }
+  matrix
+}
+
+// How much of source speaker goes into target speaker, before normalisation
+fn weight(from: &DeviceFormat, to: &DeviceFormat, targets: &[u32], source: u32, target: u32) -> f32 {
+  let front_pair = SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT;
+  if to.channels == 1 {
+    // everything but the subwoofer is averaged
+    if source == SPEAKER_LOW_FREQUENCY {
+      0.0
+    } else {
+      1.0
+    }
+  } else if from.channels == 1 {
+    // mono is played by front pair at full level
+    if target & front_pair != 0 {
+      1.0
+    } else {
+      0.0
+    }
+  } else if source == target && source != 0 {
+    1.0
+  } else if source == 0 || targets.contains(&source) {
+    // unknown position or the speaker exists in the target layout
+    0.0
+  } else if source & CENTER != 0 {
+    if target == SPEAKER_FRONT_CENTER {
+      1.0
+    } else if target & front_pair != 0 && !targets.contains(&SPEAKER_FRONT_CENTER) {
+      HALF_POWER
+    } else {
+      0.0
+    }
+  } else if (source & LEFT != 0 && target == SPEAKER_FRONT_LEFT) || (source & RIGHT != 0 && target == SPEAKER_FRONT_RIGHT) {
+    HALF_POWER
+  } else {
+    0.0
+  }
+}
+
+// zero crossings of the sinc on each side at full bandwidth, the kernel widens when it low-passes
+const SINC_ZERO_CROSSINGS: f64 = 16.0;
+
+// Streaming interpolator over interleaved samples. Every output frame at fractional input position
+// p is the sum of the input frames around p weighted by the kernel
+struct Resampler {
+  resampling: Resampling,
+  channels: usize,
+  // input frames per output frame
+  step: f64,
+  // kernel bandwidth relative to input nyquist, below 1 when downsampling
+  cutoff: f64,
+  // input frames used on each side of p
+  width: usize,
+  // input not consumed yet, starts with width - 1 frames of silence so p never looks before it
+  history: Vec<f32>,
+  position: f64,
+  // kernel at the current position, kept to not allocate per buffer
+  weights: Vec<f64>,
+}
+
+impl Resampler {
+  fn new(from: u32, to: u32, channels: u16, resampling: Resampling) -> Resampler {
+    let cutoff = (to as f64 / from as f64).min(1.0);
+    let width = match resampling {
+      Resampling::Linear => 1,
+      Resampling::Sinc => (SINC_ZERO_CROSSINGS / cutoff).ceil() as usize,
+    };
+    Resampler {
+      resampling,
+      channels: channels as usize,
+      step: from as f64 / to as f64,
+      cutoff,
+      width,
+      history: vec![0.0; (width - 1) * channels as usize],
+      position: (width - 1) as f64,
+      weights: vec![0.0; 2 * width],
+    }
+  }
+
+  fn kernel(&self, distance: f64) -> f64 {
+    match self.resampling {
+      Resampling::Linear => (1.0 - distance.abs()).max(0.0),
+      Resampling::Sinc => {
+        if distance.abs() >= self.width as f64 {
+          return 0.0;
+        }
+        let x = distance * self.cutoff * PI;
+        let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+        let window = 0.5 * (1.0 + (PI * distance / self.width as f64).cos());
+        self.cutoff * sinc * window
+      }
+    }
+  }
+
+  // appends to `result`
+  fn process(&mut self, samples: &[f32], result: &mut Vec<f32>) {
+    self.history.extend_from_slice(samples);
+    let frames = self.history.len() / self.channels;
+    result.reserve(((samples.len() / self.channels) as f64 / self.step) as usize * self.channels + self.channels);
+    // taken out while `kernel` borrows self
+    let mut weights = mem::take(&mut self.weights);
+    // the last frame the kernel reaches has to be there already
+    while (self.position as usize) + self.width < frames {
+      let first = self.position as usize + 1 - self.width;
+      for (offset, weight) in weights.iter_mut().enumerate() {
+        *weight = self.kernel(self.position - (first + offset) as f64);
+      }
+      for channel in 0..self.channels {
+        let mut sum = 0.0;
+        for (offset, weight) in weights.iter().enumerate() {
+          sum += self.history[(first + offset) * self.channels + channel] as f64 * weight;
+        }
+        result.push(sum as f32);
+      }
+      self.position += self.step;
+    }
+    // frames left of the next kernel are done with
+    let consumed = (self.position as usize + 1).saturating_sub(self.width).min(frames);
+    self.history.drain(..consumed * self.channels);
+    self.position -= consumed as f64;
+    self.weights = weights;
+  }
+}
+
+// One sample of the format's type as -1.0..1.0, `bytes` starts at the sample
+pub fn read_sample(bytes: &[u8], format: &DeviceFormat) -> f32 {
+  match (format.encoding, format.bits) {
+    (Encoding::Float, _) => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    (Encoding::Pcm, 8) => bytes[0].to_f32(),
+    (Encoding::Pcm, 16) => i16::from_le_bytes([bytes[0], bytes[1]]).to_f32(),
+    // shifted into the top of an i32 so the sign is kept
+    (Encoding::Pcm, 24) => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]).to_f32(),
+    (Encoding::Pcm, _) => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).to_f32(),
+  }
+}
+
+// scaled like read_sample so integer samples survive the round trip. Sample types of the same size do
+// the scaling, their casts saturate at full scale. 24 bit has no integer type of its own
+pub fn write_sample(out: &mut Vec<u8>, sample: f32, format: &DeviceFormat) {
+  let sample = sample.clamp(-1.0, 1.0);
+  match (format.encoding, format.bits) {
+    (Encoding::Float, _) => out.extend_from_slice(&sample.to_le_bytes()),
+    (Encoding::Pcm, 8) => out.push(u8::from_f32(sample)),
+    (Encoding::Pcm, 16) => out.extend_from_slice(&i16::from_f32(sample).to_le_bytes()),
+    (Encoding::Pcm, 24) => out.extend_from_slice(&((sample * 8388608.0).round().min(8388607.0) as i32).to_le_bytes()[..3]),
+    (Encoding::Pcm, _) => out.extend_from_slice(&i32::from_f32(sample).to_le_bytes()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn format(channels: u16) -> DeviceFormat {
+    DeviceFormat {
+      frequency: 44100,
+      channels,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    }
+  }
+
+  fn pcm(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect()
+  }
+
+  fn samples(data: &[u8]) -> Vec<i16> {
+    data.chunks_exact(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect()
+  }
+
+  fn sine(frequency: f64, rate: u32, frames: usize) -> Vec<i16> {
+    (0..frames)
+      .map(|frame| ((frame as f64 * frequency / rate as f64 * 2.0 * PI).sin() * 16000.0) as i16)
+      .collect()
+  }
+
+  fn rms(samples: &[i16]) -> f64 {
+    (samples.iter().map(|&sample| (sample as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+  }
+
+  fn convert(from: &DeviceFormat, to: &DeviceFormat, resampling: Resampling, input: &[i16]) -> Vec<i16> {
+    let mut converter = Converter::new(from, to, resampling).unwrap();
+    let input = AudioBuffer::from_bytes(*from, &pcm(input));
+    // fed in device sized pieces, resampler state carries over
+    input
+      .chunks(441)
+      .flat_map(|chunk| samples(&converter.convert(&chunk).to_bytes()))
+      .collect()
+  }
+
+  #[test]
+  fn mono_and_stereo_convert_both_ways() {
+    assert!(Converter::new(&format(2), &format(2), Resampling::Sinc).is_none());
+
+    let up = convert(&format(1), &format(2), Resampling::Sinc, &[1000, -2000]);
+    assert_eq!(up, vec![1000, 1000, -2000, -2000]);
+
+    let down = convert(&format(2), &format(1), Resampling::Sinc, &[1000, 3000, -2000, 2000]);
+    assert_eq!(down, vec![2000, 0]);
+  }
+
+  #[test]
+  fn surround_is_folded_into_stereo_without_clipping() {
+    // FL FR FC LFE BL BR
+    let stereo = convert(&format(6), &format(2), Resampling::Sinc, &[8000, 0, 8000, 32000, 8000, 0]);
+    assert!(stereo[0] > stereo[1], "{:?}", stereo);
+    assert!(stereo[1] > 0, "center reaches both sides: {:?}", stereo);
+
+    let stereo = convert(&format(6), &format(2), Resampling::Sinc, &[32767; 6]);
+    assert!(stereo.iter().all(|&sample| sample > 30000), "{:?}", stereo);
+  }
+
+  #[test]
+  fn resampling_keeps_pitch_and_duration() {
+    let from = DeviceFormat {
+      frequency: 22050,
+      ..format(1)
+    };
+    for &resampling in &Resampling::all() {
+      let output = convert(&from, &format(1), resampling, &sine(441.0, 22050, 22050));
+      // only the frames the kernel still looks ahead for are missing
+      assert!(
+        output.len() <= 44100 && output.len() > 44100 - 64,
+        "{}: {}",
+        resampling,
+        output.len()
+      );
+      let expected = sine(441.0, 44100, output.len());
+      // start is faded in from the silence before the stream
+      for (frame, (&actual, &expected)) in output.iter().zip(&expected).enumerate().skip(64) {
+        assert!(
+          (actual - expected).abs() < 160,
+          "{} at {}: {} != {}",
+          resampling,
+          frame,
+          actual,
+          expected
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn sinc_filters_what_the_lower_rate_can_not_carry() {
+    let (from, to) = (
+      DeviceFormat {
+        frequency: 48000,
+        ..format(1)
+      },
+      DeviceFormat {
+        frequency: 8000,
+        ..format(1)
+      },
+    );
+    // above 4kHz nyquist of the output, would alias to 2kHz
+    let input = sine(6000.0, 48000, 48000);
+    let aliased = convert(&from, &to, Resampling::Linear, &input);
+    let filtered = convert(&from, &to, Resampling::Sinc, &input);
+    assert!(rms(&aliased[100..]) > rms(&input) * 0.5);
+    assert!(rms(&filtered[100..]) < rms(&input) * 0.05, "{}", rms(&filtered[100..]));
+  }
+
+  #[test]
+  fn every_sample_type_round_trips() {
+    for &(bits, encoding) in &DeviceFormat::candidate_samples() {
+      let format = DeviceFormat {
+        bits,
+        encoding,
+        ..format(1)
+      };
+      let mut data = Vec::new();
+      for &sample in &[0.0, 0.5, -0.5, 1.0, -1.0] {
+        write_sample(&mut data, sample, &format);
+      }
+      assert_eq!(data.len(), 5 * bits as usize / 8);
+      let tolerance = if bits == 8 { 0.01 } else { 0.0001 };
+      let read: Vec<f32> = data
+        .chunks_exact(bits as usize / 8)
+        .map(|bytes| read_sample(bytes, &format))
+        .collect();
+      for (&expected, &actual) in [0.0, 0.5, -0.5, 1.0, -1.0].iter().zip(&read) {
+        assert!((expected - actual).abs() < tolerance, "{}: {:?}", format, read);
+      }
+    }
+  }
+}
@@ -0,0 +1,31 @@
+use {crate::device::info::DeviceFormat, std::io, thiserror::Error};
+
+// What can go wrong with a device, backends map their own codes onto it. Device threads stop on
+// the first error and report it over their status channel
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum DeviceError {
+  #[error("cannot open device: {reason}")]
+  OpenFailed { reason: String },
+  #[error("format {format} is not supported by the device")]
+  BadFormat { format: DeviceFormat },
+  #[error("device is used by another application")]
+  Busy,
+  #[error("device was removed")]
+  Removed,
+  // capture has nothing more to hand out, recordings replayed from a file get there
+  #[error("end of the recording")]
+  EndOfStream,
+  #[error("{backend} error {code}: {description}")]
+  Backend {
+    backend: &'static str,
+    code: i32,
+    description: String,
+  },
+}
+
+// a device whose thread cannot be started is as good as one which cannot be opened
+pub fn spawn_error(err: io::Error) -> DeviceError {
+  DeviceError::OpenFailed {
+    reason: format!("cannot start device thread: {}", err),
+  }
+}
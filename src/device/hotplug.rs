@@ -0,0 +1,153 @@
+use {
+  crate::device::{error::DeviceError, info::*},
+  std::{
+    fmt,
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+  },
+};
+
+// how often a removed device is looked for again
+const REOPEN_INTERVAL: Duration = Duration::from_millis(200);
+
+// What a device thread does once its device is removed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HotPlug {
+  // report the removal and stop
+  Stop,
+  // wait until the same device is plugged back
+  SameDevice,
+  // same device if it is there, otherwise the first one the backend lists
+  FallBackToDefault,
+}
+
+impl fmt::Display for HotPlug {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let description = match self {
+      HotPlug::Stop => "stop when device is removed",
+      HotPlug::SameDevice => "wait for removed device to come back",
+      HotPlug::FallBackToDefault => "switch to default device",
+    };
+    write!(f, "{}", description)
+  }
+}
+
+impl HotPlug {
+  pub fn all() -> [HotPlug; 3] {
+    [HotPlug::Stop, HotPlug::SameDevice, HotPlug::FallBackToDefault]
+  }
+
+  // as written in the config and typed in the shell
+  pub fn name(&self) -> &'static str {
+    match self {
+      HotPlug::Stop => "stop",
+      HotPlug::SameDevice => "same-device",
+      HotPlug::FallBackToDefault => "fall-back-to-default",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<HotPlug> {
+    HotPlug::all().iter().copied().find(|hot_plug| hot_plug.name() == name)
+  }
+
+  fn pick<'a>(&self, devices: &'a [DeviceInfo], id: &DeviceId) -> Option<&'a DeviceInfo> {
+    match self {
+      HotPlug::Stop => None,
+      HotPlug::SameDevice => DeviceInfo::find(devices, id),
+      HotPlug::FallBackToDefault => DeviceInfo::find(devices, id).or_else(|| devices.first()),
+    }
+  }
+}
+
+// Reported by device threads over their status channel
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+  // thread has stopped
+  Failed(DeviceError),
+  // device is gone, thread keeps looking for a replacement
+  Lost(DeviceError),
+  // playing or capturing again
+  Reopened(DeviceId),
+  // capture has read the whole recording and the thread has stopped, what it read is played still
+  Ended,
+}
+
+// Device thread side of the removal handling: polls the device list until `policy` finds something
+// `open` succeeds with. The list only has to carry names, opening is what checks the format.
+// Commands arriving meanwhile are dropped, None is returned once one of them asks to stop
+pub fn reopen<C, S>(
+  recv_timeout: impl Fn(Duration) -> Result<C, RecvTimeoutError>,
+  is_stop: fn(&C) -> bool,
+  policy: HotPlug,
+  id: &DeviceId,
+  devices: impl Fn() -> Vec<DeviceInfo>,
+  mut open: impl FnMut(&DeviceInfo) -> Result<S, DeviceError>,
+) -> Option<(S, DeviceId)> {
+  if policy == HotPlug::Stop {
+    return None;
+  }
+  let mut next_attempt = Instant::now() + REOPEN_INTERVAL;
+  loop {
+    let now = Instant::now();
+    if now < next_attempt {
+      match recv_timeout(next_attempt - now) {
+        Ok(command) if is_stop(&command) => return None,
+        Ok(_) | Err(RecvTimeoutError::Timeout) => continue,
+        Err(RecvTimeoutError::Disconnected) => return None,
+      }
+    }
+    next_attempt = now + REOPEN_INTERVAL;
+    let devices = devices();
+    let device = match policy.pick(&devices, id) {
+      Some(device) => device,
+      None => continue,
+    };
+    // device may be listed before it can be opened again, next attempt tells
+    if let Ok(stream) = open(device) {
+      return Some((stream, device.id.clone()));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn devices(names: &[&str]) -> Vec<DeviceInfo> {
+    let mut devices: Vec<DeviceInfo> = names
+      .iter()
+      .enumerate()
+      .map(|(index, name)| DeviceInfo::new(index as u32, name.to_string(), Vec::new()))
+      .collect();
+    DeviceInfo::assign_ordinals(&mut devices);
+    devices
+  }
+
+  #[test]
+  fn policy_follows_identity_not_index() {
+    let before = devices(&["Speakers", "Headset", "Headset"]);
+    let second_headset = before[2].id.clone();
+    assert_eq!(second_headset.to_string(), "Headset #2");
+
+    // first device vanished, indices shifted
+    let after = devices(&["Headset", "Headset"]);
+    let picked = HotPlug::SameDevice.pick(&after, &second_headset).unwrap();
+    assert_eq!(picked.index, 1);
+
+    let after = devices(&["Speakers", "Headset"]);
+    assert!(HotPlug::SameDevice.pick(&after, &second_headset).is_none());
+    assert_eq!(HotPlug::FallBackToDefault.pick(&after, &second_headset).unwrap().index, 0);
+    assert!(HotPlug::Stop.pick(&after, &after[0].id).is_none());
+  }
+
+  #[test]
+  fn devices_are_selected_by_index_id_or_unique_name() {
+    let devices = devices(&["Speakers", "USB Headset", "USB Headset"]);
+    assert_eq!(DeviceInfo::select(&devices, "1").unwrap().index, 1);
+    assert_eq!(DeviceInfo::select(&devices, "usb headset #2").unwrap().index, 2);
+    assert_eq!(DeviceInfo::select(&devices, "USB Headset").unwrap().index, 1);
+    assert_eq!(DeviceInfo::select(&devices, "speak").unwrap().index, 0);
+    assert!(DeviceInfo::select(&devices, "head").is_none());
+    assert!(DeviceInfo::select(&devices, "3").is_none());
+  }
+}
@@ -1,166 +1,375 @@
-use {
-  crate::device::common::*,
-  std::{
-    fmt,
-    mem::{size_of, zeroed},
-  },
-  winapi::{
-    shared::{basetsd::UINT_PTR, minwindef::DWORD},
-    um::{mmeapi::*, mmsystem::*},
-  },
-};
-
-#[derive(Clone, Copy)]
+use std::{fmt, time::Duration};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct DeviceFormat {
-  pub format: DWORD,
   pub frequency: u32,
   pub channels: u16,
   pub bits: u16,
+  pub encoding: Encoding,
+}
+
+// How samples of `bits` width are stored. 8 bit PCM is unsigned, wider PCM is signed little endian
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+  Pcm,
+  Float,
+}
+
+impl Encoding {
+  // wav format tag, also used on the wire
+  pub fn tag(&self) -> u16 {
+    match self {
+      Encoding::Pcm => 0x0001,
+      Encoding::Float => 0x0003,
+    }
+  }
+
+  pub fn from_tag(tag: u16) -> Option<Encoding> {
+    match tag {
+      0x0001 => Some(Encoding::Pcm),
+      0x0003 => Some(Encoding::Float),
+      _ => None,
+    }
+  }
 }
 
 impl fmt::Display for DeviceFormat {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(
-      f,
-      "{}hz {} {}bit",
-      self.frequency,
-      if self.channels == 1 { "Mono" } else { "Stereo" },
-      self.bits
-    )
+    write!(f, "{}hz ", self.frequency)?;
+    match self.channels {
+      1 => write!(f, "Mono")?,
+      2 => write!(f, "Stereo")?,
+      6 => write!(f, "5.1")?,
+      8 => write!(f, "7.1")?,
+      channels => write!(f, "{}ch", channels)?,
+    }
+    write!(f, " {}bit", self.bits)?;
+    if self.encoding == Encoding::Float {
+      write!(f, " float")?;
+    }
+    Ok(())
   }
 }
 
+// Speaker positions as in WAVEFORMATEXTENSIBLE dwChannelMask, interleaved channels follow bit order
+pub const SPEAKER_FRONT_LEFT: u32 = 0x1;
+pub const SPEAKER_FRONT_RIGHT: u32 = 0x2;
+pub const SPEAKER_FRONT_CENTER: u32 = 0x4;
+pub const SPEAKER_LOW_FREQUENCY: u32 = 0x8;
+pub const SPEAKER_BACK_LEFT: u32 = 0x10;
+pub const SPEAKER_BACK_RIGHT: u32 = 0x20;
+pub const SPEAKER_BACK_CENTER: u32 = 0x100;
+pub const SPEAKER_SIDE_LEFT: u32 = 0x200;
+pub const SPEAKER_SIDE_RIGHT: u32 = 0x400;
+
 impl DeviceFormat {
-  pub fn unpack(packed_format: DWORD) -> Vec<DeviceFormat> {
-    // TODO there is simplification: stereo not allowed, need to break this simplification in feauture
-    // on_device_format: ($const_dword: expr, $frequency: expr, $channels: expr, $bits: expr)
-    macro_rules! enumerate_device_formats {
-      ($on_device_format: ident) => {
-        $on_device_format!(WAVE_FORMAT_1M08, 11025u32, 1u16, 8u16);
-        $on_device_format!(WAVE_FORMAT_1M16, 11025u32, 1u16, 16u16);
-        // $on_device_format!(WAVE_FORMAT_1S08, 11025u32, 2u16, 8u16)
-        // $on_device_format!(WAVE_FORMAT_1S16, 11025u32, 2u16, 16u16)
-        $on_device_format!(WAVE_FORMAT_2M08, 22050u32, 1u16, 8u16);
-        $on_device_format!(WAVE_FORMAT_2M16, 22050u32, 1u16, 16u16);
-        // $on_device_format!(WAVE_FORMAT_2S08, 22050u32, 2u16, 8u16)
-        // $on_device_format!(WAVE_FORMAT_2S16, 22050u32, 2u16, 16u16)
-        $on_device_format!(WAVE_FORMAT_4M08, 44100u32, 1u16, 8u16);
-        $on_device_format!(WAVE_FORMAT_4M16, 44100u32, 1u16, 16u16);
-        // $on_device_format!(WAVE_FORMAT_4S08, 44100u32, 2u16, 8u16)
-        // $on_device_format!(WAVE_FORMAT_4S16, 44100u32, 2u16, 16u16)
-        $on_device_format!(WAVE_FORMAT_96M08, 96000u32, 1u16, 8u16);
-        $on_device_format!(WAVE_FORMAT_96M16, 96000u32, 1u16, 16u16);
-        // $on_device_format!(WAVE_FORMAT_96S08, 96000u32, 2u16, 8u16)
-        // $on_device_format!(WAVE_FORMAT_96S16, 96000u32, 2u16, 16u16)
-      };
-    }
-    let mut result: Vec<DeviceFormat> = Vec::new();
-    macro_rules! expand_device_format_enum {
-      ($const_dword: expr, $frequency: expr, $channels: expr, $bits: expr) => {
-        if packed_format & $const_dword != 0 {
-          result.push(DeviceFormat {
-            format: $const_dword,
-            frequency: $frequency,
-            channels: $channels,
-            bits: $bits,
-          })
+  // tried against devices when they are enumerated, in order of preference
+  pub fn candidate_frequencies() -> [u32; 11] {
+    [44100, 48000, 96000, 88200, 192000, 176400, 32000, 22050, 16000, 11025, 8000]
+  }
+
+  pub fn candidate_samples() -> [(u16, Encoding); 5] {
+    [
+      (8, Encoding::Pcm),
+      (16, Encoding::Pcm),
+      (24, Encoding::Pcm),
+      (32, Encoding::Pcm),
+      (32, Encoding::Float),
+    ]
+  }
+
+  // mono, stereo and the full layout of a device with `max_channels`
+  pub fn channel_counts(max_channels: u16) -> Vec<u16> {
+    let mut channel_counts = vec![1, 2, max_channels];
+    channel_counts.retain(|&channels| channels >= 1 && channels <= max_channels);
+    channel_counts.dedup();
+    channel_counts
+  }
+
+  // every candidate with one of `channel_counts` that `supported` accepts. Formats differing only in
+  // sample type are checked one by one, drivers often take 16 bit and refuse 24 at the same rate
+  pub fn probe(channel_counts: &[u16], mut supported: impl FnMut(&DeviceFormat) -> bool) -> Vec<DeviceFormat> {
+    let mut formats = Vec::new();
+    for &channels in channel_counts {
+      for &frequency in &DeviceFormat::candidate_frequencies() {
+        for &(bits, encoding) in &DeviceFormat::candidate_samples() {
+          let format = DeviceFormat {
+            frequency,
+            channels,
+            bits,
+            encoding,
+          };
+          if supported(&format) {
+            formats.push(format);
+          }
         }
-      };
+      }
     }
-    enumerate_device_formats!(expand_device_format_enum);
-    result
+    formats
   }
-  pub fn relevant_frequencies() -> [u32; 4] {
-    [44100, 96000, 22050, 11025]
+
+  // "mono", "stereo", "5.1", "7.1", "6ch" or a plain count, the way Display writes channels
+  pub fn parse_channels(text: &str) -> Option<u16> {
+    match text.to_lowercase().as_str() {
+      "mono" => Some(1),
+      "stereo" => Some(2),
+      "5.1" => Some(6),
+      "7.1" => Some(8),
+      text => text.trim_end_matches("ch").parse().ok().filter(|&channels| channels > 0),
+    }
+  }
+
+  // "16" or "16bit" for PCM, "float" for 32 bit float
+  pub fn parse_sample(text: &str) -> Option<(u16, Encoding)> {
+    match text.to_lowercase().as_str() {
+      "float" => Some((32, Encoding::Float)),
+      text => match text.trim_end_matches("bit").parse().ok()? {
+        bits @ 8 | bits @ 16 | bits @ 24 | bits @ 32 => Some((bits, Encoding::Pcm)),
+        _ => None,
+      },
+    }
+  }
+
+  pub fn block_align(&self) -> u16 {
+    self.bits / 8 * self.channels
+  }
+
+  // default layout for the channel count, the one Windows assumes for plain WAVEFORMATEX
+  pub fn channel_mask(&self) -> u32 {
+    match self.channels {
+      1 => SPEAKER_FRONT_CENTER,
+      2 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT,
+      3 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT | SPEAKER_FRONT_CENTER,
+      4 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT | SPEAKER_BACK_LEFT | SPEAKER_BACK_RIGHT,
+      5 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT | SPEAKER_FRONT_CENTER | SPEAKER_BACK_LEFT | SPEAKER_BACK_RIGHT,
+      6 => 0x3F,
+      7 => 0x3F | SPEAKER_BACK_CENTER,
+      8 => 0x3F | SPEAKER_SIDE_LEFT | SPEAKER_SIDE_RIGHT,
+      // no standard layout, first positions in bit order
+      channels => (1u32 << channels.min(18)) - 1,
+    }
+  }
+
+  // speaker position of every channel in interleaving order
+  pub fn speakers(&self) -> Vec<u32> {
+    let mask = self.channel_mask();
+    let mut speakers: Vec<u32> = (0..32).map(|bit| 1u32 << bit).filter(|speaker| mask & speaker != 0).collect();
+    speakers.truncate(self.channels as usize);
+    speakers
+  }
+}
+
+// Ring of device buffers cycled through the backend, latency is about count * period
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferConfig {
+  pub count: u32,
+  pub period: Duration,
+}
+
+impl Default for BufferConfig {
+  fn default() -> BufferConfig {
+    BufferConfig {
+      count: 4,
+      period: Duration::from_millis(20),
+    }
+  }
+}
+
+impl fmt::Display for BufferConfig {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}x{}ms", self.count, self.period.as_millis())
+  }
+}
+
+impl BufferConfig {
+  // "4x20" or "4x20ms"
+  pub fn parse(text: &str) -> Option<BufferConfig> {
+    let mut parts = text.trim().trim_end_matches("ms").split('x');
+    let count = parts.next()?.trim().parse::<u32>().ok()?;
+    let period = parts.next()?.trim().parse::<u64>().ok()?;
+    if parts.next().is_some() || count == 0 || period == 0 {
+      return None;
+    }
+    Some(BufferConfig {
+      count,
+      period: Duration::from_millis(period),
+    })
+  }
+
+  pub fn frames(&self, format: &DeviceFormat) -> u32 {
+    ((format.frequency as u128 * self.period.as_millis() / 1000) as u32).max(1)
+  }
+
+  // bytes in one buffer, always a whole number of frames
+  pub fn buffer_length(&self, format: &DeviceFormat) -> usize {
+    self.frames(format) as usize * format.block_align() as usize
+  }
+
+  // samples in all buffers together, what one read of a capture stream usually returns at most
+  pub fn ring_samples(&self, format: &DeviceFormat) -> usize {
+    self.frames(format) as usize * format.channels as usize * self.count as usize
+  }
+}
+
+// Identifies a device across re-enumeration, unlike the index which shifts when devices come and go.
+// Equally named devices (two identical headsets) are told apart by their order among each other
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+  pub name: String,
+  pub ordinal: u32,
+}
+
+impl fmt::Display for DeviceId {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.ordinal {
+      0 => write!(f, "{}", self.name),
+      ordinal => write!(f, "{} #{}", self.name, ordinal + 1),
+    }
   }
 }
 
 #[derive(Clone)]
 pub struct DeviceInfo {
+  // valid only until devices are enumerated again
   pub index: u32,
-  name: String,
+  pub id: DeviceId,
   formats: Vec<DeviceFormat>,
 }
 
 impl fmt::Display for DeviceInfo {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{}", self.name)
+    write!(f, "{}", self.id)
   }
 }
 
 impl DeviceInfo {
-  pub fn input_devices() -> Vec<DeviceInfo> {
-    let mut available_devices: Vec<DeviceInfo> = Vec::new();
-    unsafe {
-      let device_count = waveInGetNumDevs();
-      for device_index in 0..device_count {
-        let size = size_of::<WAVEINCAPSW>() as u32;
-        let mut device_capabilities = zeroed::<WAVEINCAPSW>();
-        let mmresult = waveInGetDevCapsW(device_index as UINT_PTR, &mut device_capabilities, size);
-        if mmresult != MMSYSERR_NOERROR {
-          println!("waveInGetDevCapsW: {}", mm_error_to_string(mmresult));
-          continue;
-        }
-        let name = match String::from_utf16(&device_capabilities.szPname) {
-          Ok(res) => res,
-          _ => continue,
-        };
-        let formats = DeviceFormat::unpack(device_capabilities.dwFormats);
-        let info = DeviceInfo {
-          index: device_index,
-          name,
-          formats,
-        };
-        if info.formats.is_empty() {
-          continue;
-        }
-        available_devices.push(info);
-      }
+  pub fn new(index: u32, name: String, formats: Vec<DeviceFormat>) -> DeviceInfo {
+    DeviceInfo {
+      index,
+      id: DeviceId { name, ordinal: 0 },
+      formats,
     }
-    available_devices
   }
 
-  pub fn output_devices() -> Vec<DeviceInfo> {
-    let mut available_devices: Vec<DeviceInfo> = Vec::new();
-    unsafe {
-      let device_count = waveOutGetNumDevs();
-      for device_index in 0..device_count {
-        let size = size_of::<WAVEOUTCAPSW>() as u32;
-        let mut device_capabilities = zeroed::<WAVEOUTCAPSW>();
-        let mmresult = waveOutGetDevCapsW(device_index as UINT_PTR, &mut device_capabilities, size);
-        if mmresult != MMSYSERR_NOERROR {
-          println!("waveOutGetDevCapsW: {}", mm_error_to_string(mmresult));
-          continue;
-        }
-        let name = match String::from_utf16(&device_capabilities.szPname) {
-          Ok(res) => res,
-          _ => continue,
-        };
-        let formats = DeviceFormat::unpack(device_capabilities.dwFormats);
-        let info = DeviceInfo {
-          index: device_index,
-          name,
-          formats,
-        };
-        if info.formats.is_empty() {
-          continue;
-        }
-        available_devices.push(info);
-      }
+  // numbers equally named devices in enumeration order, backends call it on everything they list
+  pub fn assign_ordinals(devices: &mut [DeviceInfo]) {
+    for i in 0..devices.len() {
+      let ordinal = devices[..i].iter().filter(|other| other.id.name == devices[i].id.name).count();
+      devices[i].id.ordinal = ordinal as u32;
     }
-    available_devices
   }
 
-  pub fn get_best_format(&self) -> DeviceFormat {
-    let frequencies = DeviceFormat::relevant_frequencies();
-    for &frequency in &frequencies {
-      let mut freq_formats: Vec<&DeviceFormat> = self.formats.iter().filter(|x| x.frequency == frequency).collect();
-      if !freq_formats.is_empty() {
-        freq_formats.sort_by_key(|x| x.bits);
-        freq_formats.sort_by_key(|x| x.channels);
-        return freq_formats[0].clone();
-      }
+  pub fn find<'a>(devices: &'a [DeviceInfo], id: &DeviceId) -> Option<&'a DeviceInfo> {
+    devices.iter().find(|device| &device.id == id)
+  }
+
+  // what the user typed to pick a device: its index, its id ("Headset #2") or a part of the name matching
+  // exactly one device, case is ignored
+  pub fn select<'a>(devices: &'a [DeviceInfo], query: &str) -> Option<&'a DeviceInfo> {
+    let query = query.trim();
+    if let Ok(index) = query.parse::<u32>() {
+      return devices.iter().find(|device| device.index == index);
+    }
+    let query = query.to_lowercase();
+    if let Some(device) = devices.iter().find(|device| device.id.to_string().to_lowercase() == query) {
+      return Some(device);
+    }
+    let mut matching = devices.iter().filter(|device| device.id.name.to_lowercase().contains(&query));
+    match (matching.next(), matching.next()) {
+      (Some(device), None) => Some(device),
+      _ => None,
+    }
+  }
+
+  pub fn formats(&self) -> &[DeviceFormat] {
+    &self.formats
+  }
+
+  pub fn supports(&self, format: &DeviceFormat) -> bool {
+    self.formats.contains(format)
+  }
+
+  // best format at the most preferred candidate rate, a recording may have any other rate. None only
+  // for a device without formats
+  pub fn get_best_format(&self) -> Option<DeviceFormat> {
+    DeviceFormat::candidate_frequencies()
+      .iter()
+      .find_map(|&frequency| self.get_best_format_at(frequency))
+      .or_else(|| self.get_best_format_at(self.formats.first()?.frequency))
+  }
+
+  pub fn get_best_format_at(&self, frequency: u32) -> Option<DeviceFormat> {
+    self
+      .formats
+      .iter()
+      .filter(|format| format.frequency == frequency)
+      .min_by_key(|format| preference(format))
+      .copied()
+  }
+}
+
+// lower is better. Stereo goes first, then bigger layouts, mono last. 24 bit keeps what most
+// interfaces resolve, 32 bit containers mostly carry padding and 8 bit is the last resort
+fn preference(format: &DeviceFormat) -> (u16, usize) {
+  let channels = match format.channels {
+    2 => 0,
+    1 => u16::MAX,
+    channels => u16::MAX - channels,
+  };
+  let samples = [
+    (24, Encoding::Pcm),
+    (16, Encoding::Pcm),
+    (32, Encoding::Float),
+    (32, Encoding::Pcm),
+    (8, Encoding::Pcm),
+  ];
+  let sample = samples
+    .iter()
+    .position(|&sample| sample == (format.bits, format.encoding))
+    .unwrap_or(samples.len());
+  (channels, sample)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn format(frequency: u32, channels: u16, bits: u16) -> DeviceFormat {
+    DeviceFormat {
+      frequency,
+      channels,
+      bits,
+      encoding: Encoding::Pcm,
     }
-    panic!("should not happen")
+  }
+
+  #[test]
+  fn best_format_falls_back_to_any_rate() {
+    let device = DeviceInfo::new(0, "recording".to_string(), vec![format(24000, 1, 16)]);
+    assert_eq!(device.get_best_format(), Some(format(24000, 1, 16)));
+    let device = DeviceInfo::new(0, "mixed".to_string(), vec![format(24000, 1, 16), format(48000, 1, 16)]);
+    assert_eq!(device.get_best_format(), Some(format(48000, 1, 16)));
+    assert_eq!(DeviceInfo::new(0, "empty".to_string(), Vec::new()).get_best_format(), None);
+  }
+
+  #[test]
+  fn best_format_prefers_stereo_and_deep_samples() {
+    let float = DeviceFormat {
+      encoding: Encoding::Float,
+      ..format(48000, 2, 32)
+    };
+    let best = |formats: Vec<DeviceFormat>| DeviceInfo::new(0, "device".to_string(), formats).get_best_format();
+    let formats = vec![
+      format(48000, 1, 8),
+      format(48000, 1, 24),
+      format(48000, 2, 16),
+      format(48000, 2, 24),
+      format(48000, 6, 24),
+      float,
+    ];
+    assert_eq!(best(formats), Some(format(48000, 2, 24)));
+    assert_eq!(best(vec![format(48000, 2, 8), float]), Some(float));
+    assert_eq!(best(vec![format(48000, 1, 24), format(48000, 6, 16)]), Some(format(48000, 6, 16)));
+    assert_eq!(best(vec![format(48000, 1, 8), format(48000, 1, 16)]), Some(format(48000, 1, 16)));
   }
 }
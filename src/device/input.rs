@@ -1,191 +1,174 @@
 use {
-  crate::device::{common::*, info::*, output},
+  crate::device::{
+    backend::{CaptureBackend, CaptureStream, Notifier},
+    common::PIPELINE_DEPTH,
+    error::{spawn_error, DeviceError},
+    hotplug::{self, DeviceEvent, HotPlug},
+    info::*,
+    meter::Meter,
+    output,
+  },
   std::{
-    mem::{size_of, zeroed},
-    sync::{mpsc, mpsc::RecvTimeoutError},
+    sync::{mpsc, Arc},
     thread,
     time::Duration,
   },
-  // thiserror::Error,
-  winapi::{
-    shared::{
-      basetsd::DWORD_PTR,
-      mmreg::{WAVEFORMATEX, WAVE_FORMAT_PCM},
-    },
-    um::{mmeapi::*, mmsystem::*},
-  },
 };
 
 pub struct InputDevice {
-  sender: mpsc::Sender<Command>,
+  sender: mpsc::SyncSender<Command>,
   thread: Option<std::thread::JoinHandle<()>>,
+  status: mpsc::Receiver<DeviceEvent>,
+  meter: Arc<Meter>,
 }
 
 pub enum Command {
-  Init,
   Stop,
   NewData,
 }
 
-// #[derive(Error, Debug, Clone)]
-// pub enum OpenDeviceError {
-//   #[error("winapi mm error {code:?}")]
-//   MultimediaError { code: u32, description: &'static str },
-//   #[error("unknown error")]
-//   UnknownError,
-// }
-
 impl Drop for InputDevice {
   fn drop(&mut self) {
-    println!("InputDevice.drop is running");
-    if self.thread.is_some() {
-      println!("InputDevice.drop: sending Stop signal");
-      self.sender.send(Command::Stop).unwrap();
-      println!("InputDevice.drop: running join!");
-      self.thread.take().unwrap().join().unwrap();
-      println!("InputDevice.drop: joining done");
+    if let Some(thread) = self.thread.take() {
+      // thread is already gone if it failed
+      let _ = self.sender.send(Command::Stop);
+      if thread.join().is_err() {
+        println!("InputDevice.drop: device thread panicked");
+      }
     }
-    println!("InputDevice.drop done");
   }
 }
 
 impl InputDevice {
-  // TODO handle errors
-  pub fn new(desired_format: DeviceFormat, device_index: u32, output: mpsc::Sender<output::Command>) -> InputDevice {
-    let (sender, reciever) = mpsc::channel();
+  // returns once the stream is started, later errors and removals show up in poll_event
+  pub fn new<B: CaptureBackend>(
+    backend: &B,
+    desired_format: DeviceFormat,
+    buffers: BufferConfig,
+    device: &DeviceInfo,
+    hot_plug: HotPlug,
+    output: output::OutputSender,
+  ) -> Result<InputDevice, DeviceError> {
+    let (sender, reciever) = mpsc::sync_channel(PIPELINE_DEPTH);
+    let (status_sender, status) = mpsc::channel();
+    let (started_sender, started) = mpsc::channel();
+    let backend = backend.clone();
+    let (device_index, mut device_id) = (device.index, device.id.clone());
+    let meter = Arc::new(Meter::default());
+    let thread_meter = meter.clone();
+    // backend wakes the thread on every completed buffer, nothing happens between notifications.
+    // Full queue means wakeups are pending already and each of them reads everything captured so far
+    let notify_sender = sender.clone();
+    let notifier = Notifier::new(move || {
+      let _ = notify_sender.try_send(Command::NewData);
+    });
     let thread = thread::Builder::new()
       .name("input".into())
-      .spawn(move || unsafe {
-        let mut input_processor = InputProcessor::new(desired_format, device_index, output);
+      .spawn(move || {
+        let open = |device_index: u32| {
+          let opened = backend.open_capture(desired_format, buffers, device_index, notifier.clone());
+          opened.and_then(|mut stream| stream.start().map(|_| stream))
+        };
+        let mut stream = match open(device_index) {
+          Ok(stream) => stream,
+          Err(err) => {
+            let _ = started_sender.send(Err(err));
+            return;
+          }
+        };
+        let _ = started_sender.send(Ok(()));
+        // one timeline for the whole capture, re-opened streams count from zero again
+        let mut captured_frames: u64 = 0;
         loop {
-          let msg = match reciever.recv_timeout(Duration::from_millis(10)) {
+          let msg = match reciever.recv() {
             Ok(msg) => msg,
-            Err(RecvTimeoutError::Timeout) => Command::NewData,
             Err(err) => {
               println!("InputDevice: recv error {}", err);
-              continue;
+              break;
             }
           };
           match msg {
-            Command::Init => input_processor.init(),
-            Command::NewData => input_processor.new_data(),
+            Command::NewData => match stream.read() {
+              Ok(Some(buffer)) => {
+                let buffer = buffer.with_timestamp(captured_frames);
+                captured_frames += buffer.frames() as u64;
+                thread_meter.measure(&buffer);
+                if output.send(output::Command::NewData(buffer)).is_err() {
+                  // consumer is gone, nobody to capture for
+                  let _ = stream.stop();
+                  break;
+                }
+              }
+              Ok(None) => {}
+              Err(DeviceError::EndOfStream) => {
+                let _ = stream.stop();
+                let _ = status_sender.send(DeviceEvent::Ended);
+                break;
+              }
+              Err(DeviceError::Removed) if hot_plug != HotPlug::Stop => {
+                let _ = stream.stop();
+                let _ = status_sender.send(DeviceEvent::Lost(DeviceError::Removed));
+                let is_stop = |command: &Command| matches!(command, Command::Stop);
+                let devices = || backend.input_devices_unprobed();
+                match hotplug::reopen(
+                  |timeout| reciever.recv_timeout(timeout),
+                  is_stop,
+                  hot_plug,
+                  &device_id,
+                  devices,
+                  |device| open(device.index),
+                ) {
+                  Some((reopened, id)) => {
+                    stream = reopened;
+                    device_id = id;
+                    let _ = status_sender.send(DeviceEvent::Reopened(device_id.clone()));
+                  }
+                  None => break,
+                }
+              }
+              Err(err) => {
+                let _ = stream.stop();
+                let _ = status_sender.send(DeviceEvent::Failed(err));
+                break;
+              }
+            },
             Command::Stop => {
-              input_processor.stop();
+              if let Err(err) = stream.stop() {
+                println!("InputDevice: {}", err);
+              }
               break;
             }
           }
         }
       })
-      .unwrap();
-    sender.send(Command::Init).unwrap();
-    InputDevice {
+      .map_err(spawn_error)?;
+    let device = InputDevice {
       sender,
       thread: Some(thread),
-    }
-  }
-}
-
-struct InputProcessor {
-  output: mpsc::Sender<output::Command>,
-  format: WAVEFORMATEX,
-  device_index: u32,
-  buffer: WaveBuffer,
-  handle: HWAVEIN,
-  header: WAVEHDR,
-}
-
-impl InputProcessor {
-  unsafe fn new(desired_format: DeviceFormat, device_index: u32, output: mpsc::Sender<output::Command>) -> InputProcessor {
-    let mut format = zeroed::<WAVEFORMATEX>();
-    format.wFormatTag = WAVE_FORMAT_PCM;
-    format.nChannels = desired_format.channels;
-    format.nSamplesPerSec = desired_format.frequency;
-    format.wBitsPerSample = desired_format.bits;
-    format.nBlockAlign = (format.wBitsPerSample / 8) * format.nChannels;
-    format.nAvgBytesPerSec = format.nSamplesPerSec * format.nBlockAlign as u32;
-    format.cbSize = 0;
-    let buffer = WaveBuffer::new(format.nAvgBytesPerSec as usize);
-    let handle = zeroed::<HWAVEIN>();
-    let header = zeroed::<WAVEHDR>();
-    InputProcessor {
-      output,
-      format,
-      handle,
-      header,
-      buffer,
-      device_index,
+      status,
+      meter,
+    };
+    match started.recv() {
+      Ok(Ok(())) => Ok(device),
+      Ok(Err(err)) => Err(err),
+      Err(_) => Err(DeviceError::OpenFailed {
+        reason: "input thread died while opening the device".to_string(),
+      }),
     }
   }
 
-  unsafe fn init(&mut self) {
-    // WAVE_FORMAT_DIRECT??? does not perform conversions on the audio data
-    let mmresult = waveInOpen(&mut self.handle, self.device_index, &self.format, 0 as DWORD_PTR, 0 as DWORD_PTR, 0);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveInOpen: {}", mm_error_to_string(mmresult));
-    };
-    self.header.lpData = self.buffer.data;
-    self.header.dwBufferLength = self.buffer.length();
-    let mmresult = waveInPrepareHeader(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveInPrepareHeader: {}", mm_error_to_string(mmresult));
-    };
-    let mmresult = waveInAddBuffer(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveInAddBuffer: {}", mm_error_to_string(mmresult));
-    };
-    println!("running input");
-    let mmresult = waveInStart(self.handle);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveInStart: {}", mm_error_to_string(mmresult));
-    };
-    println!("InputProcessor: initialized!");
+  // levels of what was captured
+  pub fn meter(&self) -> &Meter {
+    &self.meter
   }
 
-  unsafe fn new_data(&mut self) {
-    if self.header.dwFlags & WHDR_INQUEUE != 0 {
-      return;
-    }
-    if self.header.dwFlags & WHDR_DONE != 0 {
-      // see https://docs.rs/winapi/0.3.8/i686-pc-windows-msvc/winapi/um/mmsystem/struct.WAVEHDR.html
-      // header.lpData: *mut i8
-      // header.dwBufferLength: u32
-      //
-      // let mmresult = waveInPrepareHeader(handle, &mut header, size_of::<WAVEHDR>() as u32);
-      // if mmresult != MMSYSERR_NOERROR {
-      //   panic!("waveInPrepareHeader: {}", mm_error_to_string(mmresult));
-      // }
-      let send_buffer = self.buffer.partially_clone(self.header.dwBytesRecorded);
-      self.output.send(output::Command::NewData(send_buffer)).unwrap();
-      let mmresult = waveInAddBuffer(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-      if mmresult != MMSYSERR_NOERROR {
-        panic!("waveInAddBuffer error {}", mm_error_to_string(mmresult));
-      };
-      return;
-    }
-    println!("WARN: input header.dwFlags = {} not handled!", whdr_to_str(self.header.dwFlags));
+  // removals, re-opens and the error which stopped the device thread, in the order they happened
+  pub fn poll_event(&self) -> Option<DeviceEvent> {
+    self.status.try_recv().ok()
   }
 
-  unsafe fn stop(&mut self) {
-    println!("waveInStop");
-    let mmresult = waveInStop(self.handle);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveInStop: {}", mm_error_to_string(mmresult));
-    };
-    println!("waveInReset");
-    let mmresult = waveInReset(self.handle);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveInReset: {}", mm_error_to_string(mmresult));
-    };
-    println!("waveInUnprepareHeader");
-    let mmresult = waveInUnprepareHeader(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveInUnprepareHeader: {}", mm_error_to_string(mmresult));
-    };
-    println!("waveInClose");
-    let mmresult = waveInClose(self.handle);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveInClose: {}", mm_error_to_string(mmresult));
-    };
-    println!("InputProcessor: stop!");
+  // like poll_event, but waits up to `timeout` for the next event
+  pub fn wait_event(&self, timeout: Duration) -> Option<DeviceEvent> {
+    self.status.recv_timeout(timeout).ok()
   }
 }
@@ -0,0 +1,407 @@
+use {
+  crate::device::{
+    convert::{read_sample, write_sample},
+    info::*,
+    net::NetPacket,
+  },
+  std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+  },
+};
+
+// how long missing audio is concealed by fading out the last packet before playout rebuffers
+const CONCEAL_FADE: Duration = Duration::from_millis(60);
+// played sequence numbers remembered to tell duplicates from late packets
+const PLAYED_HISTORY: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JitterConfig {
+  pub min_delay: Duration,
+  pub max_delay: Duration,
+}
+
+impl Default for JitterConfig {
+  fn default() -> JitterConfig {
+    JitterConfig {
+      min_delay: Duration::from_millis(40),
+      max_delay: Duration::from_millis(500),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct JitterStats {
+  pub received: u64,
+  // arrived after their playout time
+  pub late: u64,
+  // never arrived in time, replaced by concealment
+  pub lost: u64,
+  pub duplicate: u64,
+  // thrown away because buffer grew far beyond target delay
+  pub dropped: u64,
+  pub concealed: Duration,
+  pub depth: Duration,
+  pub target: Duration,
+  pub jitter: Duration,
+}
+
+impl fmt::Display for JitterStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "received {}, late {}, lost {}, duplicate {}, dropped {}, concealed {}ms, depth {}ms (target {}ms), jitter {}ms",
+      self.received,
+      self.late,
+      self.lost,
+      self.duplicate,
+      self.dropped,
+      self.concealed.as_millis(),
+      self.depth.as_millis(),
+      self.target.as_millis(),
+      self.jitter.as_millis()
+    )
+  }
+}
+
+// Reorders network packets and releases them at the pace of the consumer. Playout starts once
+// the buffered audio reaches target delay, which follows measured arrival jitter. Gaps are concealed
+// by repeating the last packet with fading gain, long underruns make playout buffer again
+pub struct JitterBuffer {
+  format: DeviceFormat,
+  config: JitterConfig,
+  // keyed by sequence number extended to i64 so wrapping does not break ordering
+  packets: BTreeMap<i64, NetPacket>,
+  buffering: bool,
+  next_sequence: Option<i64>,
+  next_timestamp: i64,
+  // part of the packet which did not fit into the previous pull
+  current: Vec<u8>,
+  last_payload: Vec<u8>,
+  concealed_frames: usize,
+  played: VecDeque<i64>,
+  // arrival clock, transit times and jitter are in frames
+  start: Option<Instant>,
+  last_transit: Option<f64>,
+  jitter: f64,
+  stats: JitterStats,
+}
+
+impl JitterBuffer {
+  pub fn new(format: DeviceFormat, config: JitterConfig) -> JitterBuffer {
+    JitterBuffer {
+      format,
+      config,
+      packets: BTreeMap::new(),
+      buffering: true,
+      next_sequence: None,
+      next_timestamp: 0,
+      current: Vec::new(),
+      last_payload: Vec::new(),
+      concealed_frames: 0,
+      played: VecDeque::new(),
+      start: None,
+      last_transit: None,
+      jitter: 0.0,
+      stats: JitterStats::default(),
+    }
+  }
+
+  pub fn stats(&self) -> JitterStats {
+    JitterStats {
+      depth: self.frames_to_duration(self.depth_frames()),
+      target: self.frames_to_duration(self.target_frames()),
+      jitter: self.frames_to_duration(self.jitter as usize),
+      ..self.stats
+    }
+  }
+
+  pub fn push(&mut self, packet: NetPacket, arrival: Instant) {
+    self.stats.received += 1;
+    let sequence = extend(self.next_sequence.or_else(|| self.packets.keys().next().cloned()), packet.sequence);
+    if self.played.contains(&sequence) || self.packets.contains_key(&sequence) {
+      self.stats.duplicate += 1;
+      return;
+    }
+    if let Some(next_sequence) = self.next_sequence {
+      if sequence < next_sequence {
+        self.stats.late += 1;
+        return;
+      }
+    }
+    self.update_jitter(&packet, arrival);
+    self.packets.insert(sequence, packet);
+
+    // sender outpaces us or a burst arrived after a stall: keep latency bounded. The newest packet
+    // stays even if it alone is longer than the limit, or nothing would ever play
+    let limit = self.target_frames() + self.duration_to_frames(self.config.max_delay);
+    while self.packets.len() > 1 && self.depth_frames() > limit {
+      let sequence = *self.packets.keys().next().unwrap();
+      self.packets.remove(&sequence);
+      self.next_sequence = Some(sequence + 1);
+      self.stats.dropped += 1;
+    }
+  }
+
+  // Appends exactly `frames` frames of audio to `out` or nothing while buffering
+  pub fn pull(&mut self, frames: usize, out: &mut Vec<u8>) {
+    if self.buffering {
+      if self.packets.is_empty() || self.depth_frames() < self.target_frames() {
+        return;
+      }
+      self.resync();
+    }
+    let block_align = self.block_align();
+    let end = out.len() + frames * block_align;
+    while out.len() < end {
+      let missing = (end - out.len()) / block_align;
+      if !self.current.is_empty() {
+        let length = (missing * block_align).min(self.current.len());
+        out.extend(self.current.drain(..length));
+        continue;
+      }
+      let next_sequence = self.next_sequence.unwrap();
+      let (sequence, timestamp) = match self.packets.iter().next() {
+        Some((&sequence, packet)) => (sequence, extend(Some(self.next_timestamp), packet.timestamp)),
+        None => {
+          // underrun
+          self.conceal(missing, out);
+          if self.concealed_frames >= self.duration_to_frames(CONCEAL_FADE) {
+            self.buffering = true;
+            break;
+          }
+          continue;
+        }
+      };
+      if sequence > next_sequence && timestamp > self.next_timestamp {
+        let gap = (timestamp - self.next_timestamp) as usize;
+        self.conceal(gap.min(missing), out);
+        continue;
+      }
+      if sequence > next_sequence {
+        self.stats.lost += (sequence - next_sequence) as u64;
+      }
+      self.play(sequence);
+    }
+  }
+
+  fn play(&mut self, sequence: i64) {
+    let packet = self.packets.remove(&sequence).unwrap();
+    let frames = packet.payload.len() / self.block_align();
+    self.next_sequence = Some(sequence + 1);
+    self.next_timestamp = extend(Some(self.next_timestamp), packet.timestamp) + frames as i64;
+    self.played.push_back(sequence);
+    if self.played.len() > PLAYED_HISTORY {
+      self.played.pop_front();
+    }
+    self.current = packet.payload.clone();
+    self.last_payload = packet.payload;
+    self.concealed_frames = 0;
+  }
+
+  // playout (re)starts from the oldest buffered packet, everything skipped before it is lost
+  fn resync(&mut self) {
+    let (&sequence, packet) = self.packets.iter().next().unwrap();
+    if let Some(next_sequence) = self.next_sequence {
+      self.stats.lost += (sequence - next_sequence).max(0) as u64;
+    }
+    self.next_sequence = Some(sequence);
+    self.next_timestamp = packet.timestamp as i64;
+    self.buffering = false;
+  }
+
+  fn conceal(&mut self, frames: usize, out: &mut Vec<u8>) {
+    let channels = self.format.channels as usize;
+    let fade_frames = self.duration_to_frames(CONCEAL_FADE).max(1);
+    let last_frames = self.last_payload.len() / self.block_align();
+    let sample_length = self.format.bits as usize / 8;
+    for _ in 0..frames {
+      let gain = 1.0 - (self.concealed_frames as f32 / fade_frames as f32).min(1.0);
+      for channel in 0..channels {
+        let sample = if last_frames == 0 {
+          0.0
+        } else {
+          let index = (self.concealed_frames % last_frames) * channels + channel;
+          read_sample(&self.last_payload[index * sample_length..], &self.format) * gain
+        };
+        write_sample(out, sample, &self.format);
+      }
+      self.concealed_frames += 1;
+    }
+    self.next_timestamp += frames as i64;
+    self.stats.concealed += self.frames_to_duration(frames);
+  }
+
+  // RFC 3550 interarrival jitter
+  fn update_jitter(&mut self, packet: &NetPacket, arrival: Instant) {
+    let start = *self.start.get_or_insert(arrival);
+    let arrival_frames = arrival.duration_since(start).as_secs_f64() * self.format.frequency as f64;
+    let transit = arrival_frames - packet.timestamp as f64;
+    if let Some(last_transit) = self.last_transit {
+      let difference = (transit - last_transit).abs();
+      // timestamps wrapped or sender restarted, do not let it blow the estimate up
+      if difference < self.format.frequency as f64 {
+        self.jitter += (difference - self.jitter) / 16.0;
+      }
+    }
+    self.last_transit = Some(transit);
+  }
+
+  fn target_frames(&self) -> usize {
+    let min = self.duration_to_frames(self.config.min_delay);
+    let max = self.duration_to_frames(self.config.max_delay);
+    ((self.jitter * 4.0) as usize).max(min).min(max)
+  }
+
+  fn depth_frames(&self) -> usize {
+    let bytes: usize = self.packets.values().map(|packet| packet.payload.len()).sum::<usize>() + self.current.len();
+    bytes / self.block_align()
+  }
+
+  fn block_align(&self) -> usize {
+    self.format.block_align() as usize
+  }
+
+  fn duration_to_frames(&self, duration: Duration) -> usize {
+    (duration.as_secs_f64() * self.format.frequency as f64) as usize
+  }
+
+  fn frames_to_duration(&self, frames: usize) -> Duration {
+    Duration::from_micros(frames as u64 * 1_000_000 / self.format.frequency as u64)
+  }
+}
+
+// u32 counter on the wire to i64 nearest to the reference
+fn extend(reference: Option<i64>, value: u32) -> i64 {
+  match reference {
+    Some(reference) => reference + (value.wrapping_sub(reference as u32) as i32) as i64,
+    None => value as i64,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FORMAT: DeviceFormat = DeviceFormat {
+    frequency: 1000,
+    channels: 1,
+    bits: 8,
+    encoding: Encoding::Pcm,
+  };
+
+  // 10ms packets, every packet is filled with value derived from its sequence number
+  fn packet(sequence: u32) -> NetPacket {
+    NetPacket {
+      format: FORMAT,
+      sequence,
+      timestamp: sequence * 10,
+      payload: vec![level(sequence); 10],
+    }
+  }
+
+  fn level(sequence: u32) -> u8 {
+    128 + 10 * sequence as u8
+  }
+
+  fn pull(jitter: &mut JitterBuffer, frames: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    jitter.pull(frames, &mut out);
+    out
+  }
+
+  fn buffer() -> JitterBuffer {
+    JitterBuffer::new(
+      FORMAT,
+      JitterConfig {
+        min_delay: Duration::from_millis(30),
+        max_delay: Duration::from_millis(200),
+      },
+    )
+  }
+
+  #[test]
+  fn reorders_and_waits_for_target_delay() {
+    let mut jitter = buffer();
+    let now = Instant::now();
+    jitter.push(packet(1), now);
+    jitter.push(packet(0), now);
+    assert!(pull(&mut jitter, 10).is_empty());
+    jitter.push(packet(2), now);
+    let played = pull(&mut jitter, 30);
+    assert_eq!(&played[..10], &[level(0); 10]);
+    assert_eq!(&played[10..20], &[level(1); 10]);
+    assert_eq!(&played[20..], &[level(2); 10]);
+    assert_eq!(jitter.stats().lost, 0);
+  }
+
+  #[test]
+  fn counts_late_and_duplicate_packets() {
+    let mut jitter = buffer();
+    let now = Instant::now();
+    for sequence in 0..4 {
+      jitter.push(packet(sequence), now);
+    }
+    pull(&mut jitter, 20);
+    jitter.push(packet(1), now);
+    jitter.push(packet(3), now);
+    let stats = jitter.stats();
+    assert_eq!(stats.duplicate, 2);
+    assert_eq!(stats.late, 0);
+    assert_eq!(stats.depth, Duration::from_millis(20));
+
+    let mut jitter = buffer();
+    for &sequence in &[0, 1, 3, 4] {
+      jitter.push(packet(sequence), now);
+    }
+    pull(&mut jitter, 50);
+    jitter.push(packet(2), now);
+    assert_eq!(jitter.stats().late, 1);
+    assert_eq!(jitter.stats().lost, 1);
+  }
+
+  #[test]
+  fn limit_shorter_than_a_packet_keeps_the_newest() {
+    let mut jitter = JitterBuffer::new(
+      FORMAT,
+      JitterConfig {
+        min_delay: Duration::from_millis(0),
+        max_delay: Duration::from_millis(2),
+      },
+    );
+    let now = Instant::now();
+    jitter.push(packet(0), now);
+    jitter.push(packet(1), now);
+    assert_eq!(jitter.stats().dropped, 1);
+    assert_eq!(pull(&mut jitter, 5), vec![level(1); 5]);
+    // half of packet 1 is still being played and is already over the limit
+    jitter.push(packet(2), now);
+    let played = pull(&mut jitter, 15);
+    assert_eq!(&played[..5], &[level(1); 5]);
+    assert_eq!(&played[5..], &[level(2); 10]);
+    assert_eq!(jitter.stats().dropped, 1);
+  }
+
+  #[test]
+  fn conceals_lost_packet_with_faded_repetition() {
+    let mut jitter = buffer();
+    let now = Instant::now();
+    for &sequence in &[0, 1, 3] {
+      jitter.push(packet(sequence), now);
+    }
+    let played = pull(&mut jitter, 40);
+    assert_eq!(&played[10..20], &[level(1); 10]);
+    // gap is filled with the previous packet fading towards silence
+    assert_eq!(played[20], level(1));
+    assert!(played[20..30].windows(2).all(|pair| pair[0] >= pair[1] && pair[1] > 128));
+    assert_eq!(&played[30..], &[level(3); 10]);
+    assert_eq!(jitter.stats().lost, 1);
+
+    // nothing more arrives: fade out, then rebuffer
+    let played = pull(&mut jitter, 100);
+    assert_eq!(played.len(), 100);
+    assert!(played[60..].iter().all(|&sample| sample == 128));
+    assert!(pull(&mut jitter, 10).is_empty());
+  }
+}
@@ -0,0 +1,95 @@
+use {crate::device::common::AudioBuffer, std::sync::Mutex};
+
+// Levels of the audio a device thread passed on, measured per channel since the meter was last read.
+// Measuring does not allocate unless the channel count changes
+#[derive(Default)]
+pub struct Meter {
+  state: Mutex<MeterState>,
+}
+
+#[derive(Default)]
+struct MeterState {
+  peak: Vec<f32>,
+  squares: Vec<f64>,
+  frames: u64,
+  seconds: f64,
+}
+
+// full scale is 1.0
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Levels {
+  pub peak: Vec<f32>,
+  pub rms: Vec<f32>,
+  // audio measured since the device started
+  pub seconds: f64,
+}
+
+impl Meter {
+  pub fn measure(&self, buffer: &AudioBuffer<f32>) {
+    let channels = buffer.format().channels as usize;
+    if channels == 0 {
+      return;
+    }
+    let mut state = self.state.lock().unwrap();
+    if state.peak.len() != channels {
+      state.peak = vec![0.0; channels];
+      state.squares = vec![0.0; channels];
+    }
+    for frame in buffer.samples().chunks_exact(channels) {
+      for (channel, &sample) in frame.iter().enumerate() {
+        state.peak[channel] = state.peak[channel].max(sample.abs());
+        state.squares[channel] += sample as f64 * sample as f64;
+      }
+    }
+    state.frames += buffer.frames() as u64;
+    state.seconds += buffer.frames() as f64 / buffer.format().frequency as f64;
+  }
+
+  // levels since the last read, silence if nothing was measured meanwhile
+  pub fn read(&self) -> Levels {
+    let mut state = self.state.lock().unwrap();
+    let frames = state.frames.max(1) as f64;
+    let levels = Levels {
+      peak: state.peak.clone(),
+      rms: state.squares.iter().map(|squares| (squares / frames).sqrt() as f32).collect(),
+      seconds: state.seconds,
+    };
+    state.peak.iter_mut().for_each(|peak| *peak = 0.0);
+    state.squares.iter_mut().for_each(|squares| *squares = 0.0);
+    state.frames = 0;
+    levels
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::device::info::{DeviceFormat, Encoding},
+  };
+
+  #[test]
+  fn peak_and_rms_are_per_channel_and_reset_on_read() {
+    let format = DeviceFormat {
+      frequency: 4,
+      channels: 2,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    };
+    let meter = Meter::default();
+    // left is a full scale square wave, right is silent
+    meter.measure(&AudioBuffer::new(format, vec![1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0]));
+    let levels = meter.read();
+    assert_eq!(levels.peak, vec![1.0, 0.0]);
+    assert_eq!(levels.rms, vec![1.0, 0.0]);
+    assert_eq!(levels.seconds, 1.0);
+
+    let levels = meter.read();
+    assert_eq!(levels.peak, vec![0.0, 0.0]);
+    assert_eq!(levels.seconds, 1.0);
+
+    let no_channels = DeviceFormat { channels: 0, ..format };
+    meter.measure(&AudioBuffer::new(no_channels, vec![1.0, 1.0]));
+    assert_eq!(meter.read().seconds, 1.0);
+  }
+}
@@ -1,4 +1,13 @@
-mod common;
+pub mod backend;
+pub mod common;
+pub mod convert;
+pub mod error;
+pub mod hotplug;
 pub mod info;
 pub mod input;
+pub mod jitter;
+pub mod meter;
+pub mod net;
 pub mod output;
+pub mod pool;
+mod wav;
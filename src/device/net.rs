@@ -0,0 +1,503 @@
+use {
+  crate::device::{
+    common::*,
+    error::{spawn_error, DeviceError},
+    hotplug::DeviceEvent,
+    info::*,
+    jitter::*,
+    output,
+    pool::BufferPool,
+  },
+  std::{
+    convert::TryInto,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+  },
+};
+
+pub const DEFAULT_PORT: u16 = 7373;
+
+// Every datagram is self-describing raw PCM, all numbers are little endian:
+// magic "DVNA" | frequency u32 | channels u16 | bits u16 | encoding u16 | sequence u32 | timestamp u32 | samples
+const MAGIC: &[u8; 4] = b"DVNA";
+const HEADER_LENGTH: usize = 22;
+// keeps datagrams below usual ethernet MTU so they are never fragmented
+const MAX_DATAGRAM: usize = 1400;
+// playout period of the jitter buffer
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(10);
+
+#[derive(Clone, PartialEq)]
+pub struct NetPacket {
+  pub format: DeviceFormat,
+  // increments by one per datagram
+  pub sequence: u32,
+  // position of the first sample in frames since the start of the stream
+  pub timestamp: u32,
+  pub payload: Vec<u8>,
+}
+
+impl NetPacket {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&self.format.frequency.to_le_bytes());
+    bytes.extend_from_slice(&self.format.channels.to_le_bytes());
+    bytes.extend_from_slice(&self.format.bits.to_le_bytes());
+    bytes.extend_from_slice(&self.format.encoding.tag().to_le_bytes());
+    bytes.extend_from_slice(&self.sequence.to_le_bytes());
+    bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+    bytes.extend_from_slice(&self.payload);
+    bytes
+  }
+
+  // None for datagrams which are not ours or do not describe whole frames of a playable format
+  pub fn parse(bytes: &[u8]) -> Option<NetPacket> {
+    if bytes.len() < HEADER_LENGTH || &bytes[0..4] != MAGIC {
+      return None;
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let format = DeviceFormat {
+      frequency: u32_at(4),
+      channels: u16_at(8),
+      bits: u16_at(10),
+      encoding: Encoding::from_tag(u16_at(12))?,
+    };
+    let payload = &bytes[HEADER_LENGTH..];
+    // the jitter buffer divides by the rate and the frame size, conversion reads whole samples
+    if format.frequency == 0
+      || format.channels == 0
+      || !DeviceFormat::candidate_samples().contains(&(format.bits, format.encoding))
+      || !payload.len().is_multiple_of(format.block_align() as usize)
+    {
+      return None;
+    }
+    Some(NetPacket {
+      format,
+      sequence: u32_at(14),
+      timestamp: u32_at(18),
+      payload: payload.to_vec(),
+    })
+  }
+}
+
+fn socket_error(action: &str, err: io::Error) -> DeviceError {
+  DeviceError::Backend {
+    backend: "net",
+    code: err.raw_os_error().unwrap_or(0),
+    description: format!("cannot {}: {}", action, err),
+  }
+}
+
+// Stands in for OutputDevice on the capturing side: InputDevice sends its buffers here
+// and they are streamed to the peer
+pub struct NetSender {
+  pub sender: output::OutputSender,
+  thread: Option<thread::JoinHandle<()>>,
+  status: mpsc::Receiver<DeviceEvent>,
+}
+
+impl Drop for NetSender {
+  fn drop(&mut self) {
+    if let Some(thread) = self.thread.take() {
+      // thread is already gone if it panicked
+      let _ = self.sender.send(output::Command::Stop);
+      if thread.join().is_err() {
+        println!("NetSender.drop: sender thread panicked");
+      }
+    }
+  }
+}
+
+impl NetSender {
+  pub fn new(peer: SocketAddr) -> Result<NetSender, DeviceError> {
+    let local: SocketAddr = if peer.is_ipv4() {
+      "0.0.0.0:0".parse().unwrap()
+    } else {
+      "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local).map_err(|err| socket_error("bind", err))?;
+    socket.connect(peer).map_err(|err| socket_error("connect", err))?;
+    let (sender, reciever) = output::queue();
+    let (status_sender, status) = mpsc::channel();
+    let thread = thread::Builder::new()
+      .name("net sender".into())
+      .spawn(move || {
+        let mut sequence: u32 = 0;
+        'stream: while let Ok(msg) = reciever.recv() {
+          match msg {
+            // datagrams carry the format and capture timeline of the buffers
+            output::Command::NewData(buffer) => {
+              let format = buffer.format();
+              let chunk_frames = (MAX_DATAGRAM - HEADER_LENGTH) / format.block_align() as usize;
+              for chunk in buffer.chunks(chunk_frames) {
+                let packet = NetPacket {
+                  format,
+                  sequence,
+                  // wraps, receiver extends it back
+                  timestamp: chunk.timestamp() as u32,
+                  payload: chunk.to_bytes(),
+                };
+                match socket.send(&packet.to_bytes()) {
+                  Ok(_) => {}
+                  // peer is not listening yet, the connected socket hears it back from an earlier datagram.
+                  // udp does not care and neither do we
+                  Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {}
+                  Err(err) => {
+                    let _ = status_sender.send(DeviceEvent::Failed(socket_error("send", err)));
+                    break 'stream;
+                  }
+                }
+                sequence = sequence.wrapping_add(1);
+              }
+            }
+            // playback gain, the peer sets its own
+            output::Command::Volume(_) => {}
+            output::Command::Stop => break,
+          }
+        }
+      })
+      .map_err(spawn_error)?;
+    Ok(NetSender {
+      sender,
+      thread: Some(thread),
+      status,
+    })
+  }
+
+  // the error which stopped streaming
+  pub fn poll_event(&self) -> Option<DeviceEvent> {
+    self.status.try_recv().ok()
+  }
+}
+
+// Receives a stream from NetSender and feeds it to the output device through a jitter buffer.
+// Audio is passed on in the format the peer streams, output device converts it
+pub struct NetReceiver {
+  pub local_addr: SocketAddr,
+  running: Arc<AtomicBool>,
+  stats: Arc<Mutex<JitterStats>>,
+  thread: Option<thread::JoinHandle<()>>,
+  status: mpsc::Receiver<DeviceEvent>,
+}
+
+impl Drop for NetReceiver {
+  fn drop(&mut self) {
+    self.running.store(false, Ordering::SeqCst);
+    if let Some(thread) = self.thread.take() {
+      if thread.join().is_err() {
+        println!("NetReceiver.drop: receiver thread panicked");
+      }
+    }
+  }
+}
+
+impl NetReceiver {
+  pub fn new(
+    bind: SocketAddr,
+    format: DeviceFormat,
+    config: JitterConfig,
+    output: output::OutputSender,
+  ) -> Result<NetReceiver, DeviceError> {
+    let socket = UdpSocket::bind(bind).map_err(|err| socket_error("bind", err))?;
+    // wake up regularly to feed the output and to notice stop request
+    let local_addr = socket
+      .set_read_timeout(Some(RECEIVE_TIMEOUT))
+      .and_then(|_| socket.local_addr())
+      .map_err(|err| socket_error("set up", err))?;
+    let running = Arc::new(AtomicBool::new(true));
+    let stats = Arc::new(Mutex::new(JitterStats::default()));
+    let (thread_running, thread_stats) = (running.clone(), stats.clone());
+    let (status_sender, status) = mpsc::channel();
+    let thread = thread::Builder::new()
+      .name("net receiver".into())
+      .spawn(move || {
+        let mut datagram = vec![0u8; 65536];
+        // kept so playing out does not allocate, a pull covers about one receive timeout
+        let mut data = Vec::new();
+        let pool = BufferPool::new(
+          PIPELINE_DEPTH + 2,
+          (format.frequency as f64 * RECEIVE_TIMEOUT.as_secs_f64() * 2.0) as usize * format.channels as usize,
+        );
+        // `format` is only a guess until the first packet arrives
+        let mut stream_format = format;
+        let mut jitter = JitterBuffer::new(stream_format, config);
+        let mut last_pull = Instant::now();
+        // frames output device consumed since the last pull, fractional part is carried over
+        let mut owed_frames = 0.0;
+        while thread_running.load(Ordering::SeqCst) {
+          match socket.recv(&mut datagram) {
+            Ok(length) => {
+              if let Some(packet) = NetPacket::parse(&datagram[..length]) {
+                // peer switched format, timeline of the old stream is of no use
+                if packet.format != stream_format {
+                  stream_format = packet.format;
+                  jitter = JitterBuffer::new(stream_format, config);
+                  owed_frames = 0.0;
+                }
+                jitter.push(packet, Instant::now());
+              }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => {
+              let _ = status_sender.send(DeviceEvent::Failed(socket_error("receive", err)));
+              break;
+            }
+          }
+
+          let now = Instant::now();
+          owed_frames += (now - last_pull).as_secs_f64() * stream_format.frequency as f64;
+          last_pull = now;
+          let frames = owed_frames as usize;
+          owed_frames -= frames as f64;
+          data.clear();
+          jitter.pull(frames, &mut data);
+          *thread_stats.lock().unwrap() = jitter.stats();
+          if data.is_empty() {
+            // buffering, playout clock starts over once there is enough audio
+            owed_frames = 0.0;
+            continue;
+          }
+          let buffer = AudioBuffer::from_pooled_bytes(stream_format, &data, &pool);
+          if output.send(output::Command::NewData(buffer)).is_err() {
+            break;
+          }
+        }
+      })
+      .map_err(spawn_error)?;
+    Ok(NetReceiver {
+      local_addr,
+      running,
+      stats,
+      thread: Some(thread),
+      status,
+    })
+  }
+
+  // the error which stopped receiving
+  pub fn poll_event(&self) -> Option<DeviceEvent> {
+    self.status.try_recv().ok()
+  }
+
+  pub fn stats(&self) -> JitterStats {
+    *self.stats.lock().unwrap()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::device::{
+      backend::{memory::MemoryBackend, CaptureBackend, PlaybackBackend},
+      convert::Resampling,
+      hotplug::HotPlug,
+      input::InputDevice,
+      output::OutputDevice,
+    },
+  };
+
+  #[test]
+  fn packet_survives_serialization() {
+    let packet = NetPacket {
+      format: DeviceFormat {
+        frequency: 48000,
+        channels: 2,
+        bits: 32,
+        encoding: Encoding::Float,
+      },
+      sequence: 7,
+      timestamp: 4410,
+      payload: vec![1, 2, 3, 4, 5, 6, 7, 8],
+    };
+    assert!(NetPacket::parse(&packet.to_bytes()) == Some(packet));
+    assert!(NetPacket::parse(b"not a packet at all!!").is_none());
+  }
+
+  #[test]
+  fn capture_is_played_by_peer() {
+    let format = DeviceFormat {
+      frequency: 8000,
+      channels: 1,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    };
+    let capture = MemoryBackend::new(format);
+    let playback = MemoryBackend::new(format);
+
+    let buffers = BufferConfig::default();
+    let output = OutputDevice::new(
+      &playback,
+      format,
+      buffers,
+      &playback.output_devices()[0],
+      HotPlug::Stop,
+      Resampling::Linear,
+    )
+    .unwrap();
+    let receiver = NetReceiver::new(
+      "127.0.0.1:0".parse().unwrap(),
+      format,
+      JitterConfig::default(),
+      output.sender.clone(),
+    )
+    .unwrap();
+    let sender = NetSender::new(receiver.local_addr).unwrap();
+    let input = InputDevice::new(
+      &capture,
+      format,
+      buffers,
+      &capture.input_devices()[0],
+      HotPlug::Stop,
+      sender.sender.clone(),
+    )
+    .unwrap();
+
+    // 100ms of audio is split into several datagrams
+    let samples: Vec<u8> = (0..1600).map(|i| (i % 251) as u8).collect();
+    capture.push_capture(&samples);
+    capture.advance(Duration::from_millis(100));
+    // jitter buffer conceals the end of the stream, so more than was sent may be played
+    let played = playback.wait_playback(samples.len(), Duration::from_secs(5));
+    assert_eq!(&played[..samples.len()], &samples[..]);
+    assert_eq!(receiver.stats().lost, 0);
+
+    drop(input);
+    drop(sender);
+    drop(receiver);
+    drop(output);
+  }
+
+  #[test]
+  fn malformed_formats_are_dropped_by_receiver() {
+    let format = DeviceFormat {
+      frequency: 8000,
+      channels: 1,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    };
+    let playback = MemoryBackend::new(format);
+    let output = OutputDevice::new(
+      &playback,
+      format,
+      BufferConfig::default(),
+      &playback.output_devices()[0],
+      HotPlug::Stop,
+      Resampling::Linear,
+    )
+    .unwrap();
+    let receiver = NetReceiver::new(
+      "127.0.0.1:0".parse().unwrap(),
+      format,
+      JitterConfig::default(),
+      output.sender.clone(),
+    )
+    .unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let datagram = |format: DeviceFormat, payload: &[u8]| {
+      let packet = NetPacket {
+        format,
+        sequence: 0,
+        timestamp: 0,
+        payload: payload.to_vec(),
+      };
+      packet.to_bytes()
+    };
+
+    let malformed = [
+      datagram(DeviceFormat { channels: 0, ..format }, &[0; 4]),
+      datagram(DeviceFormat { frequency: 0, ..format }, &[0; 4]),
+      datagram(DeviceFormat { bits: 4, ..format }, &[0; 4]),
+      datagram(
+        DeviceFormat {
+          bits: 8,
+          encoding: Encoding::Float,
+          ..format
+        },
+        &[0; 4],
+      ),
+      // half a frame
+      datagram(format, &[0; 3]),
+    ];
+    for bytes in malformed.iter() {
+      assert!(NetPacket::parse(bytes).is_none());
+      socket.send_to(bytes, receiver.local_addr).unwrap();
+    }
+    // receiver is still there and plays what comes next
+    let samples: Vec<u8> = (0..1600).map(|i| (i % 251) as u8).collect();
+    socket.send_to(&datagram(format, &samples), receiver.local_addr).unwrap();
+    let played = playback.wait_playback(samples.len(), Duration::from_secs(5));
+    assert_eq!(&played[..samples.len()], &samples[..]);
+
+    drop(receiver);
+    drop(output);
+  }
+
+  #[test]
+  fn peer_format_is_converted_for_output() {
+    let captured = DeviceFormat {
+      frequency: 8000,
+      channels: 1,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    };
+    let played = DeviceFormat {
+      bits: 32,
+      encoding: Encoding::Float,
+      ..captured
+    };
+    let capture = MemoryBackend::new(captured);
+    let playback = MemoryBackend::new(played);
+
+    let buffers = BufferConfig::default();
+    let output = OutputDevice::new(
+      &playback,
+      played,
+      buffers,
+      &playback.output_devices()[0],
+      HotPlug::Stop,
+      Resampling::Linear,
+    )
+    .unwrap();
+    let receiver = NetReceiver::new(
+      "127.0.0.1:0".parse().unwrap(),
+      played,
+      JitterConfig::default(),
+      output.sender.clone(),
+    )
+    .unwrap();
+    let sender = NetSender::new(receiver.local_addr).unwrap();
+    let input = InputDevice::new(
+      &capture,
+      captured,
+      buffers,
+      &capture.input_devices()[0],
+      HotPlug::Stop,
+      sender.sender.clone(),
+    )
+    .unwrap();
+
+    let samples: Vec<i16> = (0..800).map(|i| if i % 2 == 0 { 16384 } else { -16384 }).collect();
+    let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect();
+    capture.push_capture(&bytes);
+    capture.advance(Duration::from_millis(100));
+    let played = playback.wait_playback(samples.len() * 4, Duration::from_secs(5));
+    let played: Vec<f32> = played[..samples.len() * 4]
+      .chunks_exact(4)
+      .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+      .collect();
+    let expected: Vec<f32> = samples.iter().map(|&sample| sample as f32 / 32768.0).collect();
+    assert_eq!(played, expected);
+
+    drop(input);
+    drop(sender);
+    drop(receiver);
+    drop(output);
+  }
+}
@@ -1,191 +1,268 @@
 use {
-  crate::device::{common::*, info::*},
-  std::{
-    mem::{size_of, zeroed},
-    sync::mpsc,
-    thread,
+  crate::device::{
+    backend::{PlaybackBackend, PlaybackStream},
+    common::*,
+    convert::{Converter, Resampling},
+    error::{spawn_error, DeviceError},
+    hotplug::{self, DeviceEvent, HotPlug},
+    info::*,
+    meter::Meter,
+    pool::BufferPool,
   },
-  // thiserror::Error,
-  winapi::{
-    shared::{
-      basetsd::DWORD_PTR,
-      mmreg::{WAVEFORMATEX, WAVE_FORMAT_PCM},
+  std::{
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      mpsc, Arc,
     },
-    um::{mmeapi::*, mmsystem::*},
+    thread,
+    time::Duration,
   },
 };
 
 pub struct OutputDevice {
-  pub sender: mpsc::Sender<Command>,
+  pub sender: OutputSender,
   thread: Option<std::thread::JoinHandle<()>>,
+  status: mpsc::Receiver<DeviceEvent>,
+  meter: Arc<Meter>,
 }
 
 pub enum Command {
-  Init,
   Stop,
-  NewData(WaveBuffer),
+  // converted to the device format if it comes in another one
+  NewData(AudioBuffer<f32>),
+  // gain for everything played from now on
+  Volume(f32),
+}
+
+// Sending side of the queue in front of an output, knows how many buffers wait in it
+#[derive(Clone)]
+pub struct OutputSender {
+  sender: mpsc::SyncSender<Command>,
+  queued: Arc<AtomicUsize>,
+}
+
+pub struct OutputReceiver {
+  reciever: mpsc::Receiver<Command>,
+  queued: Arc<AtomicUsize>,
+}
+
+// bounded, producers wait for the consumer instead of piling up buffers
+pub fn queue() -> (OutputSender, OutputReceiver) {
+  let (sender, reciever) = mpsc::sync_channel(PIPELINE_DEPTH);
+  let queued = Arc::new(AtomicUsize::new(0));
+  let sender = OutputSender {
+    sender,
+    queued: queued.clone(),
+  };
+  (sender, OutputReceiver { reciever, queued })
+}
+
+impl OutputSender {
+  // blocks while the queue is full, a buffer waiting for room counts as queued
+  pub fn send(&self, command: Command) -> Result<(), mpsc::SendError<Command>> {
+    let is_data = matches!(command, Command::NewData(_));
+    if is_data {
+      self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+    let result = self.sender.send(command);
+    if is_data && result.is_err() {
+      self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+    result
+  }
+
+  // buffers sent and not taken by the consumer yet
+  pub fn queued(&self) -> usize {
+    self.queued.load(Ordering::Relaxed)
+  }
 }
 
-// #[derive(Error, Debug, Clone)]
-// pub enum OpenDeviceError {
-//   #[error("winapi mm error {code:?}")]
-//   MultimediaError { code: u32, description: &'static str },
-//   #[error("unknown error")]
-//   UnknownError,
-// }
+impl OutputReceiver {
+  pub fn recv(&self) -> Result<Command, mpsc::RecvError> {
+    self.taken(self.reciever.recv())
+  }
+
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<Command, mpsc::RecvTimeoutError> {
+    self.taken(self.reciever.recv_timeout(timeout))
+  }
+
+  fn taken<E>(&self, received: Result<Command, E>) -> Result<Command, E> {
+    if let Ok(Command::NewData(_)) = &received {
+      self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+    received
+  }
+}
 
 impl Drop for OutputDevice {
   fn drop(&mut self) {
-    println!("OutputDevice.drop is running");
-    if self.thread.is_some() {
-      println!("OutputDevice.drop: sending Stop signal");
-      self.sender.send(Command::Stop).unwrap();
-      println!("OutputDevice.drop: running join!");
-      self.thread.take().unwrap().join().unwrap();
-      println!("OutputDevice.drop: joining done");
+    if let Some(thread) = self.thread.take() {
+      // thread is already gone if it failed
+      let _ = self.sender.send(Command::Stop);
+      if thread.join().is_err() {
+        println!("OutputDevice.drop: device thread panicked");
+      }
     }
-    println!("OutputDevice.drop done");
   }
 }
 
+// converter is kept while the incoming format stays the same, resampler state spans buffers
+fn convert(
+  converter: &mut Option<Converter>,
+  buffer: AudioBuffer<f32>,
+  desired_format: &DeviceFormat,
+  resampling: Resampling,
+) -> AudioBuffer<f32> {
+  if buffer.format() == *desired_format {
+    return buffer;
+  }
+  match converter {
+    Some(converter) if converter.from() == buffer.format() => {}
+    _ => *converter = Converter::new(&buffer.format(), desired_format, resampling),
+  }
+  match converter {
+    Some(converter) => converter.convert(&buffer),
+    None => buffer,
+  }
+}
+
+// gain of 1.0 passes the buffer through untouched, other gains are applied to pooled samples
+fn apply_volume(buffer: AudioBuffer<f32>, volume: f32, pool: &BufferPool<f32>) -> AudioBuffer<f32> {
+  if volume == 1.0 {
+    return buffer;
+  }
+  let mut samples = pool.take();
+  samples.extend(buffer.samples().iter().map(|sample| sample * volume));
+  AudioBuffer::from_pool(buffer.format(), samples).with_timestamp(buffer.timestamp())
+}
+
 impl OutputDevice {
-  // TODO handle errors
-  pub fn new(desired_format: DeviceFormat, device_index: u32) -> OutputDevice {
-    let (sender, reciever) = mpsc::channel::<Command>();
+  // returns once the stream is started, later errors and removals show up in poll_event
+  pub fn new<B: PlaybackBackend>(
+    backend: &B,
+    desired_format: DeviceFormat,
+    buffers: BufferConfig,
+    device: &DeviceInfo,
+    hot_plug: HotPlug,
+    resampling: Resampling,
+  ) -> Result<OutputDevice, DeviceError> {
+    let (sender, reciever) = queue();
+    let (status_sender, status) = mpsc::channel();
+    let (started_sender, started) = mpsc::channel();
+    let backend = backend.clone();
+    let (device_index, mut device_id) = (device.index, device.id.clone());
+    let meter = Arc::new(Meter::default());
+    let thread_meter = meter.clone();
     let thread = thread::Builder::new()
       .name("output".into())
-      .spawn(move || unsafe {
-        let mut output_processor = OutputProcessor::new(desired_format, device_index);
+      .spawn(move || {
+        let open = |device_index: u32| {
+          let opened = backend.open_playback(desired_format, buffers, device_index);
+          opened.and_then(|mut stream| stream.start().map(|_| stream))
+        };
+        let mut stream = match open(device_index) {
+          Ok(stream) => stream,
+          Err(err) => {
+            let _ = started_sender.send(Err(err));
+            return;
+          }
+        };
+        let _ = started_sender.send(Ok(()));
+        let mut converter: Option<Converter> = None;
+        let mut volume = 1.0;
+        // every buffer is played before the next one is taken
+        let volume_pool = BufferPool::new(2, 0);
         loop {
           let msg = match reciever.recv() {
             Ok(msg) => msg,
             Err(err) => {
               println!("OutputDevice: recv error {}", err);
-              continue;
+              break;
             }
           };
           match msg {
-            Command::Init => output_processor.init(),
-            Command::NewData(buffer) => output_processor.new_data(&buffer),
+            Command::NewData(buffer) => {
+              let buffer = apply_volume(convert(&mut converter, buffer, &desired_format, resampling), volume, &volume_pool);
+              thread_meter.measure(&buffer);
+              match stream.write(&buffer) {
+                Ok(()) => {}
+                // buffer is lost, playback resumes with whatever comes after the re-open
+                Err(DeviceError::Removed) if hot_plug != HotPlug::Stop => {
+                  let _ = stream.stop();
+                  let _ = status_sender.send(DeviceEvent::Lost(DeviceError::Removed));
+                  let is_stop = |command: &Command| matches!(command, Command::Stop);
+                  let devices = || backend.output_devices_unprobed();
+                  match hotplug::reopen(
+                    |timeout| reciever.recv_timeout(timeout),
+                    is_stop,
+                    hot_plug,
+                    &device_id,
+                    devices,
+                    |device| open(device.index),
+                  ) {
+                    Some((reopened, id)) => {
+                      stream = reopened;
+                      device_id = id;
+                      let _ = status_sender.send(DeviceEvent::Reopened(device_id.clone()));
+                    }
+                    None => break,
+                  }
+                }
+                Err(err) => {
+                  let _ = stream.stop();
+                  let _ = status_sender.send(DeviceEvent::Failed(err));
+                  break;
+                }
+              }
+            }
+            Command::Volume(new_volume) => volume = new_volume,
             Command::Stop => {
-              output_processor.stop();
+              if let Err(err) = stream.stop() {
+                println!("OutputDevice: {}", err);
+              }
               break;
             }
           }
         }
       })
-      .unwrap();
-    sender.send(Command::Init).unwrap();
-    OutputDevice {
+      .map_err(spawn_error)?;
+    let device = OutputDevice {
       sender,
       thread: Some(thread),
+      status,
+      meter,
+    };
+    match started.recv() {
+      Ok(Ok(())) => Ok(device),
+      Ok(Err(err)) => Err(err),
+      Err(_) => Err(DeviceError::OpenFailed {
+        reason: "output thread died while opening the device".to_string(),
+      }),
     }
   }
-}
 
-struct OutputProcessor {
-  buffer: WaveBuffer,
-  header: WAVEHDR,
-  format: WAVEFORMATEX,
-  device_index: u32,
-  handle: HWAVEOUT,
-}
+  // buffers waiting to be played, at most PIPELINE_DEPTH and one more the producer is blocked with
+  pub fn queued(&self) -> usize {
+    self.sender.queued()
+  }
 
-impl OutputProcessor {
-  unsafe fn new(desired_format: DeviceFormat, device_index: u32) -> OutputProcessor {
-    let mut format = zeroed::<WAVEFORMATEX>();
-    format.wFormatTag = WAVE_FORMAT_PCM;
-    format.nChannels = desired_format.channels;
-    format.nSamplesPerSec = desired_format.frequency; // assumes that channels = 1
-    format.wBitsPerSample = desired_format.bits;
-    format.nBlockAlign = (format.wBitsPerSample / 8) * format.nChannels; // idk what is that
-    format.nAvgBytesPerSec = format.nSamplesPerSec * format.nBlockAlign as u32;
-    format.cbSize = 0;
-    let handle = zeroed::<HWAVEOUT>();
-    let header = zeroed::<WAVEHDR>();
-    let buffer = WaveBuffer::new(format.nAvgBytesPerSec as usize);
-    OutputProcessor {
-      buffer,
-      header,
-      format,
-      handle,
-      device_index,
-    }
+  pub fn set_volume(&self, volume: f32) {
+    // thread is already gone if it failed
+    let _ = self.sender.send(Command::Volume(volume));
   }
 
-  unsafe fn init(&mut self) {
-    // WAVE_FORMAT_DIRECT??? does not perform conversions on the audio data
-    let mmresult = waveOutOpen(&mut self.handle, self.device_index, &self.format, 0 as DWORD_PTR, 0 as DWORD_PTR, 0);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveOutOpen: {}", mm_error_to_string(mmresult));
-    };
-    // let mmresult = waveOutPrepareHeader(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-    // if mmresult != MMSYSERR_NOERROR {
-    //   panic!("waveOutPrepareHeader: {}", mm_error_to_string(mmresult));
-    // };
-    println!("OutputProcessor: initialized!");
-  }
-
-  unsafe fn new_data(&mut self, buffer: &WaveBuffer) {
-    // TODO wait device using events
-    println!("WARN: output header.dwFlags = {} not handled!", whdr_to_str(self.header.dwFlags));
-    while !(self.header.dwFlags == 0 || self.header.dwFlags & WHDR_DONE != 0) {}
-    buffer.copy_to(&mut self.buffer);
-    self.header.lpData = self.buffer.data;
-    self.header.dwBufferLength = self.buffer.length();
-    let mmresult = waveOutPrepareHeader(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveOutPrepareHeader: {}", mm_error_to_string(mmresult));
-    };
-    loop {
-      let mmresult = waveOutWrite(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-      if mmresult == WAVERR_STILLPLAYING {
-        println!("WAVERR_STILLPLAYING");
-        continue;
-      }
-      if mmresult != MMSYSERR_NOERROR {
-        panic!("waveOutWrite error {}", mm_error_to_string(mmresult));
-      };
-      break;
-    }
-    return;
-    // if self.header.dwFlags & WHDR_DONE != 0 {
-    //   println!("OutputProcessor: new data");
-    //   // see https://docs.rs/winapi/0.3.8/i686-pc-windows-msvc/winapi/um/mmsystem/struct.WAVEHDR.html
-    //   // header.lpData: *mut i8
-    //   // header.dwBufferLength: u32
-    //   //
-    //   // let mmresult = waveOutPrepareHeader(handle, &mut header, size_of::<WAVEHDR>() as u32);
-    //   // if mmresult != MMSYSERR_NOERROR {
-    //   //   panic!("waveOutPrepareHeader: {}", mm_error_to_string(mmresult));
-    //   // }
-    //   // let mmresult = waveOutAddBuffer(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-    //   // if mmresult != MMSYSERR_NOERROR {
-    //   //   panic!("waveOutAddBuffer error {}", mm_error_to_string(mmresult));
-    //   // };
-    //   return;
-    // }
-    //
-    // println!("WARN: output header.dwFlags = {} not handled!", whdr_to_str(self.header.dwFlags));
-  }
-
-  unsafe fn stop(&mut self) {
-    // TODO how to unprepare header if it is not presented here?
-    println!("waveOutReset");
-    let mmresult = waveOutReset(self.handle);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveOutReset: {}", mm_error_to_string(mmresult));
-    };
-    // println!("waveOutUnprepareHeader");
-    // let mmresult = waveOutUnprepareHeader(self.handle, &mut self.header, size_of::<WAVEHDR>() as u32);
-    // if mmresult != MMSYSERR_NOERROR {
-    //   panic!("waveOutUnprepareHeader: {}", mm_error_to_string(mmresult));
-    // };
-    println!("waveOutClose");
-    let mmresult = waveOutClose(self.handle);
-    if mmresult != MMSYSERR_NOERROR {
-      panic!("waveOutClose: {}", mm_error_to_string(mmresult));
-    };
-    println!("OutputProcessor: stop!");
+  // levels of what was played, after conversion and volume
+  pub fn meter(&self) -> &Meter {
+    &self.meter
+  }
+
+  // removals, re-opens and the error which stopped the device thread, in the order they happened
+  pub fn poll_event(&self) -> Option<DeviceEvent> {
+    self.status.try_recv().ok()
+  }
+
+  // like poll_event, but waits up to `timeout` for the next event
+  pub fn wait_event(&self, timeout: Duration) -> Option<DeviceEvent> {
+    self.status.recv_timeout(timeout).ok()
   }
 }
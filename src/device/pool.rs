@@ -0,0 +1,320 @@
+use {
+  crate::device::common::Sample,
+  std::{
+    cell::UnsafeCell,
+    mem::{ManuallyDrop, MaybeUninit},
+    ops::{Deref, DerefMut},
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc, Weak,
+    },
+  },
+};
+
+// Bounded MPMC queue (Vyukov). Every slot has a sequence number telling whether it is free for the
+// push at that position or full for the pop at that position, so neither side ever waits for the other
+struct Queue<T> {
+  slots: Box<[Slot<T>]>,
+  mask: usize,
+  // next position to push to / pop from
+  tail: AtomicUsize,
+  head: AtomicUsize,
+}
+
+struct Slot<T> {
+  sequence: AtomicUsize,
+  value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// values are moved in and out under the sequence protocol, never shared
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+  fn new(capacity: usize) -> Queue<T> {
+    let capacity = capacity.max(2).next_power_of_two();
+    Queue {
+      slots: (0..capacity)
+        .map(|position| Slot {
+          sequence: AtomicUsize::new(position),
+          value: UnsafeCell::new(MaybeUninit::uninit()),
+        })
+        .collect(),
+      mask: capacity - 1,
+      tail: AtomicUsize::new(0),
+      head: AtomicUsize::new(0),
+    }
+  }
+
+  // value comes back if the queue is full
+  fn push(&self, value: T) -> Result<(), T> {
+    let mut position = self.tail.load(Ordering::Relaxed);
+    loop {
+      let slot = &self.slots[position & self.mask];
+      let sequence = slot.sequence.load(Ordering::Acquire);
+      match sequence.wrapping_sub(position) as isize {
+        0 => match self
+          .tail
+          .compare_exchange_weak(position, position.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed)
+        {
+          Ok(_) => {
+            unsafe { (*slot.value.get()).as_mut_ptr().write(value) };
+            slot.sequence.store(position.wrapping_add(1), Ordering::Release);
+            return Ok(());
+          }
+          Err(current) => position = current,
+        },
+        // slot still holds the value pushed one lap ago
+        lag if lag < 0 => return Err(value),
+        _ => position = self.tail.load(Ordering::Relaxed),
+      }
+    }
+  }
+
+  fn pop(&self) -> Option<T> {
+    let mut position = self.head.load(Ordering::Relaxed);
+    loop {
+      let slot = &self.slots[position & self.mask];
+      let sequence = slot.sequence.load(Ordering::Acquire);
+      match sequence.wrapping_sub(position.wrapping_add(1)) as isize {
+        0 => match self
+          .head
+          .compare_exchange_weak(position, position.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed)
+        {
+          Ok(_) => {
+            let value = unsafe { (*slot.value.get()).as_ptr().read() };
+            slot.sequence.store(position.wrapping_add(self.mask + 1), Ordering::Release);
+            return Some(value);
+          }
+          Err(current) => position = current,
+        },
+        // nothing was pushed to this slot yet
+        lag if lag < 0 => return None,
+        _ => position = self.head.load(Ordering::Relaxed),
+      }
+    }
+  }
+}
+
+impl<T> Drop for Queue<T> {
+  fn drop(&mut self) {
+    while self.pop().is_some() {}
+  }
+}
+
+// Sample memory of buffers. Arc only keeps the memory alive, `users` counts the buffers reading it: the
+// one which drops it to zero is the only one left and hands the storage back, so two buffers dropped at
+// once on different threads can not both leave it to the other
+struct Storage<T: Sample> {
+  samples: UnsafeCell<Vec<T>>,
+  users: AtomicUsize,
+  // weak so storages parked in the pool do not keep it alive
+  pool: Weak<Queue<Arc<Storage<T>>>>,
+}
+
+// samples are written only by the single user of a storage taken from the pool
+unsafe impl<T: Sample> Send for Storage<T> {}
+unsafe impl<T: Sample> Sync for Storage<T> {}
+
+impl<T: Sample> Storage<T> {
+  fn new(samples: Vec<T>, pool: Weak<Queue<Arc<Storage<T>>>>) -> Arc<Storage<T>> {
+    Arc::new(Storage {
+      samples: UnsafeCell::new(samples),
+      users: AtomicUsize::new(1),
+      pool,
+    })
+  }
+}
+
+// Read only samples shared by buffers, cloning copies nothing. Pooled samples go back to their pool
+// when the last clone is dropped, storage of a full or gone pool is freed
+pub struct SharedSamples<T: Sample> {
+  storage: ManuallyDrop<Arc<Storage<T>>>,
+}
+
+impl<T: Sample> SharedSamples<T> {
+  pub fn unpooled(samples: Vec<T>) -> SharedSamples<T> {
+    SharedSamples {
+      storage: ManuallyDrop::new(Storage::new(samples, Weak::new())),
+    }
+  }
+
+  pub fn ptr_eq(a: &SharedSamples<T>, b: &SharedSamples<T>) -> bool {
+    Arc::ptr_eq(&a.storage, &b.storage)
+  }
+}
+
+impl<T: Sample> Deref for SharedSamples<T> {
+  type Target = [T];
+
+  fn deref(&self) -> &[T] {
+    unsafe { &*self.storage.samples.get() }
+  }
+}
+
+impl<T: Sample> Clone for SharedSamples<T> {
+  fn clone(&self) -> SharedSamples<T> {
+    self.storage.users.fetch_add(1, Ordering::Relaxed);
+    SharedSamples {
+      storage: ManuallyDrop::new(Arc::clone(&self.storage)),
+    }
+  }
+}
+
+impl<T: Sample> Drop for SharedSamples<T> {
+  fn drop(&mut self) {
+    let storage = unsafe { ManuallyDrop::take(&mut self.storage) };
+    // reads of the other users happen before the storage is handed out again
+    if storage.users.fetch_sub(1, Ordering::AcqRel) != 1 {
+      return;
+    }
+    if let Some(pool) = storage.pool.upgrade() {
+      let _ = pool.push(storage);
+    }
+  }
+}
+
+// Sample storages shared by whoever fills them and whoever drops the last buffer using them. Once
+// every storage made a round trip streaming does not allocate
+#[derive(Clone)]
+pub struct BufferPool<T: Sample> {
+  free: Arc<Queue<Arc<Storage<T>>>>,
+}
+
+impl<T: Sample> BufferPool<T> {
+  // `count` storages with room for `samples` each are made right away, more are allocated only if all
+  // of them are in flight
+  pub fn new(count: usize, samples: usize) -> BufferPool<T> {
+    let free = Arc::new(Queue::new(count));
+    for _ in 0..count {
+      let storage = Storage::new(Vec::with_capacity(samples), Arc::downgrade(&free));
+      let _ = free.push(storage);
+    }
+    BufferPool { free }
+  }
+
+  // empty samples to fill and pass to AudioBuffer::from_pool
+  pub fn take(&self) -> PooledSamples<T> {
+    let storage = match self.free.pop() {
+      Some(storage) => {
+        // nobody reads it anymore, users which left earlier may still be dropping their Arc
+        storage.users.store(1, Ordering::Relaxed);
+        unsafe { (*storage.samples.get()).clear() };
+        storage
+      }
+      None => Storage::new(Vec::new(), Arc::downgrade(&self.free)),
+    };
+    PooledSamples {
+      shared: SharedSamples {
+        storage: ManuallyDrop::new(storage),
+      },
+    }
+  }
+}
+
+// Samples taken from a pool and not yet turned into a buffer, the only user of its storage
+pub struct PooledSamples<T: Sample> {
+  shared: SharedSamples<T>,
+}
+
+impl<T: Sample> PooledSamples<T> {
+  pub fn into_shared(self) -> SharedSamples<T> {
+    self.shared
+  }
+}
+
+impl<T: Sample> Deref for PooledSamples<T> {
+  type Target = Vec<T>;
+
+  fn deref(&self) -> &Vec<T> {
+    unsafe { &*self.shared.storage.samples.get() }
+  }
+}
+
+impl<T: Sample> DerefMut for PooledSamples<T> {
+  fn deref_mut(&mut self) -> &mut Vec<T> {
+    unsafe { &mut *self.shared.storage.samples.get() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::device::{
+      common::AudioBuffer,
+      info::{DeviceFormat, Encoding},
+    },
+    std::{sync::Barrier, thread},
+  };
+
+  #[test]
+  fn queue_hands_out_every_value_once() {
+    let queue = Arc::new(Queue::new(8));
+    let pushers: Vec<_> = (0..4u64)
+      .map(|pusher| {
+        let queue = queue.clone();
+        thread::spawn(move || {
+          for value in pusher * 10000..(pusher + 1) * 10000 {
+            let mut value = value;
+            while let Err(rejected) = queue.push(value) {
+              value = rejected;
+              thread::yield_now();
+            }
+          }
+        })
+      })
+      .collect();
+    let poppers: Vec<_> = (0..4)
+      .map(|_| {
+        let queue = queue.clone();
+        thread::spawn(move || {
+          let mut popped = Vec::new();
+          while popped.len() < 10000 {
+            match queue.pop() {
+              Some(value) => popped.push(value),
+              None => thread::yield_now(),
+            }
+          }
+          popped
+        })
+      })
+      .collect();
+    pushers.into_iter().for_each(|pusher| pusher.join().unwrap());
+    let mut popped: Vec<u64> = poppers.into_iter().flat_map(|popper| popper.join().unwrap()).collect();
+    popped.sort_unstable();
+    assert_eq!(popped, (0..40000).collect::<Vec<u64>>());
+    assert!(queue.pop().is_none());
+  }
+
+  // storage made by the pool has room for 64 samples, one allocated because the pool was empty has none
+  #[test]
+  fn storage_dropped_on_two_threads_at_once_is_recycled() {
+    let format = DeviceFormat {
+      frequency: 8000,
+      channels: 1,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    };
+    let pool = BufferPool::<f32>::new(1, 64);
+    let barrier = Arc::new(Barrier::new(2));
+    for _ in 0..1000 {
+      let mut samples = pool.take();
+      assert_eq!(samples.capacity(), 64);
+      samples.resize(64, 0.5);
+      let buffer = AudioBuffer::from_pool(format, samples);
+      let (first, second) = (buffer.slice(0..32), buffer.slice(32..64));
+      drop(buffer);
+      let other = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+          barrier.wait();
+          drop(second);
+        })
+      };
+      barrier.wait();
+      drop(first);
+      other.join().unwrap();
+    }
+  }
+}
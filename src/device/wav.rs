@@ -0,0 +1,273 @@
+use {
+  crate::device::info::*,
+  std::io::{self, Read, Write},
+};
+
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+// fmt chunk of WAVE_FORMAT_EXTENSIBLE up to the first field of its SubFormat guid, which is the format tag
+const EXTENSIBLE_LENGTH: u32 = 28;
+// whole fmt chunks, without the chunk header
+const PLAIN_FMT_LENGTH: u32 = 16;
+const EXTENSIBLE_FMT_LENGTH: u32 = 40;
+// SubFormat guid after the format tag it starts with, the same for PCM and float
+const SUBFORMAT_TAIL: [u8; 12] = [0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+pub struct WavHeader {
+  pub format: DeviceFormat,
+  pub data_length: u32,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_tag<R: Read>(reader: &mut R) -> io::Result<[u8; 4]> {
+  let mut tag = [0u8; 4];
+  reader.read_exact(&mut tag)?;
+  Ok(tag)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+  let mut bytes = [0u8; 2];
+  reader.read_exact(&mut bytes)?;
+  Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+  let mut bytes = [0u8; 4];
+  reader.read_exact(&mut bytes)?;
+  Ok(u32::from_le_bytes(bytes))
+}
+
+fn skip<R: Read>(reader: &mut R, length: u64) -> io::Result<()> {
+  let skipped = io::copy(&mut reader.take(length), &mut io::sink())?;
+  if skipped != length {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of wav chunk"));
+  }
+  Ok(())
+}
+
+// reads RIFF header and all chunks up to "data", leaves reader at the first sample
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<WavHeader> {
+  if &read_tag(reader)? != b"RIFF" {
+    return Err(invalid_data("not a RIFF file"));
+  }
+  read_u32(reader)?; // riff size, not trusted: recorders often leave it zero
+  if &read_tag(reader)? != b"WAVE" {
+    return Err(invalid_data("not a WAVE file"));
+  }
+  let mut format: Option<DeviceFormat> = None;
+  loop {
+    let tag = read_tag(reader)?;
+    let chunk_length = read_u32(reader)?;
+    match &tag {
+      b"fmt " => {
+        if chunk_length < 16 {
+          return Err(invalid_data("fmt chunk is too short"));
+        }
+        let format_tag = read_u16(reader)?;
+        let channels = read_u16(reader)?;
+        let frequency = read_u32(reader)?;
+        read_u32(reader)?; // avg bytes per second
+        read_u16(reader)?; // block align
+        let bits = read_u16(reader)?;
+        let mut read_length = 16;
+        let format_tag = if format_tag == WAVE_FORMAT_EXTENSIBLE && chunk_length >= EXTENSIBLE_LENGTH {
+          read_u16(reader)?; // extension size
+          read_u16(reader)?; // valid bits per sample
+          read_u32(reader)?; // channel mask
+          read_length = EXTENSIBLE_LENGTH;
+          read_u32(reader)? as u16
+        } else {
+          format_tag
+        };
+        let encoding = match Encoding::from_tag(format_tag) {
+          Some(encoding) => encoding,
+          None => return Err(invalid_data("only PCM and float wav files are supported")),
+        };
+        format = Some(DeviceFormat {
+          frequency,
+          channels,
+          bits,
+          encoding,
+        });
+        skip(reader, (chunk_length - read_length) as u64 + (chunk_length & 1) as u64)?;
+      }
+      b"data" => {
+        return match format {
+          Some(format) => Ok(WavHeader {
+            format,
+            data_length: chunk_length,
+          }),
+          None => Err(invalid_data("data chunk found before fmt chunk")),
+        };
+      }
+      _ => skip(reader, chunk_length as u64 + (chunk_length & 1) as u64)?,
+    }
+  }
+}
+
+// plain PCM and float headers are ambiguous about sample containers and speakers beyond stereo 16 bit,
+// other tools guess wrong unless these formats are written as WAVE_FORMAT_EXTENSIBLE
+fn is_extensible(format: &DeviceFormat) -> bool {
+  format.bits > 16 || format.channels > 2 || format.encoding == Encoding::Float
+}
+
+// bytes written by write_header for `format`
+pub fn header_length(format: &DeviceFormat) -> u32 {
+  let fmt_length = if is_extensible(format) {
+    EXTENSIBLE_FMT_LENGTH
+  } else {
+    PLAIN_FMT_LENGTH
+  };
+  12 + 8 + fmt_length + 8
+}
+
+// canonical header, data chunk follows it immediately
+pub fn write_header<W: Write>(writer: &mut W, format: &DeviceFormat, data_length: u32) -> io::Result<()> {
+  let block_align = format.block_align();
+  let extensible = is_extensible(format);
+  writer.write_all(b"RIFF")?;
+  writer.write_all(&(header_length(format) - 8 + data_length).to_le_bytes())?;
+  writer.write_all(b"WAVE")?;
+  writer.write_all(b"fmt ")?;
+  if extensible {
+    writer.write_all(&EXTENSIBLE_FMT_LENGTH.to_le_bytes())?;
+    writer.write_all(&WAVE_FORMAT_EXTENSIBLE.to_le_bytes())?;
+  } else {
+    writer.write_all(&PLAIN_FMT_LENGTH.to_le_bytes())?;
+    writer.write_all(&format.encoding.tag().to_le_bytes())?;
+  }
+  writer.write_all(&format.channels.to_le_bytes())?;
+  writer.write_all(&format.frequency.to_le_bytes())?;
+  writer.write_all(&(format.frequency * block_align as u32).to_le_bytes())?;
+  writer.write_all(&block_align.to_le_bytes())?;
+  writer.write_all(&format.bits.to_le_bytes())?;
+  if extensible {
+    writer.write_all(&((EXTENSIBLE_FMT_LENGTH - PLAIN_FMT_LENGTH - 2) as u16).to_le_bytes())?; // extension size
+    writer.write_all(&format.bits.to_le_bytes())?; // valid bits per sample
+    writer.write_all(&format.channel_mask().to_le_bytes())?;
+    writer.write_all(&(format.encoding.tag() as u32).to_le_bytes())?;
+    writer.write_all(&SUBFORMAT_TAIL)?;
+  }
+  writer.write_all(b"data")?;
+  writer.write_all(&data_length.to_le_bytes())?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn push_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  #[test]
+  fn read_header_skips_unknown_chunks() {
+    let mut file = Vec::new();
+    file.extend_from_slice(b"RIFF");
+    push_u32(&mut file, 0);
+    file.extend_from_slice(b"WAVE");
+    file.extend_from_slice(b"LIST");
+    push_u32(&mut file, 3);
+    file.extend_from_slice(&[1, 2, 3, 0]);
+    file.extend_from_slice(b"fmt ");
+    push_u32(&mut file, 16);
+    push_u16(&mut file, Encoding::Pcm.tag());
+    push_u16(&mut file, 1);
+    push_u32(&mut file, 22050);
+    push_u32(&mut file, 44100);
+    push_u16(&mut file, 2);
+    push_u16(&mut file, 16);
+    file.extend_from_slice(b"data");
+    push_u32(&mut file, 4);
+    file.extend_from_slice(&[9, 8, 7, 6]);
+
+    let mut reader = &file[..];
+    let header = read_header(&mut reader).unwrap();
+    assert_eq!(header.format.frequency, 22050);
+    assert_eq!(header.format.channels, 1);
+    assert_eq!(header.format.bits, 16);
+    assert_eq!(header.data_length, 4);
+    assert_eq!(reader, &[9, 8, 7, 6]);
+  }
+
+  #[test]
+  fn written_header_is_readable() {
+    let format = DeviceFormat {
+      frequency: 44100,
+      channels: 1,
+      bits: 8,
+      encoding: Encoding::Pcm,
+    };
+    let float = DeviceFormat {
+      frequency: 48000,
+      channels: 2,
+      bits: 32,
+      encoding: Encoding::Float,
+    };
+    for format in &[format, float] {
+      let mut file = Vec::new();
+      write_header(&mut file, format, 1000).unwrap();
+      assert_eq!(file.len(), header_length(format) as usize);
+      assert_eq!(&file[4..8], &(header_length(format) - 8 + 1000).to_le_bytes());
+
+      let header = read_header(&mut &file[..]).unwrap();
+      assert!(&header.format == format);
+      assert_eq!(header.data_length, 1000);
+    }
+  }
+
+  #[test]
+  fn deep_float_and_surround_formats_are_written_extensible() {
+    let stereo = DeviceFormat {
+      frequency: 44100,
+      channels: 2,
+      bits: 16,
+      encoding: Encoding::Pcm,
+    };
+    let surround = DeviceFormat {
+      frequency: 48000,
+      channels: 6,
+      bits: 24,
+      encoding: Encoding::Pcm,
+    };
+    let float = DeviceFormat {
+      frequency: 96000,
+      channels: 2,
+      bits: 32,
+      encoding: Encoding::Float,
+    };
+    let mut file = Vec::new();
+    write_header(&mut file, &stereo, 0).unwrap();
+    assert_eq!(file.len(), 44);
+    assert_eq!(&file[20..22], &Encoding::Pcm.tag().to_le_bytes());
+
+    for format in &[surround, float] {
+      let mut file = Vec::new();
+      write_header(&mut file, format, 6).unwrap();
+      file.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+      assert_eq!(file.len(), 68 + 6);
+      assert_eq!(&file[20..22], &WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+      assert_eq!(&file[40..44], &format.channel_mask().to_le_bytes());
+      assert_eq!(&file[44..48], &(format.encoding.tag() as u32).to_le_bytes());
+
+      let mut reader = &file[..];
+      let header = read_header(&mut reader).unwrap();
+      assert!(&header.format == format);
+      assert_eq!(header.data_length, 6);
+      assert_eq!(reader, &[1, 2, 3, 4, 5, 6]);
+    }
+  }
+
+  #[test]
+  fn read_header_rejects_non_riff() {
+    let mut reader = &b"OggS\0\0\0\0"[..];
+    assert!(read_header(&mut reader).is_err());
+  }
+}
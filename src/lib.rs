@@ -0,0 +1,6 @@
+// Devices, conversion and streaming. A library of its own so integration tests can link it without
+// the shell around it
+pub mod device;
+
+#[cfg(test)]
+mod test_util;
@@ -1,284 +1,902 @@
 #[macro_use(lazy_static)]
 extern crate lazy_static;
 
-mod device;
+mod cli;
+mod config;
+#[cfg(test)]
+mod test_util;
+mod tui;
 mod ui;
 mod vorbis;
 
-use device::{info::*, input::*, output::*};
-use portaudio as pa;
+use divana::device;
 use vorbis::ogg;
+use {
+  config::{Config, SavedSelection},
+  device::{
+    backend::{portaudio::PortAudioBackend, *},
+    convert::Resampling,
+    hotplug::*,
+    info::*,
+    input::*,
+    jitter::*,
+    net::*,
+    output::*,
+  },
+  std::{
+    fs,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+  },
+};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 enum Command {
-  MainMenu,
+  Help,
+  Devices,
   SetupInput,
+  SetupOutput,
+  Format,
+  Volume,
   Exit,
   Start,
-  SetupOutput,
   Stop,
+  Send,
+  Listen,
+  NetStats,
+  HotPlug,
+  Resampling,
+  Wait,
 }
 
-type CommandDefinition = (&'static str, Command);
+struct CommandDefinition {
+  name: &'static str,
+  command: Command,
+  // [optional] and <required> arguments, also how many of them are accepted
+  arguments: &'static str,
+  description: &'static str,
+}
 
 struct DeviceSelection {
   device: DeviceInfo,
   format: DeviceFormat,
+  buffers: BufferConfig,
 }
 struct GlobalState {
   input_selection: Option<DeviceSelection>,
   output_selection: Option<DeviceSelection>,
   input: Option<InputDevice>,
   output: Option<OutputDevice>,
+  net_sender: Option<NetSender>,
+  net_receiver: Option<NetReceiver>,
+  hot_plug: HotPlug,
+  resampling: Resampling,
+  jitter: JitterConfig,
+  volume: f32,
+  // selections and settings are saved here whenever they change, None if there is no config dir
+  config_path: Option<PathBuf>,
+  // commands may ask the user for what they were not given, scripts have to give everything
+  interactive: bool,
 }
 
 lazy_static! {
-  static ref COMMAND_MAP: [CommandDefinition; 5] = [
-    ("input", Command::SetupInput),
-    ("output", Command::SetupOutput),
-    ("exit", Command::Exit),
-    ("start", Command::Start),
-    ("stop", Command::Stop),
+  static ref COMMAND_MAP: [CommandDefinition; 15] = [
+    CommandDefinition {
+      name: "help",
+      command: Command::Help,
+      arguments: "[command]",
+      description: "lists commands or tells what one of them does",
+    },
+    CommandDefinition {
+      name: "devices",
+      command: Command::Devices,
+      arguments: "",
+      description: "lists input and output devices",
+    },
+    CommandDefinition {
+      name: "input",
+      command: Command::SetupInput,
+      arguments: "[device] [buffers]",
+      description: "selects the capture device by index, id or part of its name, e.g. input 2 4x20. \
+                    Asks for both when no device is given",
+    },
+    CommandDefinition {
+      name: "output",
+      command: Command::SetupOutput,
+      arguments: "[device] [buffers]",
+      description: "selects the playback device the same way as input does",
+    },
+    CommandDefinition {
+      name: "format",
+      command: Command::Format,
+      arguments: "[input|output] <rate> [channels] [bits]",
+      description: "sets the format of both selected devices or one of them, e.g. format 48000 stereo 16. \
+                    Channels are mono, stereo, 5.1, 7.1 or a count, bits are 8, 16, 24, 32 or float",
+    },
+    CommandDefinition {
+      name: "volume",
+      command: Command::Volume,
+      arguments: "[gain]",
+      description: "playback gain, 1 plays the audio as it is and 0.5 at half amplitude. Applies right away",
+    },
+    CommandDefinition {
+      name: "exit",
+      command: Command::Exit,
+      arguments: "",
+      description: "stops everything and leaves",
+    },
+    CommandDefinition {
+      name: "start",
+      command: Command::Start,
+      arguments: "",
+      description: "plays the input device on the output device",
+    },
+    CommandDefinition {
+      name: "stop",
+      command: Command::Stop,
+      arguments: "",
+      description: "stops everything which is running",
+    },
+    CommandDefinition {
+      name: "send",
+      command: Command::Send,
+      arguments: "[host:port]",
+      description: "streams the input device to a peer which listens",
+    },
+    CommandDefinition {
+      name: "listen",
+      command: Command::Listen,
+      arguments: "[address]",
+      description: "plays a stream sent by a peer on the output device",
+    },
+    CommandDefinition {
+      name: "jitter",
+      command: Command::NetStats,
+      arguments: "",
+      description: "shows jitter buffer statistics of the running listen",
+    },
+    CommandDefinition {
+      name: "hotplug",
+      command: Command::HotPlug,
+      arguments: "[stop|same-device|fall-back-to-default]",
+      description: "what a device does once it is removed",
+    },
+    CommandDefinition {
+      name: "resampling",
+      command: Command::Resampling,
+      arguments: "[linear|sinc]",
+      description: "how the sample rate is converted when input and output rates differ",
+    },
+    CommandDefinition {
+      name: "wait",
+      command: Command::Wait,
+      arguments: "<time>",
+      description: "lets running devices play for a while or until they stop, e.g. wait 10s. Meant for scripts",
+    },
   ];
 }
 
-fn show_help() {
-  println!("available commands:");
-  for (command_name, _) in COMMAND_MAP.iter() {
-    println!("  {}", command_name);
+fn show_help(out: &mut dyn FnMut(String)) {
+  out("available commands:".to_string());
+  for definition in COMMAND_MAP.iter() {
+    out(format!("  {:<48} {}", usage(definition), definition.description));
   }
 }
 
-fn something_is_wrong() {
-  println!("\\_(@u@)_/");
-  // use winapi::um::winuser::{MessageBeep, MB_ICONERROR};
-  // unsafe { MessageBeep(MB_ICONERROR) };
+fn usage(definition: &CommandDefinition) -> String {
+  format!("{} {}", definition.name, definition.arguments).trim_end().to_string()
 }
 
-fn pa_test() -> std::result::Result<(), pa::Error> {
-  const SAMPLE_RATE: f64 = 44_100.0;
-  const FRAMES: u32 = 256;
-  const CHANNELS: i32 = 2;
-  const INTERLEAVED: bool = true;
+// exact name or the only command starting with it
+fn find_command(name: &str) -> Result<&'static CommandDefinition, String> {
+  if let Some(definition) = COMMAND_MAP.iter().find(|definition| definition.name == name) {
+    return Ok(definition);
+  }
+  let matched_commands: Vec<&CommandDefinition> = COMMAND_MAP.iter().filter(|definition| definition.name.starts_with(name)).collect();
+  match matched_commands.len() {
+    1 => Ok(matched_commands[0]),
+    0 => Err(format!("there is no commands matched to {} (see \"help\")", name)),
+    _ => {
+      let names: Vec<&str> = matched_commands.iter().map(|definition| definition.name).collect();
+      Err(format!(
+        "{} matches multiple commands: {}, please, be more explicit",
+        name,
+        names.join(", ")
+      ))
+    }
+  }
+}
 
-  let pa = pa::PortAudio::new()?;
-  let default_host = pa.default_host_api()?;
-  println!("default host: {:#?}", pa.host_api_info(default_host));
-  let def_input = pa.default_input_device()?;
-  let input_info = pa.device_info(def_input)?;
-  println!("Default input device info: {:#?}", &input_info);
-  let latency = input_info.default_low_input_latency;
-  let input_params = pa::StreamParameters::<f32>::new(def_input, CHANNELS, INTERLEAVED, latency);
-  let def_output = pa.default_output_device()?;
-  let output_info = pa.device_info(def_output)?;
-  println!("Default output device info: {:#?}", &output_info);
-  let latency = output_info.default_low_output_latency;
-  let output_params = pa::StreamParameters::new(def_output, CHANNELS, INTERLEAVED, latency);
-  pa.is_duplex_format_supported(input_params, output_params, SAMPLE_RATE)?;
-  let settings = pa::DuplexStreamSettings::new(input_params, output_params, SAMPLE_RATE, FRAMES);
+// command output of the shell, the full screen view collects it instead
+fn print(line: String) {
+  println!("{}", line);
+}
 
-  let mut count_down = 20.0;
-  let mut maybe_last_time = None;
-  let (sender, receiver) = ::std::sync::mpsc::channel();
-  // A callback to pass to the non-blocking stream.
-  let callback = move |pa::DuplexStreamCallbackArgs {
-                         in_buffer,
-                         out_buffer,
-                         frames,
-                         time,
-                         ..
-                       }| {
-    let current_time = time.current;
-    let prev_time = maybe_last_time.unwrap_or(current_time);
-    let dt = current_time - prev_time;
-    count_down -= dt;
-    maybe_last_time = Some(current_time);
+fn something_is_wrong(out: &mut dyn FnMut(String)) {
+  out("\\_(@u@)_/".to_string());
+  // use winapi::um::winuser::{MessageBeep, MB_ICONERROR};
+  // unsafe { MessageBeep(MB_ICONERROR) };
+}
 
-    assert!(frames == FRAMES as usize);
-    sender.send(count_down).ok();
+fn resolve(address: &str) -> Result<SocketAddr, String> {
+  match address.to_socket_addrs() {
+    Ok(mut addresses) => addresses.next().ok_or(format!("{} has no address", address)),
+    Err(err) => Err(format!("cannot resolve {}: {}", address, err)),
+  }
+}
 
-    // println!("{:#?}", out_buffer);
+// None if user input is not an address, empty input selects default
+fn ask_address(question: &str, default: Option<String>) -> Option<SocketAddr> {
+  match &default {
+    Some(default) => println!("{} [{}]", question, default),
+    None => println!("{}", question),
+  }
+  let address = match (ui::process_user_input(), default) {
+    (Some(address), Some(default)) if address.is_empty() => default,
+    (Some(address), _) => address,
+    (None, _) => return None,
+  };
+  match resolve(&address) {
+    Ok(address) => Some(address),
+    Err(err) => {
+      println!("{}", err);
+      None
+    }
+  }
+}
 
-    for (output_sample, input_sample) in out_buffer.iter_mut().zip(in_buffer.iter()) {
-      *output_sample = *input_sample;
+fn ask_buffers() -> BufferConfig {
+  let default = BufferConfig::default();
+  loop {
+    println!("buffers (count x period in ms) [{}]", default);
+    let buffers = match ui::process_user_input() {
+      Some(buffers) => buffers,
+      None => return default,
+    };
+    if buffers.is_empty() {
+      return default;
+    }
+    match BufferConfig::parse(&buffers) {
+      Some(buffers) => return buffers,
+      None => println!("write it like 4x20"),
     }
+  }
+}
 
-    if count_down > 0.0 {
-      pa::Continue
-    } else {
-      pa::Complete
+// device and buffers from the arguments, asked for when there are none. The current device is what
+// an empty answer keeps
+fn select_device(
+  devices: Vec<DeviceInfo>,
+  current: Option<&DeviceSelection>,
+  arguments: &[String],
+  interactive: bool,
+  out: &mut dyn FnMut(String),
+) -> Result<DeviceSelection, String> {
+  let device = match arguments.first() {
+    Some(query) => DeviceInfo::select(&devices, query)
+      .cloned()
+      .ok_or(format!("no single device matches {} (see \"devices\")", query))?,
+    None if devices.is_empty() => {
+      return Err("no device is currently available (may be there is no devices in your computer?)".to_string())
     }
+    None if interactive => {
+      let current = current.and_then(|current| devices.iter().position(|device| device.id == current.device.id));
+      ui::process_select_one_of(&devices, current).ok_or("no device selected, nothing changed")?
+    }
+    None => return Err("which device? (see \"devices\")".to_string()),
+  };
+  let format = device.get_best_format().ok_or(format!("{} supports no format", device))?;
+  out(format!("selected device: {}", device));
+  out(format!("current format: {}", format));
+  let buffers = match arguments.get(1) {
+    Some(buffers) => BufferConfig::parse(buffers).ok_or(format!("write buffers like 4x20, not {}", buffers))?,
+    None if interactive && arguments.is_empty() => ask_buffers(),
+    None => BufferConfig::default(),
   };
+  out(format!("current buffers: {}", buffers));
+  Ok(DeviceSelection { device, format, buffers })
+}
+
+// new format for a selection, parts not given are kept
+fn change_format(selection: &DeviceSelection, arguments: &[String]) -> Result<DeviceFormat, String> {
+  let rate = &arguments[0];
+  let frequency = rate.trim_end_matches("hz").parse::<u32>().ok().filter(|&frequency| frequency > 0);
+  let channels = arguments
+    .get(1)
+    .map(|channels| DeviceFormat::parse_channels(channels).ok_or(channels));
+  let sample = arguments.get(2).map(|sample| DeviceFormat::parse_sample(sample).ok_or(sample));
+  let (bits, encoding) = match sample.transpose() {
+    Ok(sample) => sample.unwrap_or((selection.format.bits, selection.format.encoding)),
+    Err(sample) => return Err(format!("bits are 8, 16, 24, 32 or float, not {}", sample)),
+  };
+  let format = DeviceFormat {
+    frequency: frequency.ok_or(format!("{} is not a sample rate", rate))?,
+    channels: channels
+      .transpose()
+      .map_err(|channels| format!("{} is not a channel layout", channels))?
+      .unwrap_or(selection.format.channels),
+    bits,
+    encoding,
+  };
+  if !selection.device.supports(&format) {
+    return Err(format!("{} does not support {}", selection.device, format));
+  }
+  Ok(format)
+}
 
-  let mut stream = pa.open_non_blocking_stream(settings, callback)?;
-  stream.start()?;
+fn stop_devices(state: &mut GlobalState) {
+  // producers go first so nothing is sent to already stopped consumers
+  state.input = None;
+  state.net_sender = None;
+  state.net_receiver = None;
+  state.output = None;
+}
 
-  // Loop while the non-blocking stream is active.
-  while let true = stream.is_active()? {
-    // Do some stuff!
-    while let Ok(count_down) = receiver.try_recv() {
-      // println!("count_down: {:?}", count_down);
-    }
+// what happened to running devices since the last look. A failed device thread has stopped, everything
+// is stopped then so the user can start again. True if that happened. Ended input stops everything too,
+// but that is not a failure
+fn device_events(state: &mut GlobalState) -> (Vec<String>, bool) {
+  let mut events = Vec::new();
+  if let Some(input) = &state.input {
+    events.extend(std::iter::from_fn(|| input.poll_event()).map(|event| ("input", event)));
   }
+  if let Some(output) = &state.output {
+    events.extend(std::iter::from_fn(|| output.poll_event()).map(|event| ("output", event)));
+  }
+  if let Some(sender) = &state.net_sender {
+    events.extend(std::iter::from_fn(|| sender.poll_event()).map(|event| ("network send", event)));
+  }
+  if let Some(receiver) = &state.net_receiver {
+    events.extend(std::iter::from_fn(|| receiver.poll_event()).map(|event| ("network receive", event)));
+  }
+  let (mut failed, mut ended) = (false, false);
+  let mut messages = Vec::new();
+  for (direction, event) in events {
+    messages.push(match event {
+      DeviceEvent::Failed(err) => {
+        failed = true;
+        format!("{} device failed: {}", direction, err)
+      }
+      DeviceEvent::Lost(err) => format!("{} device lost: {} ({})", direction, err, state.hot_plug),
+      DeviceEvent::Reopened(id) => format!("{} device is back: {}", direction, id),
+      DeviceEvent::Ended => {
+        ended = true;
+        format!("{} device has ended, stopping", direction)
+      }
+    });
+  }
+  if failed || ended {
+    stop_devices(state);
+  }
+  (messages, failed)
+}
 
-  stream.stop()?;
-  Ok(())
+// reports device events since the last command
+fn check_devices(state: &mut GlobalState, out: &mut dyn FnMut(String)) -> bool {
+  let (messages, failed) = device_events(state);
+  for message in messages {
+    out(message);
+  }
+  if failed {
+    something_is_wrong(out);
+  }
+  failed
 }
 
 fn main() {
-  match pa_test() {
-    Ok(_) => {}
-    e => {
-      eprintln!("Example failed with the following: {:?}", e);
+  let cli = match cli::Cli::parse(std::env::args().skip(1)) {
+    Ok(cli) => cli,
+    Err(err) => {
+      eprintln!("{}\n{}", err, cli::USAGE);
+      process::exit(2);
+    }
+  };
+  let result = match &cli.host_api {
+    Some(host_api)
+      if !PortAudioBackend::host_api_names()
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(host_api)) =>
+    {
+      Err(format!("there is no host api {} (see \"host-apis\")", host_api))
+    }
+    Some(host_api) => run(cli.command, PortAudioBackend::with_host_api(host_api)),
+    None => run(cli.command, DefaultBackend::default()),
+  };
+  if let Err(err) = result {
+    eprintln!("{}", err);
+    process::exit(1);
+  }
+}
+
+fn run<B: AudioBackend>(command: cli::Command, backend: B) -> Result<(), String> {
+  let config_path = config::default_path();
+  // full screen has no place for them until it runs, everything else prints them right away
+  let mut startup_messages = Vec::new();
+  let mut out = |message: String| match command {
+    cli::Command::Tui => startup_messages.push(message),
+    _ => print(message),
+  };
+  let config = config_path.as_ref().map(|path| Config::load(path, &mut out)).unwrap_or_default();
+  match command {
+    cli::Command::Shell => return run_shell(backend, config, config_path, None),
+    cli::Command::Tui => {
+      let state = new_state(&backend, config, config_path, false, &mut out);
+      return tui::run(&backend, state, startup_messages);
     }
+    cli::Command::Script(script) => return run_shell(backend, config, config_path, Some(&script)),
+    cli::Command::Help => println!("{}", cli::USAGE),
+    cli::Command::Devices => cli::list_devices(&backend, &mut print),
+    cli::Command::HostApis => {
+      for name in PortAudioBackend::host_api_names() {
+        println!("{}", name);
+      }
+    }
+    cli::Command::Loopback(options) => return cli::loopback(&backend, &options, config.hot_plug, config.resampling),
   }
-  println!("exiting!");
-  return;
-  let mut state = GlobalState {
-    input_selection: None,
-    output_selection: None,
+  Ok(())
+}
+
+// saved selection if there is one, first device otherwise
+fn restore_selection(saved: &Option<SavedSelection>, devices: Vec<DeviceInfo>, out: &mut dyn FnMut(String)) -> Option<DeviceSelection> {
+  match saved {
+    Some(saved) => saved.restore(&devices, out).map(|(device, format)| DeviceSelection {
+      device,
+      format,
+      buffers: saved.buffers,
+    }),
+    None => devices.first().and_then(|device| {
+      Some(DeviceSelection {
+        device: device.clone(),
+        format: device.get_best_format()?,
+        buffers: BufferConfig::default(),
+      })
+    }),
+  }
+}
+
+fn save_config(state: &GlobalState, out: &mut dyn FnMut(String)) {
+  let path = match &state.config_path {
+    Some(path) => path,
+    None => return,
+  };
+  let saved = |selection: &Option<DeviceSelection>| {
+    selection.as_ref().map(|selection| SavedSelection {
+      device: selection.device.id.clone(),
+      format: selection.format,
+      buffers: selection.buffers,
+    })
+  };
+  let config = Config {
+    input: saved(&state.input_selection),
+    output: saved(&state.output_selection),
+    hot_plug: state.hot_plug,
+    resampling: state.resampling,
+    jitter: state.jitter,
+    volume: state.volume,
+  };
+  if let Err(err) = config.save(path) {
+    out(format!("WARN: cannot save settings to {}: {}", path.display(), err));
+  }
+}
+
+// selections restored from the config, nothing running yet. What restoring has to say goes to `out`
+fn new_state<B: AudioBackend>(
+  backend: &B,
+  config: Config,
+  config_path: Option<PathBuf>,
+  interactive: bool,
+  out: &mut dyn FnMut(String),
+) -> GlobalState {
+  let state = GlobalState {
+    input_selection: restore_selection(&config.input, backend.input_devices(), out),
+    output_selection: restore_selection(&config.output, backend.output_devices(), out),
     input: None,
     output: None,
+    net_sender: None,
+    net_receiver: None,
+    hot_plug: config.hot_plug,
+    resampling: config.resampling,
+    jitter: config.jitter,
+    volume: config.volume,
+    config_path,
+    interactive,
   };
-  //-------------------------------------------------------------------- DEBUG STUFF
-  let (input_devices, output_devices) = (DeviceInfo::input_devices(), DeviceInfo::output_devices());
-  let (input_device, output_device) = (input_devices.first().unwrap().clone(), output_devices.first().unwrap().clone());
-  state.input_selection = Some(DeviceSelection {
-    device: input_device.clone(),
-    format: input_device.get_best_format(),
-  });
-  state.output_selection = Some(DeviceSelection {
-    device: output_device.clone(),
-    format: output_device.get_best_format(),
-  });
-  //-------------------------------------------------------------------- DEBUG STUFF
-  let mut current_command = Command::MainMenu;
+  for (direction, selection) in [("input", &state.input_selection), ("output", &state.output_selection)].iter() {
+    if let Some(selection) = selection {
+      out(format!(
+        "{}: {} {} {}",
+        direction, selection.device, selection.format, selection.buffers
+      ));
+    }
+  }
+  state
+}
+
+// runs one command line, false once it asks to leave. What it has to say goes to `out`
+fn run_command<B: AudioBackend>(backend: &B, state: &mut GlobalState, line: &str, out: &mut dyn FnMut(String)) -> Result<bool, String> {
+  let arguments = ui::split_arguments(line)?;
+  let (name, arguments) = match arguments.split_first() {
+    Some(split) => split,
+    None => return Ok(true),
+  };
+  let definition = find_command(name)?;
+  if arguments.len() > definition.arguments.split_whitespace().count() {
+    return Err(format!("usage: {}", usage(definition)));
+  }
+  if definition.command == Command::Exit {
+    return Ok(false);
+  }
+  execute(backend, state, definition, arguments, out).map(|_| true)
+}
+
+// Reads commands from the user, or from `script` until its end. A failing script command stops
+// everything and is returned with its line number
+fn run_shell<B: AudioBackend>(backend: B, config: Config, config_path: Option<PathBuf>, script: Option<&Path>) -> Result<(), String> {
+  let script = match script {
+    Some(path) => {
+      let text = fs::read_to_string(path).map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+      Some((path, text))
+    }
+    None => None,
+  };
+  let mut script_lines = script.as_ref().map(|(_, text)| text.lines().enumerate());
+  let mut state = new_state(&backend, config, config_path, script.is_none(), &mut print);
   loop {
-    match current_command {
-      Command::MainMenu => {
-        let cmd_name = match ui::process_user_input() {
-          Some(command_name) => command_name,
-          None => {
-            something_is_wrong();
-            continue;
-          }
-        };
-        let matched_commands: Vec<CommandDefinition> = COMMAND_MAP
-          .iter()
-          .filter(|(k, _)| k.starts_with(cmd_name.as_str()))
-          .cloned()
-          .collect();
-        match matched_commands.len() {
-          1 => current_command = matched_commands[0].1.clone(),
-          0 => {
-            println!("there is no commands matched to your query");
-            show_help();
-            something_is_wrong();
-          }
-          _ => {
-            println!("there is multiple commands matched to your query:");
-            for (key, _) in matched_commands.iter() {
-              println!("  {}", key);
-            }
-            println!("please, be more explicit");
-            something_is_wrong();
-          }
-        };
-      }
-      Command::Exit => {
-        state.input_selection = None;
-        state.output_selection = None;
-        state.input = None;
-        return;
+    let failed = check_devices(&mut state, &mut print);
+    let (line_number, line) = match &mut script_lines {
+      Some(_) if failed => {
+        let (path, _) = script.as_ref().unwrap();
+        return Err(format!("{}: stopped because a device failed", path.display()));
       }
-      Command::SetupInput => {
-        current_command = Command::MainMenu;
-        if state.input.is_some() {
-          something_is_wrong();
-          println!("cannot setup device because some device already used. need to stop it first");
-          continue;
+      Some(lines) => match lines.next() {
+        Some((index, line)) => {
+          println!("> {}", line);
+          (index + 1, line.to_string())
         }
-        let device = match ui::process_select_one_of(DeviceInfo::input_devices()) {
-          Some(device) => device,
-          None => {
-            something_is_wrong();
-            println!("no device is currently available (may be there is no devices in your computer?)");
-            continue;
-          }
-        };
-        let format = device.get_best_format();
-        println!("selected device: {}", device);
-        println!("current format: {}", format);
-        state.input_selection = Some(DeviceSelection { device, format });
+        None => break,
+      },
+      // end of input leaves like exit does
+      None => match ui::process_user_input() {
+        Some(line) => (0, line),
+        None => break,
+      },
+    };
+    match (run_command(&backend, &mut state, &line, &mut print), &script) {
+      (Ok(true), _) => {}
+      (Ok(false), _) => break,
+      (Err(err), None) => {
+        something_is_wrong(&mut print);
+        println!("{}", err);
       }
-      Command::SetupOutput => {
-        current_command = Command::MainMenu;
-        // if state.input.is_some() {
-        //   something_is_wrong();
-        //   println!("cannot setup device because some device already used. need to stop it first");
-        //   continue;
-        // }
-        let device = match ui::process_select_one_of(DeviceInfo::output_devices()) {
-          Some(device) => device,
+      (Err(err), Some((path, _))) => {
+        stop_devices(&mut state);
+        return Err(format!("{}:{}: {}", path.display(), line_number, err));
+      }
+    }
+  }
+  stop_devices(&mut state);
+  Ok(())
+}
+
+fn execute<B: AudioBackend>(
+  backend: &B,
+  state: &mut GlobalState,
+  definition: &CommandDefinition,
+  arguments: &[String],
+  out: &mut dyn FnMut(String),
+) -> Result<(), String> {
+  match definition.command {
+    Command::Help => match arguments.first() {
+      Some(name) => {
+        let definition = find_command(name)?;
+        out(format!("usage: {}", usage(definition)));
+        out(definition.description.to_string());
+      }
+      None => show_help(out),
+    },
+    Command::Devices => cli::list_devices(backend, out),
+    Command::SetupInput => {
+      if state.input.is_some() {
+        return Err("cannot setup device because some device already used. need to stop it first".to_string());
+      }
+      state.input_selection = Some(select_device(
+        backend.input_devices(),
+        state.input_selection.as_ref(),
+        arguments,
+        state.interactive,
+        out,
+      )?);
+      save_config(state, out);
+    }
+    Command::SetupOutput => {
+      // if state.input.is_some() {
+      //   something_is_wrong();
+      //   println!("cannot setup device because some device already used. need to stop it first");
+      //   continue;
+      // }
+      state.output_selection = Some(select_device(
+        backend.output_devices(),
+        state.output_selection.as_ref(),
+        arguments,
+        state.interactive,
+        out,
+      )?);
+      save_config(state, out);
+    }
+    Command::Format => {
+      let (directions, arguments): (&[&str], _) = match arguments.first().map(String::as_str) {
+        Some("input") => (&["input"], &arguments[1..]),
+        Some("output") => (&["output"], &arguments[1..]),
+        _ => (&["input", "output"], arguments),
+      };
+      if arguments.is_empty() {
+        return Err(format!("usage: {}", usage(definition)));
+      }
+      // nothing changes unless every selection supports the new format
+      let mut changes = Vec::new();
+      for &direction in directions {
+        let selection = match direction {
+          "input" => &state.input_selection,
+          _ => &state.output_selection,
+        };
+        match selection {
+          Some(selection) => changes.push((direction, change_format(selection, arguments)?)),
           None => {
-            something_is_wrong();
-            println!("no device is currently available (may be there is no devices in your computer?)");
-            continue;
+            return Err(format!(
+              "no {} device selected (select it using \"{}\" command)",
+              direction, direction
+            ))
           }
+        }
+      }
+      for (direction, format) in changes {
+        let selection = match direction {
+          "input" => state.input_selection.as_mut(),
+          _ => state.output_selection.as_mut(),
         };
-        let format = device.get_best_format();
-        println!("selected device: {}", device);
-        println!("current format: {}", format);
-        state.output_selection = Some(DeviceSelection { device, format });
+        if let Some(selection) = selection {
+          selection.format = format;
+          out(format!("{} format: {} (applies to devices started from now on)", direction, format));
+        }
       }
-      Command::Start => {
-        current_command = Command::MainMenu;
-        if state.input.as_ref().is_some() {
-          something_is_wrong();
-          println!("could not start input because it is already started");
-          continue;
+      save_config(state, out);
+    }
+    Command::Volume => match arguments.first() {
+      Some(gain) => {
+        state.volume = gain
+          .parse()
+          .ok()
+          .filter(|volume: &f32| volume.is_finite() && *volume >= 0.0)
+          .ok_or(format!("volume is a gain like 0.5, not {}", gain))?;
+        if let Some(output) = &state.output {
+          output.set_volume(state.volume);
         }
-        if state.output.as_ref().is_some() {
-          something_is_wrong();
-          println!("could not start output because it is already started");
-          continue;
+        save_config(state, out);
+      }
+      None => out(format!("volume: {}", state.volume)),
+    },
+    Command::Exit => {}
+    Command::Start => {
+      if state.input.as_ref().is_some() {
+        return Err("could not start input because it is already started".to_string());
+      }
+      if state.output.as_ref().is_some() {
+        return Err("could not start output because it is already started".to_string());
+      }
+      let in_selection = state
+        .input_selection
+        .as_ref()
+        .ok_or("no input device selected (before starting select device using \"input\" command)")?;
+      let out_selection = state
+        .output_selection
+        .as_ref()
+        .ok_or("no output device selected (before starting select device using \"output\" command)")?;
+      out(format!(
+        "trying to open output for {} with format {}",
+        out_selection.device, out_selection.format
+      ));
+      let output = OutputDevice::new(
+        backend,
+        out_selection.format,
+        out_selection.buffers,
+        &out_selection.device,
+        state.hot_plug,
+        state.resampling,
+      )
+      .map_err(|err| format!("could not open output: {}", err))?;
+      output.set_volume(state.volume);
+      out(format!(
+        "trying to open input for {} with format {}",
+        in_selection.device, in_selection.format
+      ));
+      let input = InputDevice::new(
+        backend,
+        in_selection.format,
+        in_selection.buffers,
+        &in_selection.device,
+        state.hot_plug,
+        output.sender.clone(),
+      )
+      .map_err(|err| format!("could not open input: {}", err))?;
+      state.output = Some(output);
+      state.input = Some(input);
+    }
+    Command::Send => {
+      if state.input.is_some() {
+        return Err("could not start input because it is already started".to_string());
+      }
+      let in_selection = state
+        .input_selection
+        .as_ref()
+        .ok_or("no input device selected (before sending select device using \"input\" command)")?;
+      let peer = match arguments.first() {
+        Some(peer) => resolve(peer)?,
+        None if state.interactive => ask_address("peer address (host:port):", None).ok_or("no peer address")?,
+        None => return Err(format!("usage: {}", usage(definition))),
+      };
+      let net_sender = NetSender::new(peer).map_err(|err| format!("could not stream to {}: {}", peer, err))?;
+      let input = InputDevice::new(
+        backend,
+        in_selection.format,
+        in_selection.buffers,
+        &in_selection.device,
+        state.hot_plug,
+        net_sender.sender.clone(),
+      )
+      .map_err(|err| format!("could not open input: {}", err))?;
+      out(format!(
+        "streaming {} with format {} to {}",
+        in_selection.device, in_selection.format, peer
+      ));
+      state.input = Some(input);
+      state.net_sender = Some(net_sender);
+    }
+    Command::Listen => {
+      if state.output.is_some() {
+        return Err("could not start output because it is already started".to_string());
+      }
+      let out_selection = state
+        .output_selection
+        .as_ref()
+        .ok_or("no output device selected (before listening select device using \"output\" command)")?;
+      let default = format!("0.0.0.0:{}", DEFAULT_PORT);
+      let bind = match arguments.first() {
+        Some(bind) => resolve(bind)?,
+        None if state.interactive => ask_address("listen address:", Some(default)).ok_or("no listen address")?,
+        None => resolve(&default)?,
+      };
+      let output = OutputDevice::new(
+        backend,
+        out_selection.format,
+        out_selection.buffers,
+        &out_selection.device,
+        state.hot_plug,
+        state.resampling,
+      )
+      .map_err(|err| format!("could not open output: {}", err))?;
+      output.set_volume(state.volume);
+      let net_receiver = NetReceiver::new(bind, out_selection.format, state.jitter, output.sender.clone())
+        .map_err(|err| format!("could not listen on {}: {}", bind, err))?;
+      out(format!(
+        "playing stream from {} on {} with format {}",
+        net_receiver.local_addr, out_selection.device, out_selection.format
+      ));
+      state.output = Some(output);
+      state.net_receiver = Some(net_receiver);
+    }
+    Command::HotPlug => {
+      let hot_plug = match arguments.first() {
+        Some(name) => Some(HotPlug::from_name(name).ok_or(format!("usage: {}", usage(definition)))?),
+        None if state.interactive => {
+          out(format!(
+            "when a device is removed: {} (applies to devices started from now on)",
+            state.hot_plug
+          ));
+          let all = HotPlug::all();
+          ui::process_select_one_of(&all, all.iter().position(|&hot_plug| hot_plug == state.hot_plug))
         }
-        let in_selection = match &state.input_selection {
-          Some(v) => v,
-          None => {
-            something_is_wrong();
-            println!("no input device selected (before starting select device using \"input\" command)");
-            continue;
-          }
-        };
-        let out_selection = match &state.output_selection {
-          Some(v) => v,
-          None => {
-            something_is_wrong();
-            println!("no output device selected (before starting select device using \"output\" command)");
-            continue;
-          }
-        };
-        println!(
-          "trying to open output for {} with format {}",
-          out_selection.device, out_selection.format
-        );
-        state.output = Some(OutputDevice::new(out_selection.format, out_selection.device.index));
-        println!(
-          "trying to open input for {} with format {}",
-          in_selection.device, in_selection.format
-        );
-        state.input = Some(InputDevice::new(
-          in_selection.format,
-          in_selection.device.index,
-          state.output.as_ref().unwrap().sender.clone(),
-        ));
+        None => None,
+      };
+      match hot_plug {
+        Some(hot_plug) => {
+          state.hot_plug = hot_plug;
+          save_config(state, out);
+        }
+        None => out(format!("when a device is removed: {}", state.hot_plug)),
       }
-      Command::Stop => {
-        current_command = Command::MainMenu;
-        state.input = None;
-        state.output = None;
+    }
+    Command::Resampling => {
+      let resampling = match arguments.first() {
+        Some(name) => Some(Resampling::from_name(name).ok_or(format!("usage: {}", usage(definition)))?),
+        None if state.interactive => {
+          out(format!(
+            "sample rate conversion: {} (applies to outputs started from now on)",
+            state.resampling
+          ));
+          let all = Resampling::all();
+          ui::process_select_one_of(&all, all.iter().position(|&resampling| resampling == state.resampling))
+        }
+        None => None,
+      };
+      match resampling {
+        Some(resampling) => {
+          state.resampling = resampling;
+          save_config(state, out);
+        }
+        None => out(format!("sample rate conversion: {}", state.resampling)),
+      }
+    }
+    Command::NetStats => match &state.net_receiver {
+      Some(net_receiver) => out(net_receiver.stats().to_string()),
+      None => out("not listening (start it using \"listen\" command)".to_string()),
+    },
+    Command::Wait => {
+      let time = arguments.first().ok_or(format!("usage: {}", usage(definition)))?;
+      let duration = cli::parse_duration(time).ok_or(format!("write time like 10s or 500ms, not {}", time))?;
+      let deadline = Instant::now() + duration;
+      // device events are reported while waiting, not only once it is over. Once everything has stopped,
+      // e.g. a replayed recording ended, there is nothing left to wait for
+      while Instant::now() < deadline {
+        thread::sleep((deadline - Instant::now()).min(Duration::from_millis(100)));
+        if check_devices(state, out) {
+          return Err("device failed while waiting".to_string());
+        }
+        if state.input.is_none() && state.output.is_none() && state.net_sender.is_none() && state.net_receiver.is_none() {
+          break;
+        }
       }
     }
+    Command::Stop => stop_devices(state),
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::test_util::{temp_path, FORMAT},
+    device::backend::memory::MemoryBackend,
+  };
+
+  fn run_script(backend: &MemoryBackend, name: &str, script: &str) -> Result<(), String> {
+    let path = temp_path(name);
+    fs::write(&path, script).unwrap();
+    let result = run_shell(backend.clone(), Config::default(), None, Some(&path));
+    fs::remove_file(&path).unwrap();
+    result
+  }
+
+  #[test]
+  fn script_drives_the_shell() {
+    let backend = MemoryBackend::new(FORMAT);
+    let samples: Vec<u8> = (0..800).map(|i| 128 + (i % 32) as u8 * 2).collect();
+    backend.push_capture(&samples);
+    // captured before the devices open, the stream reports it as soon as it starts
+    backend.advance(Duration::from_millis(100));
+    // the end of capture stops the devices, which ends the wait long before its time is over
+    backend.end_capture();
+    let script = "# loop the memory device back at half volume\n\
+                  input \"memory capture\" 2x50\n\
+                  out 0\n\
+                  format 8000 mono 8\n\
+                  volume 0.5\n\
+                  start\n\
+                  wait 60s\n\
+                  stop\n";
+    run_script(&backend, "script.txt", script).unwrap();
+    let halved: Vec<u8> = samples.iter().map(|&sample| 128 + (sample - 128) / 2).collect();
+    assert_eq!(backend.pull_playback(), halved);
+
+    let err = run_script(&backend, "failing.txt", "input 0\nformat 44100\nstart\n").unwrap_err();
+    assert!(
+      err.ends_with("failing.txt:2: memory capture does not support 44100hz Mono 8bit"),
+      "{}",
+      err
+    );
+    assert!(run_script(&backend, "unknown.txt", "s\n").is_err());
+    assert!(run_script(&backend, "arguments.txt", "stop now\n").is_err());
   }
 }
@@ -0,0 +1,19 @@
+// Fixtures shared by the tests of the library and of the shell, both crates include this file
+
+use {
+  crate::device::info::{DeviceFormat, Encoding},
+  std::{path::PathBuf, process},
+};
+
+// one byte a frame, so sizes in tests read as frame counts
+pub const FORMAT: DeviceFormat = DeviceFormat {
+  frequency: 8000,
+  channels: 1,
+  bits: 8,
+  encoding: Encoding::Pcm,
+};
+
+// the process id keeps test runs which happen at once out of each other's files
+pub fn temp_path(name: &str) -> PathBuf {
+  std::env::temp_dir().join(format!("divana-{}-{}", process::id(), name))
+}
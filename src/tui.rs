@@ -0,0 +1,454 @@
+use {
+  crate::{
+    device::{backend::AudioBackend, common::PIPELINE_DEPTH, convert::Resampling, hotplug::HotPlug, info::*, meter::Levels},
+    device_events, run_command, save_config, stop_devices, DeviceSelection, GlobalState,
+  },
+  crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+  },
+  std::{
+    collections::VecDeque,
+    io::{stdout, Write},
+    time::{Duration, Instant},
+  },
+};
+
+// meters are read and the screen is drawn this often, keys are handled as they come
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+// the panels list names only, formats are probed once a device is selected. Even that is slow with
+// some host apis, so plugged devices show up with a delay
+const DEVICE_REFRESH: Duration = Duration::from_secs(2);
+const LOG_LINES: usize = 8;
+const BAR_WIDTH: usize = 40;
+// left end of the meters, anything quieter is shown as silence
+const FLOOR_DB: f32 = -60.0;
+const KEYS: &str =
+  "tab panel  up/down device  enter select  left/right format  s start/stop  +/- volume  h hotplug  r resampling  : command  q quit";
+
+#[derive(Copy, Clone, PartialEq)]
+enum Panel {
+  Input,
+  Output,
+}
+
+impl Panel {
+  fn name(self) -> &'static str {
+    match self {
+      Panel::Input => "input",
+      Panel::Output => "output",
+    }
+  }
+}
+
+// raw mode on the alternate screen while it lives, the shell screen comes back however the view is left
+struct Terminal;
+
+impl Terminal {
+  fn open() -> crossterm::Result<Terminal> {
+    terminal::enable_raw_mode()?;
+    // leaves raw mode again even if the alternate screen cannot be entered
+    let terminal = Terminal;
+    execute!(stdout(), EnterAlternateScreen, Hide)?;
+    Ok(terminal)
+  }
+}
+
+impl Drop for Terminal {
+  fn drop(&mut self) {
+    let _ = execute!(stdout(), Show, LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+  }
+}
+
+struct Line {
+  text: String,
+  highlighted: bool,
+}
+
+fn plain(text: String) -> Line {
+  Line { text, highlighted: false }
+}
+
+struct Tui<'a, B: AudioBackend> {
+  backend: &'a B,
+  state: GlobalState,
+  panel: Panel,
+  // device under the cursor, per panel
+  cursor: [usize; 2],
+  devices: [Vec<DeviceInfo>; 2],
+  devices_listed: Instant,
+  levels: [Levels; 2],
+  // typed after ':', keys are shortcuts while there is none
+  command_line: Option<String>,
+  // device events and failed commands, newest last
+  log: VecDeque<String>,
+}
+
+// Full screen view of both devices with their meters. Runs until it is quit, devices are stopped then.
+// What commands have to say shows up in the log, as do their errors and `messages` from before it started
+pub fn run<B: AudioBackend>(backend: &B, state: GlobalState, messages: Vec<String>) -> Result<(), String> {
+  let mut tui = Tui {
+    backend,
+    state,
+    panel: Panel::Input,
+    cursor: [0, 0],
+    devices: [Vec::new(), Vec::new()],
+    devices_listed: Instant::now(),
+    levels: Default::default(),
+    command_line: None,
+    log: VecDeque::new(),
+  };
+  messages.into_iter().for_each(|message| tui.report(message));
+  tui.list_devices();
+  for panel in [Panel::Input, Panel::Output].iter().copied() {
+    let devices = &tui.devices[panel as usize];
+    if let Some(selected) = tui
+      .selection(panel)
+      .and_then(|selection| devices.iter().position(|device| device.id == selection.device.id))
+    {
+      tui.cursor[panel as usize] = selected;
+    }
+  }
+  let result = Terminal::open().and_then(|_terminal| tui.run());
+  stop_devices(&mut tui.state);
+  result.map_err(|err| format!("terminal failed: {}", err))
+}
+
+impl<'a, B: AudioBackend> Tui<'a, B> {
+  fn run(&mut self) -> crossterm::Result<()> {
+    loop {
+      if self.devices_listed.elapsed() >= DEVICE_REFRESH {
+        self.list_devices();
+      }
+      let (messages, failed) = device_events(&mut self.state);
+      messages.into_iter().for_each(|message| self.report(message));
+      if failed {
+        self.report("everything is stopped, s starts again".to_string());
+      }
+      self.levels = [
+        self.state.input.as_ref().map(|input| input.meter().read()).unwrap_or_default(),
+        self.state.output.as_ref().map(|output| output.meter().read()).unwrap_or_default(),
+      ];
+      self.draw()?;
+      // keys are handled right away, meters keep their pace
+      let deadline = Instant::now() + REDRAW_INTERVAL;
+      while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        if !event::poll(timeout)? {
+          break;
+        }
+        if let Event::Key(key) = event::read()? {
+          if !self.key(key) {
+            return Ok(());
+          }
+          self.draw()?;
+        }
+      }
+    }
+  }
+
+  fn list_devices(&mut self) {
+    self.devices = [self.backend.input_devices_unprobed(), self.backend.output_devices_unprobed()];
+    for (cursor, devices) in self.cursor.iter_mut().zip(self.devices.iter()) {
+      *cursor = (*cursor).min(devices.len().saturating_sub(1));
+    }
+    self.devices_listed = Instant::now();
+  }
+
+  fn report(&mut self, message: String) {
+    self.log.push_back(message);
+    while self.log.len() > LOG_LINES {
+      self.log.pop_front();
+    }
+  }
+
+  fn selection(&self, panel: Panel) -> Option<&DeviceSelection> {
+    match panel {
+      Panel::Input => self.state.input_selection.as_ref(),
+      Panel::Output => self.state.output_selection.as_ref(),
+    }
+  }
+
+  fn is_running(&self, panel: Panel) -> bool {
+    match panel {
+      Panel::Input => self.state.input.is_some(),
+      Panel::Output => self.state.output.is_some(),
+    }
+  }
+
+  // shell command, false once it asks to leave
+  fn command(&mut self, line: &str) -> bool {
+    let mut output = Vec::new();
+    let result = run_command(self.backend, &mut self.state, line, &mut |line| output.push(line));
+    output.into_iter().for_each(|line| self.report(line));
+    match result {
+      Ok(keep_going) => keep_going,
+      Err(err) => {
+        self.report(format!("{}: {}", line, err));
+        true
+      }
+    }
+  }
+
+  // false once the view is to be left
+  fn key(&mut self, key: KeyEvent) -> bool {
+    // raw mode swallows the interrupt signal
+    if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+      return false;
+    }
+    if let Some(line) = &mut self.command_line {
+      match key.code {
+        KeyCode::Enter => {
+          let line = self.command_line.take().unwrap_or_default();
+          return self.command(&line);
+        }
+        KeyCode::Esc => self.command_line = None,
+        KeyCode::Backspace => {
+          line.pop();
+        }
+        KeyCode::Char(c) => line.push(c),
+        _ => {}
+      }
+      return true;
+    }
+    let panel = self.panel as usize;
+    match key.code {
+      KeyCode::Char('q') | KeyCode::Esc => return false,
+      KeyCode::Tab | KeyCode::BackTab => {
+        self.panel = match self.panel {
+          Panel::Input => Panel::Output,
+          Panel::Output => Panel::Input,
+        }
+      }
+      KeyCode::Up => self.cursor[panel] = self.cursor[panel].saturating_sub(1),
+      KeyCode::Down if self.cursor[panel] + 1 < self.devices[panel].len() => self.cursor[panel] += 1,
+      KeyCode::Enter => {
+        if let Some(device) = self.devices[panel].get(self.cursor[panel]) {
+          let buffers = self.selection(self.panel).map(|selection| selection.buffers).unwrap_or_default();
+          let line = format!("{} {} {}", self.panel.name(), device.index, buffers);
+          return self.command(&line);
+        }
+      }
+      KeyCode::Left => self.cycle_format(false),
+      KeyCode::Right => self.cycle_format(true),
+      KeyCode::Char('s') if self.is_running(Panel::Input) || self.is_running(Panel::Output) => {
+        stop_devices(&mut self.state);
+        self.report("stopped".to_string());
+      }
+      KeyCode::Char('s') => return self.command("start"),
+      KeyCode::Char('+') | KeyCode::Char('=') => return self.change_volume(0.1),
+      KeyCode::Char('-') => return self.change_volume(-0.1),
+      KeyCode::Char('h') => {
+        let hot_plug = next_of(&HotPlug::all(), self.state.hot_plug);
+        return self.command(&format!("hotplug {}", hot_plug.name()));
+      }
+      KeyCode::Char('r') => {
+        let resampling = next_of(&Resampling::all(), self.state.resampling);
+        return self.command(&format!("resampling {}", resampling.name()));
+      }
+      KeyCode::Char(':') => self.command_line = Some(String::new()),
+      _ => {}
+    }
+    true
+  }
+
+  fn change_volume(&mut self, step: f32) -> bool {
+    let volume = ((self.state.volume + step) * 10.0).round() / 10.0;
+    self.command(&format!("volume {}", volume.max(0.0)))
+  }
+
+  // next or previous format the selected device supports, for devices started from now on
+  fn cycle_format(&mut self, forward: bool) {
+    let panel = self.panel;
+    let selection = match panel {
+      Panel::Input => self.state.input_selection.as_mut(),
+      Panel::Output => self.state.output_selection.as_mut(),
+    };
+    let selection = match selection {
+      Some(selection) if !selection.device.formats().is_empty() => selection,
+      _ => return,
+    };
+    let formats = selection.device.formats();
+    let next = match (formats.iter().position(|format| *format == selection.format), forward) {
+      (Some(at), true) => (at + 1) % formats.len(),
+      (Some(at), false) => (at + formats.len() - 1) % formats.len(),
+      (None, _) => 0,
+    };
+    selection.format = formats[next];
+    let message = format!(
+      "{} format: {} (applies to devices started from now on)",
+      panel.name(),
+      formats[next]
+    );
+    let mut output = Vec::new();
+    save_config(&self.state, &mut |line| output.push(line));
+    output.into_iter().for_each(|line| self.report(line));
+    self.report(message);
+  }
+
+  fn running(&self) -> String {
+    let state = &self.state;
+    if let Some(receiver) = &state.net_receiver {
+      return format!("listening on {}", receiver.local_addr);
+    }
+    match (&state.input, &state.output, &state.net_sender) {
+      (Some(_), _, Some(_)) => "sending".to_string(),
+      (Some(_), Some(_), _) => "playing".to_string(),
+      _ => "stopped".to_string(),
+    }
+  }
+
+  fn lines(&self) -> Vec<Line> {
+    let mut lines = vec![
+      plain(format!(
+        "divana  {}  volume {}  hotplug {}  resampling {}",
+        self.running(),
+        self.state.volume,
+        self.state.hot_plug.name(),
+        self.state.resampling.name()
+      )),
+      plain(String::new()),
+    ];
+    for panel in [Panel::Input, Panel::Output].iter().copied() {
+      let focused = panel == self.panel;
+      let selection = self.selection(panel);
+      lines.push(plain(format!("{} {} devices", if focused { ">" } else { " " }, panel.name())));
+      if self.devices[panel as usize].is_empty() {
+        lines.push(plain("    none".to_string()));
+      }
+      for (at, device) in self.devices[panel as usize].iter().enumerate() {
+        let selected = selection.is_some_and(|selection| selection.device.id == device.id);
+        lines.push(Line {
+          text: format!("  {} [{}] {}", if selected { "*" } else { " " }, device.index, device),
+          highlighted: focused && at == self.cursor[panel as usize],
+        });
+      }
+      lines.push(plain(match selection {
+        Some(selection) => format!("    format {}  buffers {}", selection.format, selection.buffers),
+        None => "    nothing selected".to_string(),
+      }));
+      let levels = &self.levels[panel as usize];
+      if !self.is_running(panel) {
+        lines.push(plain("    not running".to_string()));
+      }
+      for (channel, (&peak, &rms)) in levels.peak.iter().zip(levels.rms.iter()).enumerate() {
+        lines.push(plain(meter_line(channel, peak, rms)));
+      }
+      lines.push(plain(String::new()));
+    }
+    // buffers passed on by the producer and not taken by the output or the network yet
+    let queues = [
+      ("output", self.state.output.as_ref().map(|output| output.queued())),
+      ("send", self.state.net_sender.as_ref().map(|sender| sender.sender.queued())),
+    ];
+    for (name, queued) in queues.iter() {
+      if let Some(queued) = queued {
+        lines.push(plain(format!(
+          "  {} queue [{}] {}/{} buffers",
+          name,
+          bar(*queued as f64 / PIPELINE_DEPTH as f64, BAR_WIDTH),
+          queued,
+          PIPELINE_DEPTH
+        )));
+      }
+    }
+    if let Some(receiver) = &self.state.net_receiver {
+      lines.push(plain(format!("  jitter buffer: {}", receiver.stats())));
+    }
+    lines.push(plain(String::new()));
+    lines.extend(self.log.iter().map(|message| plain(format!("  {}", message))));
+    lines.push(plain(String::new()));
+    lines.push(plain(match &self.command_line {
+      Some(line) => format!(":{}_", line),
+      None => KEYS.to_string(),
+    }));
+    lines
+  }
+
+  // rewrites every line in place instead of clearing first, so the screen does not flicker
+  fn draw(&self) -> crossterm::Result<()> {
+    let (width, height) = terminal::size()?;
+    let mut out = stdout();
+    for (row, line) in self.lines().into_iter().take(height as usize).enumerate() {
+      let text: String = line.text.chars().take(width as usize).collect();
+      queue!(out, MoveTo(0, row as u16), Clear(ClearType::UntilNewLine))?;
+      if line.highlighted {
+        queue!(out, SetAttribute(Attribute::Reverse), Print(text), SetAttribute(Attribute::Reset))?;
+      } else {
+        queue!(out, Print(text))?;
+      }
+    }
+    queue!(out, Clear(ClearType::FromCursorDown))?;
+    out.flush()?;
+    Ok(())
+  }
+}
+
+fn next_of<T: Copy + PartialEq>(all: &[T], current: T) -> T {
+  let at = all.iter().position(|value| *value == current).unwrap_or(0);
+  all[(at + 1) % all.len()]
+}
+
+fn decibels(level: f32) -> f32 {
+  20.0 * level.log10()
+}
+
+fn bar(fraction: f64, width: usize) -> String {
+  let filled = (fraction.clamp(0.0, 1.0) * width as f64).round() as usize;
+  format!("{}{}", "#".repeat(filled), " ".repeat(width - filled))
+}
+
+// rms is filled in, the peak reaches further as a thinner line
+fn meter_line(channel: usize, peak: f32, rms: f32) -> String {
+  let (peak, rms) = (decibels(peak), decibels(rms));
+  if peak <= FLOOR_DB {
+    return format!("    {} [{}] silence", channel + 1, " ".repeat(BAR_WIDTH));
+  }
+  let reach = |db: f32| (((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0) * BAR_WIDTH as f32).round() as usize;
+  let (rms_reach, peak_reach) = (reach(rms), reach(peak));
+  let meter: String = (0..BAR_WIDTH)
+    .map(|at| match at {
+      at if at < rms_reach => '=',
+      at if at < peak_reach => '-',
+      _ => ' ',
+    })
+    .collect();
+  format!(
+    "    {} [{}] peak {:5.1} dB  rms {:5.1} dB{}",
+    channel + 1,
+    meter,
+    peak,
+    rms.max(FLOOR_DB),
+    if peak >= 0.0 { "  CLIP" } else { "" }
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn meters_span_sixty_decibels() {
+    assert_eq!(
+      meter_line(0, 1.0, 1.0),
+      format!("    1 [{}] peak   0.0 dB  rms   0.0 dB  CLIP", "=".repeat(BAR_WIDTH))
+    );
+    // -20 dB peak is two thirds of the way, -40 dB rms one third
+    assert_eq!(
+      meter_line(1, 0.1, 0.01),
+      format!(
+        "    2 [{}{}{}] peak -20.0 dB  rms -40.0 dB",
+        "=".repeat(13),
+        "-".repeat(14),
+        " ".repeat(13)
+      )
+    );
+    assert!(meter_line(0, 0.0, 0.0).ends_with("silence"));
+    assert!(meter_line(0, 0.0001, 0.0001).ends_with("silence"));
+    assert_eq!(bar(0.5, 4), "##  ");
+    assert_eq!(bar(3.0, 4), "####");
+  }
+}
@@ -1,48 +1,183 @@
-﻿use std::io::{stdout, Write, stdin};
 use std::fmt::Display;
+use std::io::{stdin, stdout, Write};
 
+// None at the end of input, ctrl-d or a closed pipe
 pub fn process_user_input() -> Option<String> {
   let mut user_input = String::new();
   print!("> ");
   stdout().flush().unwrap();
-  if stdin().read_line(&mut user_input).is_err() {
-    println!("input error!");
-    return None
-  };
-  Some(user_input.trim().to_string())
+  match stdin().read_line(&mut user_input) {
+    Ok(0) => {
+      println!();
+      None
+    }
+    Ok(_) => Some(user_input.trim().to_string()),
+    Err(err) => {
+      println!("input error: {}", err);
+      None
+    }
+  }
 }
 
-pub fn process_select_one_of<T: Display + Clone>(variants: Vec<T>) -> Option<T> {
-  if variants.len() == 0 {
-    return None
+// words of a command line, "double quotes" keep spaces inside an argument and # starts a comment
+pub fn split_arguments(line: &str) -> Result<Vec<String>, String> {
+  let mut arguments = Vec::new();
+  let mut chars = line.chars().peekable();
+  loop {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+      chars.next();
+    }
+    let mut argument = String::new();
+    match chars.next() {
+      None | Some('#') => return Ok(arguments),
+      Some('"') => loop {
+        match chars.next() {
+          Some('"') => break,
+          Some(c) => argument.push(c),
+          None => return Err(format!("missing closing quote in {}", line)),
+        }
+      },
+      Some(c) => {
+        argument.push(c);
+        while let Some(c) = chars.peek().copied().filter(|c| !c.is_whitespace()) {
+          argument.push(c);
+          chars.next();
+        }
+      }
+    }
+    arguments.push(argument);
   }
-  if variants.len() == 1 {
-    println!("selection of one variant performed automatically: {}", variants[0]);
-    return Some(variants[0].clone())
+}
+
+// Where prompts read their answers from, the console or lines given by a test or a script
+pub trait InputSource {
+  // None once there is nothing more to read
+  fn read_line(&mut self) -> Option<String>;
+}
+
+pub struct Console;
+
+impl InputSource for Console {
+  fn read_line(&mut self) -> Option<String> {
+    process_user_input()
+  }
+}
+
+impl<I: Iterator<Item = String>> InputSource for I {
+  fn read_line(&mut self) -> Option<String> {
+    self.next().map(|line| line.trim().to_string())
+  }
+}
+
+pub fn process_select_one_of<T: Display + Clone>(variants: &[T], default: Option<usize>) -> Option<T> {
+  select_one_of(&mut Console, variants, default)
+}
+
+// Asks until a variant is chosen by its number or a part of its name. Empty answer takes the default,
+// "cancel" or the end of input selects nothing
+pub fn select_one_of<T: Display + Clone>(input: &mut impl InputSource, variants: &[T], default: Option<usize>) -> Option<T> {
+  let default = default.filter(|&default| default < variants.len());
+  match variants {
+    [] => return None,
+    [only] => {
+      println!("selection of one variant performed automatically: {}", only);
+      return Some(only.clone());
+    }
+    _ => {}
   }
   println!("select one of:");
-  for i in 0..variants.len() {
-    println!("  [{}] {}", i, variants[i]);
+  for (index, variant) in variants.iter().enumerate() {
+    let marker = if default == Some(index) { " (default)" } else { "" };
+    println!("  [{}] {}{}", index, variant, marker);
   }
+  let names: Vec<String> = variants.iter().map(|variant| variant.to_string()).collect();
   loop {
-    let user_selection_str = match process_user_input() {
-      Some(str) => str,
+    let answer = match input.read_line() {
+      Some(answer) => answer,
       None => {
-        println!("you should select exactly one (write a number)!");
-        continue;
+        println!("nothing selected");
+        return None;
       }
     };
-    let user_selection_id = match user_selection_str.parse::<usize>() {
-      Ok(res) => res,
-      Err(_) => {
-        println!("you should write number!");
-        continue;
-      }
+    let selected = match (answer.as_str(), default) {
+      ("", Some(default)) => Ok(default),
+      ("", None) => Err("write a number or a part of the name, \"cancel\" keeps things as they are".to_string()),
+      ("cancel", _) => return None,
+      (answer, _) => match answer.parse::<usize>() {
+        Ok(index) if index < variants.len() => Ok(index),
+        Ok(_) => Err("there's no such variant".to_string()),
+        Err(_) => find_variant(&names, answer),
+      },
     };
-    if user_selection_id > variants.len() {
-      println!("there's no such variant");
-      continue;
+    match selected {
+      Ok(index) => return Some(variants[index].clone()),
+      Err(err) => println!("{}", err),
+    }
+  }
+}
+
+// case-insensitive, the whole name goes before a part of it, which goes before letters in the same order
+fn find_variant(names: &[String], query: &str) -> Result<usize, String> {
+  let query = query.to_lowercase();
+  let names: Vec<String> = names.iter().map(|name| name.to_lowercase()).collect();
+  let is_subsequence = |name: &str| {
+    let mut letters = name.chars();
+    query.chars().all(|c| letters.any(|letter| letter == c))
+  };
+  let tiers: [&dyn Fn(&str) -> bool; 3] = [&|name| name == query, &|name| name.contains(&query), &is_subsequence];
+  for matches in tiers.iter() {
+    let matching: Vec<usize> = (0..names.len()).filter(|&index| matches(&names[index])).collect();
+    match matching.as_slice() {
+      [] => continue,
+      [index] => return Ok(*index),
+      _ => {
+        let numbers: Vec<String> = matching.iter().map(|index| index.to_string()).collect();
+        return Err(format!("{} matches [{}], please, be more explicit", query, numbers.join("], [")));
+      }
     }
-    return Some(variants[user_selection_id].clone())
+  }
+  Err(format!("nothing matches {}", query))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn arguments_are_split_on_spaces_outside_quotes() {
+    assert_eq!(
+      split_arguments("  input \"USB Headset #2\"  4x20 # the good one").unwrap(),
+      vec!["input", "USB Headset #2", "4x20"]
+    );
+    assert_eq!(
+      split_arguments("format 48000 stereo 16").unwrap(),
+      vec!["format", "48000", "stereo", "16"]
+    );
+    assert!(split_arguments("# comment only").unwrap().is_empty());
+    assert!(split_arguments("input \"USB").is_err());
+  }
+
+  fn answers(lines: &[&str]) -> impl InputSource {
+    lines.iter().map(|line| line.to_string()).collect::<Vec<_>>().into_iter()
+  }
+
+  #[test]
+  fn selection_takes_numbers_names_and_defaults() {
+    let variants = ["Speakers", "USB Headset", "USB Microphone"];
+    // one past the last variant is refused instead of indexing out of bounds
+    assert_eq!(select_one_of(&mut answers(&["3", "2"]), &variants, None), Some("USB Microphone"));
+    assert_eq!(select_one_of(&mut answers(&["", "0"]), &variants, None), Some("Speakers"));
+    assert_eq!(select_one_of(&mut answers(&[""]), &variants, Some(1)), Some("USB Headset"));
+    assert_eq!(
+      select_one_of(&mut answers(&["usb", "headset"]), &variants, None),
+      Some("USB Headset")
+    );
+    assert_eq!(select_one_of(&mut answers(&["mic"]), &variants, None), Some("USB Microphone"));
+    assert_eq!(select_one_of(&mut answers(&["spkrs"]), &variants, None), Some("Speakers"));
+    assert_eq!(select_one_of(&mut answers(&["cancel", "0"]), &variants, Some(0)), None);
+    // end of input gives up instead of asking forever
+    assert_eq!(select_one_of(&mut answers(&["guitar"]), &variants, None), None);
+    assert_eq!(select_one_of(&mut answers(&[]), &["only"], None), Some("only"));
+    assert_eq!(select_one_of::<&str>(&mut answers(&["0"]), &[], None), None);
   }
 }
@@ -1,3 +1,11 @@
+mod decoder;
+mod encoder;
+mod packet;
+mod reader;
+mod writer;
+
+pub use self::{decoder::*, encoder::*, packet::*, reader::*, writer::*};
+
 #[allow(dead_code, non_camel_case_types, unused_imports)]
 pub mod ogg {
   use std::os::raw::*;
@@ -88,7 +96,9 @@ pub mod ogg {
     pub packetno: ogg_int64_t,
     pub granulepos: ogg_int64_t,
   }
-  #[link(name = "libogg", kind = "static")]
+  // prebuilt static libraries are shipped for windows, other hosts link the system ones
+  #[cfg_attr(windows, link(name = "libogg", kind = "static"))]
+  #[cfg_attr(not(windows), link(name = "ogg"))]
   extern "C" {
     //
     // Ogg BITSTREAM PRIMITIVES: bitstream
@@ -337,7 +347,8 @@ pub mod codec {
   // packetization aren't necessary as they're provided by the transport
   // and the streaming layer is not used
 
-  #[link(name = "libvorbis", kind = "static")]
+  #[cfg_attr(windows, link(name = "libvorbis", kind = "static"))]
+  #[cfg_attr(not(windows), link(name = "vorbis"))]
   extern "C" {
     /* Vorbis PRIMITIVES: general ***************************************/
 
@@ -474,7 +485,9 @@ mod enc {
                                       other when boosting or damping average bitrate.*/
   }
 
-  #[link(name = "libvorbis", kind = "static")]
+  // libvorbisenc is a separate library everywhere but in the windows build
+  #[cfg_attr(windows, link(name = "libvorbis", kind = "static"))]
+  #[cfg_attr(not(windows), link(name = "vorbisenc"))]
   extern "C" {
     /**
      * This is the primary function within libvorbisenc for setting up managed
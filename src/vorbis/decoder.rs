@@ -0,0 +1,217 @@
+use {
+  crate::{
+    device::{
+      common::{AudioBuffer, Sample},
+      info::{DeviceFormat, Encoding},
+    },
+    vorbis::{codec::*, packet::*},
+  },
+  std::{mem::zeroed, ptr, slice},
+};
+
+// Turns Vorbis packets of one logical stream back into interleaved PCM. The three header packets
+// have to come first, they produce no audio
+pub struct VorbisDecoder {
+  // libvorbis states reference each other by pointers, so all of them are boxed
+  info: Box<vorbis_info>,
+  comment: Box<vorbis_comment>,
+  dsp: Box<vorbis_dsp_state>,
+  block: Box<vorbis_block>,
+  headers_received: usize,
+  next_packet_number: Option<i64>,
+}
+
+impl VorbisDecoder {
+  pub fn new() -> VorbisDecoder {
+    unsafe {
+      let mut decoder = VorbisDecoder {
+        info: Box::new(zeroed()),
+        comment: Box::new(zeroed()),
+        dsp: Box::new(zeroed()),
+        block: Box::new(zeroed()),
+        headers_received: 0,
+        next_packet_number: None,
+      };
+      vorbis_info_init(&mut *decoder.info);
+      vorbis_comment_init(&mut *decoder.comment);
+      decoder
+    }
+  }
+
+  // all headers are received, channels and rate are known
+  pub fn is_ready(&self) -> bool {
+    self.headers_received == 3
+  }
+
+  pub fn channels(&self) -> u16 {
+    self.info.channels as u16
+  }
+
+  pub fn rate(&self) -> u32 {
+    self.info.rate as u32
+  }
+
+  // Samples are in -1.0..1.0. On VorbisError::Hole nothing is decoded, the decoder already expects
+  // the packet which revealed the gap, so it can be passed again (or dropped)
+  pub fn decode_f32(&mut self, packet: &OggPacket) -> Result<Vec<f32>, VorbisError> {
+    self.check_sequence(packet)?;
+    let mut raw_packet = packet.as_raw();
+    unsafe {
+      if !self.is_ready() {
+        let code = vorbis_synthesis_headerin(&mut *self.info, &mut *self.comment, &mut raw_packet);
+        if code != 0 {
+          return Err(VorbisError::from_code("vorbis_synthesis_headerin", code));
+        }
+        self.headers_received += 1;
+        if self.is_ready() {
+          let code = vorbis_synthesis_init(&mut *self.dsp, &mut *self.info);
+          if code != 0 {
+            // headers are accepted but unusable, start over with the next stream
+            self.headers_received = 0;
+            return Err(VorbisError::from_code("vorbis_synthesis_init", code));
+          }
+          vorbis_block_init(&mut *self.dsp, &mut *self.block);
+        }
+        return Ok(Vec::new());
+      }
+
+      let code = vorbis_synthesis(&mut *self.block, &mut raw_packet);
+      if code != 0 {
+        return Err(VorbisError::from_code("vorbis_synthesis", code));
+      }
+      let code = vorbis_synthesis_blockin(&mut *self.dsp, &mut *self.block);
+      if code != 0 {
+        return Err(VorbisError::from_code("vorbis_synthesis_blockin", code));
+      }
+
+      let channels = self.channels() as usize;
+      let mut pcm = Vec::new();
+      loop {
+        let mut channel_buffers: *mut *mut f32 = ptr::null_mut();
+        let frames = vorbis_synthesis_pcmout(&mut *self.dsp, &mut channel_buffers);
+        if frames <= 0 {
+          break;
+        }
+        let channel_buffers = slice::from_raw_parts(channel_buffers, channels);
+        pcm.reserve(frames as usize * channels);
+        for frame in 0..frames as usize {
+          for &channel_buffer in channel_buffers {
+            pcm.push(*channel_buffer.add(frame));
+          }
+        }
+        vorbis_synthesis_read(&mut *self.dsp, frames);
+      }
+      Ok(pcm)
+    }
+  }
+
+  // same scale as VorbisEncoder::encode_i16, so i16 audio survives a round trip
+  pub fn decode_i16(&mut self, packet: &OggPacket) -> Result<Vec<i16>, VorbisError> {
+    let pcm = self.decode_f32(packet)?;
+    Ok(pcm.into_iter().map(i16::from_f32).collect())
+  }
+
+  // float audio in the stream's rate and channels, output device converts it to whatever it plays
+  pub fn decode_buffer(&mut self, packet: &OggPacket) -> Result<AudioBuffer<f32>, VorbisError> {
+    let pcm = self.decode_f32(packet)?;
+    Ok(AudioBuffer::new(self.format(), pcm))
+  }
+
+  // only meaningful once `is_ready`
+  pub fn format(&self) -> DeviceFormat {
+    DeviceFormat {
+      frequency: self.rate(),
+      channels: self.channels().max(1),
+      bits: 32,
+      encoding: Encoding::Float,
+    }
+  }
+
+  fn check_sequence(&mut self, packet: &OggPacket) -> Result<(), VorbisError> {
+    let expected = self.next_packet_number;
+    self.next_packet_number = Some(packet.packet_number + 1);
+    match expected {
+      Some(expected) if packet.packet_number > expected => {
+        self.next_packet_number = Some(packet.packet_number);
+        Err(VorbisError::Hole {
+          lost: Some(packet.packet_number - expected),
+        })
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
+impl Default for VorbisDecoder {
+  fn default() -> VorbisDecoder {
+    VorbisDecoder::new()
+  }
+}
+
+impl Drop for VorbisDecoder {
+  fn drop(&mut self) {
+    unsafe {
+      if self.is_ready() {
+        vorbis_block_clear(&mut *self.block);
+        vorbis_dsp_clear(&mut *self.dsp);
+      }
+      vorbis_comment_clear(&mut *self.comment);
+      vorbis_info_clear(&mut *self.info);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::vorbis::encoder::*};
+
+  fn encode_second(rate: u32) -> Vec<OggPacket> {
+    let mut encoder = VorbisEncoder::new(1, rate, Quality::Vbr(0.3), 1).unwrap();
+    let mut packets = encoder.headers().to_vec();
+    let sine: Vec<i16> = (0..rate)
+      .map(|i| ((i as f32 * 440.0 * 2.0 * std::f32::consts::PI / rate as f32).sin() * 10000.0) as i16)
+      .collect();
+    packets.extend(encoder.encode_i16(&sine).unwrap());
+    packets.extend(encoder.finish().unwrap());
+    packets
+  }
+
+  #[test]
+  fn decodes_what_encoder_produced() {
+    let rate = 22050;
+    let mut decoder = VorbisDecoder::new();
+    let mut frames = 0;
+    for packet in encode_second(rate) {
+      let buffer = decoder.decode_buffer(&packet).unwrap();
+      assert_eq!(buffer.format(), decoder.format());
+      frames += buffer.frames();
+    }
+    assert!(decoder.is_ready());
+    assert_eq!(decoder.channels(), 1);
+    assert_eq!(decoder.rate(), rate);
+    assert_eq!(frames, rate as usize);
+  }
+
+  #[test]
+  fn garbage_and_gaps_are_errors() {
+    let mut decoder = VorbisDecoder::new();
+    let garbage = OggPacket {
+      data: b"definitely not vorbis".to_vec(),
+      serial: 1,
+      granule_position: 0,
+      packet_number: 0,
+      bos: true,
+      eos: false,
+    };
+    assert_eq!(decoder.decode_f32(&garbage), Err(VorbisError::NotVorbis));
+
+    let mut decoder = VorbisDecoder::new();
+    let mut packets = encode_second(22050).into_iter();
+    for packet in packets.by_ref().take(4) {
+      decoder.decode_f32(&packet).unwrap();
+    }
+    let after_gap = packets.nth(1).unwrap();
+    assert_eq!(decoder.decode_f32(&after_gap), Err(VorbisError::Hole { lost: Some(1) }));
+    decoder.decode_f32(&after_gap).unwrap();
+  }
+}
@@ -0,0 +1,235 @@
+use {
+  crate::vorbis::{codec::*, enc::*, ogg::*, packet::*},
+  std::{
+    ffi::CString,
+    mem::zeroed,
+    os::raw::{c_int, c_long},
+    ptr, slice,
+  },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quality {
+  // -0.1 (lowest) .. 1.0 (highest), 0.4 is about 128kbps for 44100hz stereo
+  Vbr(f32),
+  // bits per second, None leaves the limit unset
+  Managed {
+    min: Option<u32>,
+    nominal: Option<u32>,
+    max: Option<u32>,
+  },
+}
+
+// Turns interleaved PCM into Vorbis packets of one logical stream. Three header packets come first,
+// they have to be written (and flushed to their own pages) before any audio packet
+pub struct VorbisEncoder {
+  // libvorbis states reference each other by pointers, so all of them are boxed
+  info: Box<vorbis_info>,
+  comment: Box<vorbis_comment>,
+  dsp: Box<vorbis_dsp_state>,
+  block: Box<vorbis_block>,
+  channels: u16,
+  serial: i32,
+  headers: Vec<OggPacket>,
+}
+
+impl VorbisEncoder {
+  pub fn new(channels: u16, rate: u32, quality: Quality, serial: i32) -> Result<VorbisEncoder, VorbisError> {
+    unsafe {
+      let mut info = Box::new(zeroed::<vorbis_info>());
+      vorbis_info_init(&mut *info);
+      let (function, code) = match quality {
+        Quality::Vbr(quality) => (
+          "vorbis_encode_init_vbr",
+          vorbis_encode_init_vbr(&mut *info, channels as c_long, rate as c_long, quality),
+        ),
+        Quality::Managed { min, nominal, max } => {
+          let limit = |bitrate: Option<u32>| bitrate.map(|bitrate| bitrate as c_long).unwrap_or(-1);
+          (
+            "vorbis_encode_init",
+            vorbis_encode_init(
+              &mut *info,
+              channels as c_long,
+              rate as c_long,
+              limit(max),
+              limit(nominal),
+              limit(min),
+            ),
+          )
+        }
+      };
+      if code != 0 {
+        vorbis_info_clear(&mut *info);
+        return Err(VorbisError::from_code(function, code));
+      }
+
+      let mut comment = Box::new(zeroed::<vorbis_comment>());
+      vorbis_comment_init(&mut *comment);
+      let tag = CString::new("ENCODER").unwrap();
+      let encoder = CString::new(env!("CARGO_PKG_NAME")).unwrap();
+      vorbis_comment_add_tag(&mut *comment, tag.as_ptr(), encoder.as_ptr());
+
+      let mut dsp = Box::new(zeroed::<vorbis_dsp_state>());
+      let code = vorbis_analysis_init(&mut *dsp, &mut *info);
+      if code != 0 {
+        vorbis_comment_clear(&mut *comment);
+        vorbis_info_clear(&mut *info);
+        return Err(VorbisError::from_code("vorbis_analysis_init", code));
+      }
+      let mut block = Box::new(zeroed::<vorbis_block>());
+      vorbis_block_init(&mut *dsp, &mut *block);
+
+      // from here on Drop takes care of cleanup
+      let mut encoder = VorbisEncoder {
+        info,
+        comment,
+        dsp,
+        block,
+        channels,
+        serial,
+        headers: Vec::new(),
+      };
+      let mut identification = zeroed::<ogg_packet>();
+      let mut comments = zeroed::<ogg_packet>();
+      let mut codebooks = zeroed::<ogg_packet>();
+      let code = vorbis_analysis_headerout(
+        &mut *encoder.dsp,
+        &mut *encoder.comment,
+        &mut identification,
+        &mut comments,
+        &mut codebooks,
+      );
+      if code != 0 {
+        return Err(VorbisError::from_code("vorbis_analysis_headerout", code));
+      }
+      encoder.headers = vec![
+        OggPacket::from_raw(&identification, serial),
+        OggPacket::from_raw(&comments, serial),
+        OggPacket::from_raw(&codebooks, serial),
+      ];
+      Ok(encoder)
+    }
+  }
+
+  pub fn channels(&self) -> u16 {
+    self.channels
+  }
+
+  pub fn rate(&self) -> u32 {
+    self.info.rate as u32
+  }
+
+  pub fn serial(&self) -> i32 {
+    self.serial
+  }
+
+  // identification, comment and codebook packets
+  pub fn headers(&self) -> &[OggPacket] {
+    &self.headers
+  }
+
+  // returns audio packets completed so far, most calls with short input return nothing
+  pub fn encode_i16(&mut self, pcm: &[i16]) -> Result<Vec<OggPacket>, VorbisError> {
+    self.encode(pcm, |sample| sample as f32 / 32768.0)
+  }
+
+  pub fn encode_f32(&mut self, pcm: &[f32]) -> Result<Vec<OggPacket>, VorbisError> {
+    self.encode(pcm, |sample| sample)
+  }
+
+  // flushes buffered audio, last returned packet is marked as end of stream
+  pub fn finish(mut self) -> Result<Vec<OggPacket>, VorbisError> {
+    let code = unsafe { vorbis_analysis_wrote(&mut *self.dsp, 0) };
+    if code != 0 {
+      return Err(VorbisError::from_code("vorbis_analysis_wrote", code));
+    }
+    self.packets_out()
+  }
+
+  fn encode<T: Copy>(&mut self, pcm: &[T], to_f32: impl Fn(T) -> f32) -> Result<Vec<OggPacket>, VorbisError> {
+    let channels = self.channels as usize;
+    let frames = pcm.len() / channels;
+    if frames == 0 {
+      return Ok(Vec::new());
+    }
+    unsafe {
+      let buffer = vorbis_analysis_buffer(&mut *self.dsp, frames as c_int);
+      let buffer = slice::from_raw_parts(buffer, channels);
+      for (channel, &channel_buffer) in buffer.iter().enumerate() {
+        let channel_buffer = slice::from_raw_parts_mut(channel_buffer, frames);
+        for (frame, sample) in channel_buffer.iter_mut().enumerate() {
+          *sample = to_f32(pcm[frame * channels + channel]);
+        }
+      }
+      let code = vorbis_analysis_wrote(&mut *self.dsp, frames as c_int);
+      if code != 0 {
+        return Err(VorbisError::from_code("vorbis_analysis_wrote", code));
+      }
+    }
+    self.packets_out()
+  }
+
+  fn packets_out(&mut self) -> Result<Vec<OggPacket>, VorbisError> {
+    let mut packets = Vec::new();
+    unsafe {
+      while vorbis_analysis_blockout(&mut *self.dsp, &mut *self.block) == 1 {
+        let code = vorbis_analysis(&mut *self.block, ptr::null_mut());
+        if code != 0 {
+          return Err(VorbisError::from_code("vorbis_analysis", code));
+        }
+        let code = vorbis_bitrate_addblock(&mut *self.block);
+        if code != 0 {
+          return Err(VorbisError::from_code("vorbis_bitrate_addblock", code));
+        }
+        let mut packet = zeroed::<ogg_packet>();
+        while vorbis_bitrate_flushpacket(&mut *self.dsp, &mut packet) == 1 {
+          packets.push(OggPacket::from_raw(&packet, self.serial));
+        }
+      }
+    }
+    Ok(packets)
+  }
+}
+
+impl Drop for VorbisEncoder {
+  fn drop(&mut self) {
+    unsafe {
+      vorbis_block_clear(&mut *self.block);
+      vorbis_dsp_clear(&mut *self.dsp);
+      vorbis_comment_clear(&mut *self.comment);
+      vorbis_info_clear(&mut *self.info);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encodes_headers_and_audio_up_to_end_of_stream() {
+    let rate = 22050;
+    let mut encoder = VorbisEncoder::new(1, rate, Quality::Vbr(0.3), 1).unwrap();
+    assert_eq!(encoder.headers().len(), 3);
+    assert!(encoder.headers()[0].bos);
+
+    let sine: Vec<i16> = (0..rate)
+      .map(|i| ((i as f32 * 440.0 * 2.0 * std::f32::consts::PI / rate as f32).sin() * 10000.0) as i16)
+      .collect();
+    let mut packets = Vec::new();
+    for chunk in sine.chunks(1024) {
+      packets.extend(encoder.encode_i16(chunk).unwrap());
+    }
+    packets.extend(encoder.finish().unwrap());
+
+    let last = packets.last().unwrap();
+    assert!(last.eos);
+    assert_eq!(last.granule_position, rate as i64);
+    assert!(packets.iter().all(|packet| packet.serial == 1 && !packet.data.is_empty()));
+  }
+
+  #[test]
+  fn impossible_setup_is_an_error() {
+    assert!(VorbisEncoder::new(1, 44100, Quality::Vbr(5.0), 1).is_err());
+  }
+}
@@ -0,0 +1,132 @@
+use {
+  crate::vorbis::{codec::*, ogg::*},
+  std::{io, os::raw::c_int, slice},
+  thiserror::Error,
+};
+
+#[derive(Error, Debug)]
+pub enum OggError {
+  #[error("io error: {0}")]
+  Io(#[from] io::Error),
+  // reading can be continued after this one, libogg resyncs on the next page
+  #[error("lost sync with ogg stream, garbage skipped")]
+  LostSync,
+  // reading can be continued after this one too, next packet follows the gap
+  #[error("hole in data of logical stream {serial}")]
+  Hole { serial: i32 },
+  #[error("{function} failed for logical stream {serial}")]
+  Internal { function: &'static str, serial: i32 },
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum VorbisError {
+  #[error("invalid setup request for {function}")]
+  InvalidSetup { function: &'static str },
+  #[error("mode requested from {function} is not implemented")]
+  Unimplemented { function: &'static str },
+  #[error("internal libvorbis fault in {function}")]
+  Fault { function: &'static str },
+  #[error("packet is not vorbis data")]
+  NotVorbis,
+  #[error("vorbis header is corrupted")]
+  BadHeader,
+  #[error("vorbis version is not supported")]
+  Version,
+  #[error("packet is not an audio packet")]
+  NotAudio,
+  #[error("audio packet is corrupted")]
+  BadPacket,
+  // lost is None when libvorbis does not know how many packets are missing
+  #[error("hole in packet sequence, lost {lost:?} packets")]
+  Hole { lost: Option<i64> },
+  #[error("{function} failed with code {code}")]
+  Internal { function: &'static str, code: c_int },
+}
+
+impl VorbisError {
+  pub(crate) fn from_code(function: &'static str, code: c_int) -> VorbisError {
+    match code {
+      OV_EINVAL => VorbisError::InvalidSetup { function },
+      OV_EIMPL => VorbisError::Unimplemented { function },
+      OV_EFAULT => VorbisError::Fault { function },
+      OV_ENOTVORBIS => VorbisError::NotVorbis,
+      OV_EBADHEADER => VorbisError::BadHeader,
+      OV_EVERSION => VorbisError::Version,
+      OV_ENOTAUDIO => VorbisError::NotAudio,
+      OV_EBADPACKET => VorbisError::BadPacket,
+      OV_HOLE => VorbisError::Hole { lost: None },
+      _ => VorbisError::Internal { function, code },
+    }
+  }
+}
+
+// Owned copy of one raw Ogg packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct OggPacket {
+  pub data: Vec<u8>,
+  pub serial: i32,
+  pub granule_position: i64,
+  pub packet_number: i64,
+  pub bos: bool,
+  pub eos: bool,
+}
+
+impl OggPacket {
+  pub(crate) unsafe fn from_raw(packet: &ogg_packet, serial: i32) -> OggPacket {
+    OggPacket {
+      data: copy_raw(packet.packet, packet.bytes as usize),
+      serial,
+      granule_position: packet.granulepos,
+      packet_number: packet.packetno,
+      bos: packet.b_o_s != 0,
+      eos: packet.e_o_s != 0,
+    }
+  }
+
+  // borrows data, returned struct must not outlive self and must not be freed by libogg
+  pub(crate) fn as_raw(&self) -> ogg_packet {
+    ogg_packet {
+      packet: self.data.as_ptr() as *mut _,
+      bytes: self.data.len() as _,
+      b_o_s: self.bos as _,
+      e_o_s: self.eos as _,
+      granulepos: self.granule_position,
+      packetno: self.packet_number,
+    }
+  }
+}
+
+// Owned copy of one Ogg page, header fields are decoded eagerly
+#[derive(Clone, Debug, PartialEq)]
+pub struct OggPage {
+  pub header: Vec<u8>,
+  pub body: Vec<u8>,
+  pub serial: i32,
+  pub page_number: i64,
+  pub granule_position: i64,
+  pub bos: bool,
+  pub eos: bool,
+  pub continued: bool,
+}
+
+impl OggPage {
+  pub(crate) unsafe fn from_raw(page: &ogg_page) -> OggPage {
+    OggPage {
+      header: copy_raw(page.header, page.header_len as usize),
+      body: copy_raw(page.body, page.body_len as usize),
+      serial: ogg_page_serialno(page),
+      page_number: ogg_page_pageno(page) as i64,
+      granule_position: ogg_page_granulepos(page),
+      bos: ogg_page_bos(page) != 0,
+      eos: ogg_page_eos(page) != 0,
+      continued: ogg_page_continued(page) != 0,
+    }
+  }
+}
+
+unsafe fn copy_raw(data: *const u8, length: usize) -> Vec<u8> {
+  if data.is_null() || length == 0 {
+    return Vec::new();
+  }
+  slice::from_raw_parts(data, length).to_vec()
+}
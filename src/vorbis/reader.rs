@@ -0,0 +1,154 @@
+use {
+  crate::vorbis::{ogg::*, packet::*},
+  std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read},
+    mem::zeroed,
+    os::raw::c_long,
+    slice,
+  },
+};
+
+const READ_CHUNK: usize = 4096;
+
+// Demuxer over any byte source: yields pages in file order and packets of all
+// multiplexed logical streams in order of their completion
+pub struct OggReader<R: Read> {
+  reader: R,
+  // libogg states are boxed so their addresses never change while libogg works with them
+  sync: Box<ogg_sync_state>,
+  streams: HashMap<i32, Box<ogg_stream_state>>,
+  // logical streams which got pages but may still hold undelivered packets
+  dirty: VecDeque<i32>,
+}
+
+impl<R: Read> OggReader<R> {
+  pub fn new(reader: R) -> OggReader<R> {
+    let mut sync = Box::new(unsafe { zeroed::<ogg_sync_state>() });
+    unsafe { ogg_sync_init(&mut *sync) };
+    OggReader {
+      reader,
+      sync,
+      streams: HashMap::new(),
+      dirty: VecDeque::new(),
+    }
+  }
+
+  // Ok(None) means end of input
+  pub fn next_page(&mut self) -> Result<Option<OggPage>, OggError> {
+    let mut raw_page = unsafe { zeroed::<ogg_page>() };
+    loop {
+      match unsafe { ogg_sync_pageout(&mut *self.sync, &mut raw_page) } {
+        1 => break,
+        0 => {
+          if !self.fill()? {
+            return Ok(None);
+          }
+        }
+        _ => return Err(OggError::LostSync),
+      }
+    }
+    let page = unsafe { OggPage::from_raw(&raw_page) };
+    let stream = self.streams.entry(page.serial).or_insert_with(|| {
+      let mut stream = Box::new(unsafe { zeroed::<ogg_stream_state>() });
+      unsafe { ogg_stream_init(&mut *stream, page.serial) };
+      stream
+    });
+    if unsafe { ogg_stream_pagein(&mut **stream, &mut raw_page) } != 0 {
+      return Err(OggError::Internal {
+        function: "ogg_stream_pagein",
+        serial: page.serial,
+      });
+    }
+    if self.dirty.back() != Some(&page.serial) {
+      self.dirty.push_back(page.serial);
+    }
+    Ok(Some(page))
+  }
+
+  // Ok(None) means end of input
+  pub fn next_packet(&mut self) -> Result<Option<OggPacket>, OggError> {
+    loop {
+      while let Some(&serial) = self.dirty.front() {
+        let stream = self.streams.get_mut(&serial).unwrap();
+        let mut raw_packet = unsafe { zeroed::<ogg_packet>() };
+        match unsafe { ogg_stream_packetout(&mut **stream, &mut raw_packet) } {
+          1 => return Ok(Some(unsafe { OggPacket::from_raw(&raw_packet, serial) })),
+          0 => {
+            self.dirty.pop_front();
+          }
+          _ => return Err(OggError::Hole { serial }),
+        }
+      }
+      if self.next_page()?.is_none() {
+        return Ok(None);
+      }
+    }
+  }
+
+  // returns false on end of input
+  fn fill(&mut self) -> io::Result<bool> {
+    let buffer = unsafe { ogg_sync_buffer(&mut *self.sync, READ_CHUNK as c_long) };
+    let buffer = unsafe { slice::from_raw_parts_mut(buffer as *mut u8, READ_CHUNK) };
+    let count = loop {
+      match self.reader.read(buffer) {
+        Ok(count) => break count,
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+        Err(err) => return Err(err),
+      }
+    };
+    if count == 0 {
+      return Ok(false);
+    }
+    unsafe { ogg_sync_wrote(&mut *self.sync, count as c_long) };
+    Ok(true)
+  }
+
+  unsafe fn clear(&mut self) {
+    for stream in self.streams.values_mut() {
+      ogg_stream_clear(&mut **stream);
+    }
+    self.streams.clear();
+    ogg_sync_clear(&mut *self.sync);
+  }
+}
+
+impl<R: Read> Iterator for OggReader<R> {
+  type Item = Result<OggPacket, OggError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.next_packet().transpose()
+  }
+}
+
+impl<R: Read> Drop for OggReader<R> {
+  fn drop(&mut self) {
+    unsafe { self.clear() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn empty_input_has_no_packets() {
+    let mut reader = OggReader::new(&[][..]);
+    assert!(reader.next_page().unwrap().is_none());
+    assert!(reader.next_packet().unwrap().is_none());
+  }
+
+  #[test]
+  fn garbage_is_not_a_page() {
+    let garbage = vec![0x55u8; 10_000];
+    let mut reader = OggReader::new(&garbage[..]);
+    loop {
+      match reader.next_page() {
+        Ok(None) => break,
+        Ok(Some(page)) => panic!("page found in garbage: {:?}", page),
+        Err(OggError::LostSync) => continue,
+        Err(err) => panic!("unexpected error {}", err),
+      }
+    }
+  }
+}
@@ -0,0 +1,182 @@
+use {
+  crate::vorbis::{ogg::*, packet::*},
+  std::{
+    collections::HashMap,
+    io::Write,
+    mem::{zeroed, ManuallyDrop},
+    os::raw::c_int,
+    ptr, slice,
+  },
+};
+
+// How eagerly pages are cut from submitted packets
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Paging {
+  // libogg default, pages of about 4kb, good for files
+  Default,
+  // page is emitted as soon as it holds at least this many bytes, bounds latency of live streams
+  Fill(u32),
+}
+
+// Muxer over any byte sink: packets of several logical streams are interleaved page by page
+// in order of their submission. libogg computes page checksums itself in pageout/flush
+pub struct OggWriter<W: Write> {
+  writer: W,
+  paging: Paging,
+  // libogg states are boxed so their addresses never change while libogg works with them
+  streams: HashMap<i32, Box<ogg_stream_state>>,
+}
+
+impl<W: Write> OggWriter<W> {
+  pub fn new(writer: W) -> OggWriter<W> {
+    OggWriter::with_paging(writer, Paging::Default)
+  }
+
+  pub fn with_paging(writer: W, paging: Paging) -> OggWriter<W> {
+    OggWriter {
+      writer,
+      paging,
+      streams: HashMap::new(),
+    }
+  }
+
+  // first packet of a serial starts its logical stream. Returns number of pages written
+  pub fn write_packet(&mut self, packet: &OggPacket) -> Result<usize, OggError> {
+    let serial = packet.serial;
+    let stream = self.streams.entry(serial).or_insert_with(|| {
+      let mut stream = Box::new(unsafe { zeroed::<ogg_stream_state>() });
+      unsafe { ogg_stream_init(&mut *stream, serial) };
+      stream
+    });
+    let mut raw_packet = packet.as_raw();
+    if unsafe { ogg_stream_packetin(&mut **stream, &mut raw_packet) } != 0 {
+      return Err(OggError::Internal {
+        function: "ogg_stream_packetin",
+        serial,
+      });
+    }
+    // end of stream has to reach the sink right away, nothing will push it out later
+    if packet.eos {
+      return self.flush(serial);
+    }
+    let paging = self.paging;
+    self.write_pages(serial, |stream, page| unsafe {
+      match paging {
+        Paging::Default => ogg_stream_pageout(stream, page),
+        Paging::Fill(fill) => ogg_stream_pageout_fill(stream, page, fill as c_int),
+      }
+    })
+  }
+
+  // forces everything submitted for the serial into pages, e.g. to start audio on a fresh page
+  // after codec headers. Returns number of pages written
+  pub fn flush(&mut self, serial: i32) -> Result<usize, OggError> {
+    self.write_pages(serial, |stream, page| unsafe { ogg_stream_flush(stream, page) })
+  }
+
+  pub fn flush_all(&mut self) -> Result<usize, OggError> {
+    let serials: Vec<i32> = self.streams.keys().cloned().collect();
+    let mut count = 0;
+    for serial in serials {
+      count += self.flush(serial)?;
+    }
+    self.writer.flush()?;
+    Ok(count)
+  }
+
+  // flushes all streams and gives the sink back
+  pub fn finish(mut self) -> Result<W, OggError> {
+    self.flush_all()?;
+    let mut this = ManuallyDrop::new(self);
+    unsafe {
+      this.clear();
+      ptr::drop_in_place(&mut this.streams);
+      Ok(ptr::read(&this.writer))
+    }
+  }
+
+  fn write_pages<F>(&mut self, serial: i32, mut next_page: F) -> Result<usize, OggError>
+  where
+    F: FnMut(*mut ogg_stream_state, *mut ogg_page) -> c_int,
+  {
+    let stream = match self.streams.get_mut(&serial) {
+      Some(stream) => stream,
+      None => return Ok(0),
+    };
+    let mut count = 0;
+    let mut raw_page = unsafe { zeroed::<ogg_page>() };
+    while next_page(&mut **stream, &mut raw_page) != 0 {
+      let (header, body) = unsafe {
+        (
+          slice::from_raw_parts(raw_page.header, raw_page.header_len as usize),
+          slice::from_raw_parts(raw_page.body, raw_page.body_len as usize),
+        )
+      };
+      self.writer.write_all(header)?;
+      self.writer.write_all(body)?;
+      count += 1;
+    }
+    Ok(count)
+  }
+
+  unsafe fn clear(&mut self) {
+    for stream in self.streams.values_mut() {
+      ogg_stream_clear(&mut **stream);
+    }
+    self.streams.clear();
+  }
+}
+
+impl<W: Write> Drop for OggWriter<W> {
+  fn drop(&mut self) {
+    if let Err(err) = self.flush_all() {
+      println!("OggWriter: flush on drop failed: {}", err);
+    }
+    unsafe { self.clear() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::vorbis::OggReader};
+
+  fn packet(serial: i32, number: i64, size: usize, bos: bool, eos: bool) -> OggPacket {
+    OggPacket {
+      data: (0..size).map(|i| (i as i64 + number) as u8).collect(),
+      serial,
+      granule_position: number * 100,
+      packet_number: number,
+      bos,
+      eos,
+    }
+  }
+
+  #[test]
+  fn multiplexed_streams_survive_round_trip() {
+    let mut written = Vec::new();
+    let mut writer = OggWriter::with_paging(Vec::new(), Paging::Fill(1000));
+    for number in 0..20 {
+      for &serial in &[7, 42] {
+        let packet = packet(serial, number, 300 + serial as usize, number == 0, number == 19);
+        writer.write_packet(&packet).unwrap();
+        if number == 0 {
+          writer.flush(serial).unwrap();
+        }
+        written.push(packet);
+      }
+    }
+    let file = writer.finish().unwrap();
+
+    let read: Vec<OggPacket> = OggReader::new(&file[..]).map(|packet| packet.unwrap()).collect();
+    for &serial in &[7, 42] {
+      let expected: Vec<&OggPacket> = written.iter().filter(|packet| packet.serial == serial).collect();
+      let actual: Vec<&OggPacket> = read.iter().filter(|packet| packet.serial == serial).collect();
+      assert_eq!(expected.len(), actual.len());
+      for (expected, actual) in expected.into_iter().zip(actual) {
+        assert_eq!(expected.data, actual.data);
+        assert_eq!(expected.bos, actual.bos);
+        assert_eq!(expected.eos, actual.eos);
+      }
+    }
+  }
+}
@@ -0,0 +1,123 @@
+// Own test binary: the counting allocator replaces the global one for every test linked with it
+use {
+  divana::device::{
+    backend::{memory::MemoryBackend, CaptureBackend, PlaybackBackend},
+    convert::Resampling,
+    hotplug::HotPlug,
+    info::{BufferConfig, DeviceFormat, Encoding},
+    input::InputDevice,
+    output::OutputDevice,
+  },
+  std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+  },
+};
+
+// counts allocations of every thread but the ones which opted out, device threads can not be told apart
+// from the inside, so the test thread, which feeds and drains the fake devices, opts out instead
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+  static NOT_COUNTED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn count() {
+  if !NOT_COUNTED.try_with(|not_counted| not_counted.get()).unwrap_or(false) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+fn allocations() -> u64 {
+  ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    count();
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout)
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    count();
+    System.realloc(ptr, layout, new_size)
+  }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const CAPTURED: DeviceFormat = DeviceFormat {
+  frequency: 48000,
+  channels: 2,
+  bits: 16,
+  encoding: Encoding::Pcm,
+};
+
+const PLAYED: DeviceFormat = DeviceFormat {
+  frequency: 44100,
+  channels: 1,
+  bits: 24,
+  encoding: Encoding::Pcm,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Input thread decodes 10ms of the fake microphone into pooled samples. Output thread converts them
+// to the format of the fake speakers, applies volume, encodes them into the device and drops them,
+// which sends the samples back to their pools
+#[test]
+fn streaming_through_the_device_threads_does_not_allocate() {
+  const WARM_UP: u64 = 100;
+  const BUFFERS: u64 = 1000;
+  NOT_COUNTED.with(|not_counted| not_counted.set(true));
+  let (microphone, speakers) = (MemoryBackend::new(CAPTURED), MemoryBackend::new(PLAYED));
+  let backend = (microphone.clone(), speakers.clone());
+  let buffers = BufferConfig {
+    count: 4,
+    period: Duration::from_millis(10),
+  };
+  let output = OutputDevice::new(
+    &backend,
+    PLAYED,
+    buffers,
+    &backend.output_devices()[0],
+    HotPlug::Stop,
+    Resampling::Sinc,
+  )
+  .unwrap();
+  output.set_volume(0.5);
+  let input = InputDevice::new(
+    &backend,
+    CAPTURED,
+    buffers,
+    &backend.input_devices()[0],
+    HotPlug::Stop,
+    output.sender.clone(),
+  )
+  .unwrap();
+
+  let recorded: Vec<u8> = (0..480 * CAPTURED.block_align() as usize).map(|byte| byte as u8).collect();
+  let mut steady_state = 0;
+  for captured in 0..BUFFERS {
+    if captured == WARM_UP {
+      steady_state = allocations();
+    }
+    microphone.push_capture(&recorded);
+    microphone.advance(buffers.period);
+    // one buffer at a time, so the pipeline never holds more than the pools were made for
+    assert!(!speakers.wait_playback(1, TIMEOUT).is_empty(), "buffer {} was not played", captured);
+  }
+  let device_allocations = allocations() - steady_state;
+  drop(input);
+  drop(output);
+  assert_eq!(device_allocations, 0);
+}